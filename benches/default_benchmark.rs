@@ -13,7 +13,7 @@ use virtual_machine::{
 fn bench1(c: &mut Criterion) {
     let mut machine = virtual_machine::Machine::new();
     let program: Vec<Instruction> = std::hint::black_box(
-        vec![
+        [
             (0..10000)
                 .map(|i| add_instr!(Push, i))
                 .collect::<Vec<Instruction>>(),
@@ -36,5 +36,32 @@ fn bench1(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench1);
+// 1000 cells under 100 nested blocks, each block adding a cell on top of its
+// caller's. Bodies work on the caller's cells in place, so nesting deeper
+// shouldn't copy them again.
+fn bench2(c: &mut Criterion) {
+    let mut body = vec![add_instr!(Add, 0, 999)];
+    for _ in 0..100 {
+        body = vec![add_instr!(Add, 0, 1), Instruction::Block(body)];
+    }
+    let program: Vec<Instruction> = std::hint::black_box(
+        [
+            vec![Instruction::PushRange {
+                start: 0,
+                count: 1000,
+            }],
+            body,
+        ]
+        .concat(),
+    );
+
+    c.bench_function("nested blocks", |b| {
+        b.iter(|| {
+            let _ = virtual_machine::Machine::load_and_run(&program)
+                .expect("Failed to run the program");
+        })
+    });
+}
+
+criterion_group!(benches, bench1, bench2);
 criterion_main!(benches);
@@ -0,0 +1,841 @@
+/*
+ * Static passes over programs, run without a machine.
+ *
+ * Passes that need to know where a cell came from simulate the cell stack
+ * over the top-level instructions, recording for every cell the instruction
+ * that pushed it (its provenance). Instructions whose effect on the stack
+ * can't be known statically (blocks, calls, conditionals) make the simulated
+ * stack unknown until enough pushes happen on top of it again.
+ */
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use crate::{
+    Address, Cell, FunctionOp, Instruction, NullaryOp, Opcode, UnaryOpImm, definition_count,
+};
+
+// A pushed cell, identified by the instruction that pushed it and, for
+// instructions pushing several cells, its position among them.
+type Slot = (usize, u32);
+
+// The cells on top of the stack whose provenance is known. Anything below
+// them is unknown.
+#[derive(Debug, Default)]
+struct Provenance {
+    slots: Vec<Slot>,
+}
+
+impl Provenance {
+    fn push(&mut self, index: usize, count: u32) {
+        self.slots.extend((0..count).map(|k| (index, k)));
+    }
+
+    fn pop(&mut self, count: usize) {
+        self.slots.truncate(self.slots.len().saturating_sub(count));
+    }
+
+    fn forget(&mut self) {
+        self.slots.clear();
+    }
+
+    fn top(&self) -> Option<Slot> {
+        self.slots.last().copied()
+    }
+}
+
+// How a top-level instruction affects the cell stack.
+enum Effect {
+    Pure { pops: usize, pushes: u32 },
+    // Has to be kept, but leaves the stack as it was.
+    Observable,
+    // Has to be kept, and we can't tell what the stack looks like afterwards.
+    Opaque,
+}
+
+fn effect(instruction: &Instruction) -> Effect {
+    use Instruction::*;
+
+    match instruction {
+        AluNullary(NullaryOp::Nop) => Effect::Pure { pops: 0, pushes: 0 },
+        AluNullary(NullaryOp::InstructionCount) => Effect::Pure { pops: 0, pushes: 1 },
+        AluNullary(NullaryOp::Rebase | NullaryOp::Cond | NullaryOp::DupWindow) => Effect::Opaque,
+        AluUnaryImm(UnaryOpImm::Push, _) | Input(_) => Effect::Pure { pops: 0, pushes: 1 },
+        AluUnaryImm(UnaryOpImm::Pop, n) => match usize::try_from(*n) {
+            Ok(pops) => Effect::Pure { pops, pushes: 0 },
+            Err(_) => Effect::Opaque, // Fails at runtime
+        },
+        AluUnaryCell(..) | AluBinary(..) | Clamp { .. } | TestBit { .. } | HostUnary { .. } => {
+            Effect::Pure { pops: 0, pushes: 1 }
+        }
+        DivMod { .. } => Effect::Pure { pops: 0, pushes: 2 },
+        Average { .. } | Fma { .. } | HashRange { .. } | MatchMask { .. } | Load(_) => {
+            Effect::Pure { pops: 0, pushes: 1 }
+        }
+        // The reversed cells count as new ones.
+        ReverseTop(n) => Effect::Pure {
+            pops: usize::from(*n),
+            pushes: u32::from(*n),
+        },
+        SumTop(n) => Effect::Pure {
+            pops: usize::from(*n),
+            pushes: 1,
+        },
+        PushRange { count, .. } => Effect::Pure {
+            pops: 0,
+            pushes: *count,
+        },
+        AssertEq { .. } | SetImm { .. } | Store(..) | Assume(_) | Assert(_) => Effect::Observable,
+        PopDynamic(_) => Effect::Opaque,
+        // Host functions may have effects of their own.
+        Block(_)
+        | AluFunction(..)
+        | FunctionDefineArity(..)
+        | HostCall { .. }
+        | Succeeds(_)
+        | Switch { .. }
+        | BranchIfZero(..)
+        | Jump(_)
+        | JumpIfZero(..)
+        | JumpIfNotZero(..)
+        | Return
+        | ReturnCell(_) => Effect::Opaque,
+    }
+}
+
+/*
+ * Removes the instructions that run after the final result has been computed
+ * but don't contribute to it, such as scratch values pushed and popped again.
+ *
+ * Only the tail of the program is touched: instructions that must always run
+ * (assertions, function definitions, blocks, calls, conditionals) are kept,
+ * and the program is cut at the first point after them where the final
+ * result's cell is back on top of the stack. The cells left by the stripped
+ * program are the same as the original's. Trailing instructions that could
+ * only fail (e.g. a dead division by zero) are considered dead as well.
+ */
+pub fn strip_trailing_dead(program: &[Instruction]) -> Vec<Instruction> {
+    let mut stack = Provenance::default();
+    let mut tops = Vec::with_capacity(program.len());
+    let mut last_kept = None;
+    let mut index = 0;
+    let targets: Vec<_> = program
+        .iter()
+        .filter_map(Instruction::jump_target)
+        .collect();
+
+    while let Some(instruction) = program.get(index) {
+        if targets.contains(&index) {
+            // Jumps reach it with cells we can't tell.
+            stack.forget();
+            last_kept = Some(index);
+        }
+        match effect(instruction) {
+            Effect::Pure { pops, pushes } => {
+                stack.pop(pops);
+                stack.push(index, pushes);
+            }
+            Effect::Observable => last_kept = Some(index),
+            Effect::Opaque => {
+                if instruction.definition().is_some() {
+                    // The rest of the definitions and their bodies don't run here.
+                    let count = definition_count(program, index);
+                    let skipped = (2 * count - 1).min(program.len() - index - 1);
+                    for _ in 0..skipped {
+                        tops.push(stack.top());
+                    }
+                    index += skipped;
+                } else if let Instruction::AluNullary(NullaryOp::Cond) = instruction {
+                    // The next instruction only runs conditionally.
+                    if index + 1 < program.len() {
+                        tops.push(None);
+                        index += 1;
+                    }
+                }
+                stack.forget();
+                last_kept = Some(index);
+            }
+        }
+
+        tops.push(stack.top());
+        index += 1;
+    }
+
+    let Some(result) = stack.top() else {
+        return program.to_vec();
+    };
+
+    let first_candidate = last_kept.map_or(result.0, |kept| kept.max(result.0));
+    let cut = (first_candidate..program.len())
+        .find(|&index| tops[index] == Some(result))
+        .unwrap_or(program.len() - 1);
+
+    program[..=cut].to_vec()
+}
+
+// The cells an instruction reads or writes, not counting those of its body.
+pub(crate) fn cell_operands(instruction: &Instruction) -> Vec<Cell> {
+    use Instruction::*;
+
+    match instruction {
+        AluUnaryCell(_, cell) => vec![*cell],
+        AluBinary(_, a, b) | AssertEq { a, b } | DivMod { a, b } | Average { a, b } => {
+            vec![*a, *b]
+        }
+        Clamp { value, lo, hi } => vec![*value, *lo, *hi],
+        Fma { a, x, b } => vec![*a, *x, *b],
+        HashRange { start, len } => (0..*len)
+            .filter_map(|offset| start.checked_add(offset))
+            .collect(),
+        MatchMask { value, start, len } => std::iter::once(*value)
+            .chain((0..*len).filter_map(|offset| start.checked_add(offset)))
+            .collect(),
+        SetImm { dest, .. } | Load(dest) => vec![*dest],
+        Store(value, address) => vec![*value, *address],
+        HostUnary { arg, .. } | PopDynamic(arg) => vec![*arg],
+        TestBit { value, bit } => vec![*value, *bit],
+        HostCall { args, .. } => args.clone(),
+        Switch { selector, .. }
+        | BranchIfZero(selector, ..)
+        | JumpIfZero(selector, _)
+        | JumpIfNotZero(selector, _)
+        | ReturnCell(selector)
+        | Assume(selector)
+        | Assert(selector) => vec![*selector],
+        AluNullary(_)
+        | AluUnaryImm(..)
+        | AluFunction(..)
+        | FunctionDefineArity(..)
+        | Jump(_)
+        | Return
+        | PushRange { .. }
+        | Input(_)
+        | SumTop(_)
+        | ReverseTop(_)
+        | Block(_)
+        | Succeeds(_) => Vec::new(),
+    }
+}
+
+/*
+ * Returns the highest cell index referenced by any instruction of the
+ * program, including those in blocks and function bodies, or `None` if no
+ * instruction references a cell.
+ */
+pub fn max_cell_reference(program: &[Instruction]) -> Option<Cell> {
+    program
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Block(body) | Instruction::Succeeds(body) => max_cell_reference(body),
+            Instruction::Switch { cases, default, .. } => cases
+                .iter()
+                .chain([default])
+                .filter_map(|body| max_cell_reference(body))
+                .chain(cell_operands(instruction))
+                .max(),
+            Instruction::BranchIfZero(cell, then, otherwise) => [then, otherwise]
+                .into_iter()
+                .filter_map(|body| max_cell_reference(body))
+                .chain([*cell])
+                .max(),
+            _ => cell_operands(instruction).into_iter().max(),
+        })
+        .max()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // Reads or writes a cell past the most cells there can be.
+    CellOutOfRange { cell: Cell, available: usize },
+    // Pops more cells than there can be.
+    StackUnderflow { pops: usize, available: usize },
+    // Jumps past the end of its body.
+    JumpOutOfBounds(Address),
+    // Defines a function with no block left after its run of definitions.
+    MissingBody(String),
+    // Calls a function defined nowhere in the program.
+    UndefinedFunction(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub location: Vec<usize>, // The index in each enclosing body, outermost first
+    pub problem: Problem,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let location: Vec<_> = self.location.iter().map(usize::to_string).collect();
+        write!(f, "instruction {}: ", location.join("."))?;
+        match &self.problem {
+            Problem::CellOutOfRange { cell, available } => {
+                write!(f, "cell r{} out of range of {} cells", cell, available)
+            }
+            Problem::StackUnderflow { pops, available } => {
+                write!(f, "pops {} cells of at most {}", pops, available)
+            }
+            Problem::JumpOutOfBounds(target) => write!(f, "jump target {} out of bounds", target),
+            Problem::MissingBody(name) => write!(f, "function {:?} has no body", name),
+            Problem::UndefinedFunction(name) => write!(f, "function {:?} is never defined", name),
+        }
+    }
+}
+
+// The most cells a body can see after `instruction` runs, given the most it
+// could see before. `None` when there's no telling.
+fn bound_after(instruction: &Instruction, bound: Option<usize>) -> Option<usize> {
+    use Instruction::*;
+
+    let bound = bound?;
+    match effect(instruction) {
+        Effect::Pure { pops, pushes } => Some(bound.saturating_sub(pops) + pushes as usize),
+        Effect::Observable => Some(bound),
+        Effect::Opaque => match instruction {
+            AluNullary(NullaryOp::Cond) => Some(bound.saturating_sub(1)),
+            AluNullary(NullaryOp::DupWindow) => Some(2 * bound),
+            // Bodies leave their cells behind, apart from a result.
+            Block(_)
+            | Succeeds(_)
+            | Switch { .. }
+            | BranchIfZero(..)
+            | HostCall { .. }
+            | AluFunction(FunctionOp::FunctionCall | FunctionOp::Tail, _) => Some(bound + 1),
+            _ => Some(bound),
+        },
+    }
+}
+
+// At most `a` or at most `b` cells.
+fn either(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    Some(a?.max(b?))
+}
+
+struct Validator<'p> {
+    defined: HashSet<&'p str>,
+    location: Vec<usize>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'p> Validator<'p> {
+    fn report(&mut self, index: usize, problem: Problem) {
+        let mut location = self.location.clone();
+        location.push(index);
+        self.diagnostics.push(Diagnostic { location, problem });
+    }
+
+    fn nested(&mut self, index: usize, body: &'p [Instruction], bound: Option<usize>) {
+        self.location.push(index);
+        self.body(body, bound);
+        self.location.pop();
+    }
+
+    // Checks a body that starts out seeing at most `bound` cells.
+    fn body(&mut self, body: &'p [Instruction], mut bound: Option<usize>) {
+        let targets: Vec<_> = body.iter().filter_map(Instruction::jump_target).collect();
+        let mut skipped = None; // The bound if a `Cond` skips the current instruction
+        let mut index = 0;
+        while let Some(instruction) = body.get(index) {
+            if targets.contains(&index) {
+                bound = None; // Jumps reach it with cells we can't tell.
+            }
+
+            if instruction.definition().is_some() {
+                self.definitions(body, index);
+                // The run and its bodies don't run here.
+                let count = definition_count(body, index);
+                index += 2 * count;
+                if let Some(skipped) = skipped.take() {
+                    bound = either(bound, skipped);
+                }
+                continue;
+            }
+
+            if let Some(available) = bound {
+                for cell in cell_operands(instruction) {
+                    if usize::from(cell) >= available {
+                        self.report(index, Problem::CellOutOfRange { cell, available });
+                    }
+                }
+                if let Effect::Pure { pops, .. } = effect(instruction)
+                    && pops > available
+                {
+                    self.report(index, Problem::StackUnderflow { pops, available });
+                }
+            }
+            if let Some(target) = instruction.jump_target()
+                && target > body.len()
+            {
+                self.report(index, Problem::JumpOutOfBounds(target));
+            }
+            if let Instruction::AluFunction(FunctionOp::FunctionCall | FunctionOp::Tail, name) =
+                instruction
+                && !self.defined.contains(name.as_str())
+            {
+                self.report(index, Problem::UndefinedFunction(name.clone()));
+            }
+            match instruction {
+                Instruction::Block(body) | Instruction::Succeeds(body) => {
+                    self.nested(index, body, bound);
+                }
+                Instruction::Switch { cases, default, .. } => {
+                    for body in cases.iter().chain([default]) {
+                        self.nested(index, body, bound);
+                    }
+                }
+                Instruction::BranchIfZero(_, then, otherwise) => {
+                    self.nested(index, then, bound);
+                    self.nested(index, otherwise, bound);
+                }
+                _ => {}
+            }
+
+            let after = bound_after(instruction, bound);
+            bound = match skipped.take() {
+                Some(skipped) => either(after, skipped),
+                None => after,
+            };
+            if let Instruction::AluNullary(NullaryOp::Cond) = instruction {
+                skipped = Some(bound);
+            }
+            index += 1;
+        }
+    }
+
+    // Checks the run of definitions starting at `start` and their bodies.
+    // A body sees the arguments its arity declares, or else the caller's
+    // cells, which could be any number.
+    fn definitions(&mut self, body: &'p [Instruction], start: usize) {
+        let count = definition_count(body, start);
+        for offset in 0..count {
+            let Some((name, arity)) = body[start + offset].definition() else {
+                break;
+            };
+            let at = start + count + offset;
+            match body.get(at) {
+                Some(Instruction::Block(function)) => {
+                    self.nested(at, function, arity.map(usize::from));
+                }
+                _ => self.report(start + offset, Problem::MissingBody(name.clone())),
+            }
+        }
+    }
+}
+
+// Every name the program defines a function by, in any body.
+fn defined_functions<'p>(program: &'p [Instruction], defined: &mut HashSet<&'p str>) {
+    for instruction in program {
+        if let Some((name, _)) = instruction.definition() {
+            defined.insert(name);
+        }
+        match instruction {
+            Instruction::Block(body) | Instruction::Succeeds(body) => {
+                defined_functions(body, defined);
+            }
+            Instruction::Switch { cases, default, .. } => {
+                for body in cases.iter().chain([default]) {
+                    defined_functions(body, defined);
+                }
+            }
+            Instruction::BranchIfZero(_, then, otherwise) => {
+                defined_functions(then, defined);
+                defined_functions(otherwise, defined);
+            }
+            _ => {}
+        }
+    }
+}
+
+/*
+ * Looks for instructions that are bound to fail, without running anything:
+ * cells that can't exist yet, pops of more cells than there are, jumps out
+ * of their body, definitions without a body and calls to functions defined
+ * nowhere. The program is assumed to start on an empty machine.
+ *
+ * Cell counts are followed as upper bounds, so only what fails on every run
+ * is reported; past a jump target, or in a function without a declared
+ * arity, the count is unknown and cells aren't checked until the body ends.
+ * Host functions are registered outside the program and aren't checked.
+ */
+pub fn validate(program: &[Instruction]) -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        defined: HashSet::new(),
+        location: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    defined_functions(program, &mut validator.defined);
+    validator.body(program, Some(0));
+    validator.diagnostics
+}
+
+// An instruction in a control-flow graph. Successors are ordered: the
+// instruction that runs next comes first, then any other target (a body, a
+// called function, a skipped-to instruction). `None` leaves the current body.
+struct Node {
+    kind: Opcode,
+    successors: Vec<Option<usize>>,
+}
+
+#[derive(Default)]
+struct ControlFlowGraph {
+    nodes: Vec<Node>,
+    functions: HashMap<String, Option<usize>>,
+    calls: Vec<(usize, String)>, // Linked to their function once every body is known
+}
+
+impl ControlFlowGraph {
+    fn build(program: &[Instruction]) -> Self {
+        let mut graph = ControlFlowGraph::default();
+        graph.add_body(program);
+        for (node, name) in std::mem::take(&mut graph.calls) {
+            let target = graph.functions.get(&name).copied().flatten();
+            graph.nodes[node].successors.push(target);
+        }
+        graph
+    }
+
+    fn entry(&self) -> Option<usize> {
+        (!self.nodes.is_empty()).then_some(0)
+    }
+
+    // Adds the nodes of `body`, returning the entry node.
+    fn add_body(&mut self, body: &[Instruction]) -> Option<usize> {
+        use Instruction::*;
+
+        let first = self.nodes.len();
+        for instruction in body {
+            self.nodes.push(Node {
+                kind: instruction.opcode(),
+                successors: Vec::new(),
+            });
+        }
+        let node = |index: usize| (index < body.len()).then_some(first + index);
+
+        let mut index = 0;
+        while let Some(instruction) = body.get(index) {
+            let mut successors = vec![node(index + 1)];
+            match instruction {
+                AluNullary(NullaryOp::Cond) => successors.push(node(index + 2)),
+                Block(inner) | Succeeds(inner) => successors.push(self.add_body(inner)),
+                Switch { cases, default, .. } => {
+                    for inner in cases.iter().chain([default]) {
+                        successors.push(self.add_body(inner));
+                    }
+                }
+                BranchIfZero(_, then, otherwise) => {
+                    successors.push(self.add_body(then));
+                    successors.push(self.add_body(otherwise));
+                }
+                AluFunction(FunctionOp::FunctionDefine, _) | FunctionDefineArity(..) => {
+                    // Each definition of a run names one of the blocks after
+                    // it, and running the definitions skips all of them.
+                    let count = definition_count(body, index);
+                    for (define, instruction) in body.iter().enumerate().skip(index).take(count) {
+                        if let Some((name, _)) = instruction.definition() {
+                            self.functions.insert(name.clone(), node(define + count));
+                        }
+                        self.nodes[first + define].successors =
+                            vec![node(index + 2 * count), node(define + count)];
+                    }
+                    index += count;
+                    continue;
+                }
+                AluFunction(FunctionOp::FunctionCall | FunctionOp::Tail, name) => {
+                    self.calls.push((first + index, name.clone()));
+                }
+                Jump(target) => successors = vec![node(*target)],
+                Return | ReturnCell(_) => successors = vec![None],
+                JumpIfZero(_, target) | JumpIfNotZero(_, target) => {
+                    successors.push(node(*target));
+                }
+                _ => {}
+            }
+            self.nodes[first + index].successors = successors;
+            index += 1;
+        }
+
+        node(0)
+    }
+}
+
+/*
+ * Checks whether two programs have the same control-flow shape: the same
+ * kinds of instructions, connected the same way through fallthrough, blocks,
+ * branches and calls. Immediates, cell numbers and function names don't
+ * matter, so renumbering cells or renaming functions keeps programs
+ * isomorphic.
+ */
+pub fn cfg_isomorphic(a: &[Instruction], b: &[Instruction]) -> bool {
+    let (graph_a, graph_b) = (ControlFlowGraph::build(a), ControlFlowGraph::build(b));
+    let mut a_to_b: HashMap<usize, usize> = HashMap::new();
+    let mut b_to_a: HashMap<usize, usize> = HashMap::new();
+
+    let mut pending = vec![(graph_a.entry(), graph_b.entry())];
+    while let Some(pair) = pending.pop() {
+        let (node_a, node_b) = match pair {
+            (None, None) => continue,
+            (Some(node_a), Some(node_b)) => (node_a, node_b),
+            _ => return false,
+        };
+
+        match (a_to_b.get(&node_a), b_to_a.get(&node_b)) {
+            (Some(&mapped_b), Some(&mapped_a)) if mapped_b == node_b && mapped_a == node_a => {
+                continue;
+            }
+            (None, None) => {
+                a_to_b.insert(node_a, node_b);
+                b_to_a.insert(node_b, node_a);
+            }
+            _ => return false,
+        }
+
+        let (node_a, node_b) = (&graph_a.nodes[node_a], &graph_b.nodes[node_b]);
+        if node_a.kind != node_b.kind || node_a.successors.len() != node_b.successors.len() {
+            return false;
+        }
+        pending.extend(
+            node_a
+                .successors
+                .iter()
+                .copied()
+                .zip(node_b.successors.iter().copied()),
+        );
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp,
+        Instruction::*,
+        Machine, UnaryOpCell,
+        macros::{add_instr, make_block},
+    };
+
+    fn run(program: &[Instruction]) -> (Option<i64>, Vec<i64>) {
+        let mut machine = Machine::new();
+        machine.load_program(program);
+        let last = machine.run().unwrap().copied();
+        (last, machine.cells)
+    }
+
+    #[test]
+    fn test_strips_dead_arithmetic() {
+        let program = vec![
+            add_instr!(Push, 2),
+            add_instr!(Push, 3),
+            add_instr!(Mul, 0, 1), // The result
+            add_instr!(Push, 1),
+            add_instr!(Push, 1),
+            add_instr!(Add, 3, 4),
+            add_instr!(Pop, 3),
+        ];
+
+        let stripped = strip_trailing_dead(&program);
+        assert_eq!(stripped.len(), 3);
+        assert_eq!(run(&stripped), run(&program));
+    }
+
+    #[test]
+    fn test_keeps_side_effects() {
+        let program = vec![
+            add_instr!(Push, 2),
+            add_instr!(Push, 3),
+            add_instr!(Mul, 0, 1),
+            add_instr!(Push, 6), // The result, also needed by the assertion
+            AssertEq { a: 2, b: 3 },
+            add_instr!(Push, 1),
+            add_instr!(Push, 1),
+            add_instr!(Add, 4, 5),
+            add_instr!(Pop, 3),
+        ];
+
+        let stripped = strip_trailing_dead(&program);
+        assert_eq!(stripped.len(), 5);
+        assert!(matches!(stripped.last(), Some(AssertEq { a: 2, b: 3 })));
+        assert_eq!(run(&stripped), run(&program));
+    }
+
+    #[test]
+    fn test_keeps_live_tail() {
+        let program = vec![
+            add_instr!(Push, 2),
+            make_block!(add_instr!(Push, 3)),
+            add_instr!(fun FunctionDefine, String::from("f")),
+            make_block!(add_instr!(Push, 4)),
+            add_instr!(fun FunctionCall, String::from("f")),
+            add_instr!(Add, 0, 2),
+        ];
+
+        assert_eq!(strip_trailing_dead(&program).len(), program.len());
+
+        // Nothing can be said about a result coming out of a block.
+        let program = vec![add_instr!(Push, 2), make_block!(add_instr!(Push, 3))];
+        assert_eq!(strip_trailing_dead(&program).len(), program.len());
+
+        // Nor about the cells at a jump target.
+        let program = vec![
+            add_instr!(Push, 0),
+            JumpIfZero(0, 4),
+            add_instr!(Push, 1),
+            add_instr!(Push, 2),
+            add_instr!(Push, 3),
+            add_instr!(Pop, 1),
+        ];
+        assert_eq!(strip_trailing_dead(&program).len(), program.len());
+    }
+
+    #[test]
+    fn test_max_cell_reference() {
+        let program = vec![
+            add_instr!(Push, 5),
+            make_block!(
+                add_instr!(R ReadReverse, 0),
+                add_instr!(Rebase),
+                add_instr!(Mul, 0, 0),
+                add_instr!(Push, 42),
+                add_instr!(Mul, 0, 2),
+                add_instr!(Add, 1, 3)
+            ),
+        ];
+        assert_eq!(max_cell_reference(&program), Some(3));
+
+        let program = vec![add_instr!(Push, 5), PushRange { start: 0, count: 9 }];
+        assert_eq!(max_cell_reference(&program), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let program = vec![
+            add_instr!(Push, 1),
+            add_instr!(Add, 0, 1), // Only r0 exists
+            FunctionDefineArity(String::from("double"), 1),
+            make_block!(add_instr!(Add, 0, 0), add_instr!(Mul, 0, 2)),
+            make_block!(
+                add_instr!(Push, 2),
+                add_instr!(Cond),
+                add_instr!(Pop, 2),
+                add_instr!(Add, 0, 1), // Fine if the pop was skipped
+                add_instr!(Pop, 4),
+                Jump(7)
+            ),
+            add_instr!(fun FunctionCall, String::from("double")),
+            add_instr!(fun FunctionCall, String::from("triple")),
+            add_instr!(fun FunctionDefine, String::from("orphan")),
+        ];
+
+        let diagnostics = validate(&program);
+        let found: Vec<_> = (diagnostics.iter())
+            .map(|d| (d.location.clone(), d.problem.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    vec![1],
+                    Problem::CellOutOfRange {
+                        cell: 1,
+                        available: 1
+                    }
+                ),
+                (
+                    vec![3, 1],
+                    Problem::CellOutOfRange {
+                        cell: 2,
+                        available: 2
+                    }
+                ),
+                (
+                    vec![4, 4],
+                    Problem::StackUnderflow {
+                        pops: 4,
+                        available: 3
+                    }
+                ),
+                (vec![4, 5], Problem::JumpOutOfBounds(7)),
+                (vec![6], Problem::UndefinedFunction(String::from("triple"))),
+                (vec![7], Problem::MissingBody(String::from("orphan"))),
+            ]
+        );
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "instruction 3.1: cell r2 out of range of 2 cells"
+        );
+    }
+
+    #[test]
+    fn test_validate_valid_programs() {
+        let program = vec![
+            add_instr!(Push, 5),
+            make_block!(
+                add_instr!(R ReadReverse, 0),
+                add_instr!(Rebase),
+                add_instr!(Mul, 0, 0),
+                add_instr!(Push, 42),
+                add_instr!(Mul, 0, 2),
+                add_instr!(Add, 1, 3)
+            ),
+            add_instr!(fun FunctionDefine, String::from("f")),
+            make_block!(add_instr!(Add, 0, 9)), // Sees the caller's cells
+            add_instr!(Push, 0),
+            JumpIfZero(2, 6),
+            add_instr!(Add, 5, 6), // Reached by the jump
+        ];
+        assert_eq!(validate(&program), Vec::new());
+    }
+
+    #[test]
+    fn test_validate_tail_call_result() {
+        // Outside a function, a tail call returns like any other call.
+        let program = vec![
+            add_instr!(fun FunctionDefine, String::from("f")),
+            make_block!(add_instr!(Push, 5)),
+            add_instr!(Push, 1),
+            add_instr!(fun Tail, String::from("f")),
+            add_instr!(Add, 0, 1),
+        ];
+        assert_eq!(run(&program).0, Some(6));
+        assert_eq!(validate(&program), Vec::new());
+    }
+
+    #[test]
+    fn test_cfg_isomorphic() {
+        let program = |push: i64, cell: u16, name: &str, op: BinaryOp| {
+            vec![
+                add_instr!(fun FunctionDefine, String::from(name)),
+                make_block!(add_instr!(Push, push), AluBinary(op, cell, cell)),
+                add_instr!(Push, push),
+                add_instr!(Push, 1),
+                add_instr!(Cond),
+                add_instr!(fun FunctionCall, String::from(name)),
+                make_block!(add_instr!(Push, push)),
+            ]
+        };
+
+        let original = program(1, 0, "f", BinaryOp::Add);
+        assert!(cfg_isomorphic(&original, &original));
+        assert!(cfg_isomorphic(
+            &original,
+            &program(7, 0, "f", BinaryOp::Add)
+        ));
+        assert!(cfg_isomorphic(
+            &original,
+            &program(7, 3, "g", BinaryOp::Add)
+        ));
+        assert!(!cfg_isomorphic(
+            &original,
+            &program(1, 0, "f", BinaryOp::Mul)
+        ));
+
+        // Same instructions, different shape.
+        let mut flattened = original.clone();
+        flattened[6] = add_instr!(Push, 1);
+        assert!(!cfg_isomorphic(&original, &flattened));
+        assert!(!cfg_isomorphic(&original, &original[..6]));
+        assert!(cfg_isomorphic(&[], &[]));
+    }
+}
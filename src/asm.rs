@@ -0,0 +1,589 @@
+/*
+ * Text assembler: reads programs written with the instruction mnemonics.
+ *
+ * Every line holds one instruction, its mnemonic followed by its operands,
+ * separated by spaces or commas. Cells are written `r0`, `r1`, ... (or just
+ * as numbers), immediates and counts as decimal integers, and names as single
+ * words. Instructions with bodies end their line with `{`, and their body
+ * ends at a line holding only `}`:
+ *
+ *     fn square:
+ *     block {
+ *         readrev r0
+ *         mul r1, r1
+ *     }
+ *     push 5
+ *     call square
+ *
+ * A `switch` holds `case N { ... }` bodies, numbered from 0, followed by a
+ * `default { ... }` body, and a `brz` holds a `then { ... }` body followed by
+ * an `else { ... }` body. Jump targets are instruction indices within the
+ * body, or labels: a line holding only `name:` labels the instruction after
+ * it. Everything after a `;` is a comment. The output of
+ * `pretty::pretty_print` reads back as the program it was printed from.
+ */
+
+use std::{collections::HashMap, str::FromStr};
+
+use crate::{
+    Address, BinaryOp, Cell, FunctionOp, Instruction, MachineError, NullaryOp, Opcode, UnaryOpCell,
+    UnaryOpImm,
+};
+
+fn error(line: usize, reason: &str) -> MachineError {
+    MachineError::InstructionError(format!("line {}: {}", line, reason))
+}
+
+fn error_at(line: usize, column: usize, reason: &str) -> MachineError {
+    MachineError::InstructionError(format!("line {}, column {}: {}", line, column, reason))
+}
+
+// A line of source, without its comment and surrounding whitespace.
+#[derive(Debug, Clone, Copy)]
+struct Line<'s> {
+    number: usize, // Numbered from 1
+    column: usize, // Where `code` starts, numbered from 1
+    code: &'s str,
+}
+
+impl<'s> Line<'s> {
+    // The line's words, each with the column it starts at.
+    fn tokens(&self) -> Vec<(usize, &'s str)> {
+        let mut tokens = Vec::new();
+        let mut start = None;
+        let ends = self.code.char_indices().chain([(self.code.len(), ' ')]);
+        for (index, c) in ends {
+            match (c.is_whitespace() || c == ',', start) {
+                (true, Some(from)) => {
+                    tokens.push((self.column + from, &self.code[from..index]));
+                    start = None;
+                }
+                (false, None) => start = Some(index),
+                _ => {}
+            }
+        }
+        tokens
+    }
+}
+
+struct Operands<'s> {
+    tokens: std::vec::IntoIter<(usize, &'s str)>,
+    line: usize,
+    column: usize, // Of the last token read
+    end: usize,    // Just past the last token, where missing ones are reported
+}
+
+impl<'s> Operands<'s> {
+    fn token(&mut self, what: &str) -> Result<&'s str, MachineError> {
+        let (column, token) = (self.tokens.next())
+            .ok_or_else(|| error_at(self.line, self.end, &format!("missing {}", what)))?;
+        self.column = column;
+        Ok(token)
+    }
+
+    fn number<T: FromStr>(&mut self, what: &str) -> Result<T, MachineError> {
+        let token = self.token(what)?;
+        token.parse().map_err(|_| {
+            error_at(
+                self.line,
+                self.column,
+                &format!("invalid {} {:?}", what, token),
+            )
+        })
+    }
+
+    fn cell_from(&self, column: usize, token: &str) -> Result<Cell, MachineError> {
+        token
+            .strip_prefix('r')
+            .unwrap_or(token)
+            .parse()
+            .map_err(|_| error_at(self.line, column, &format!("invalid cell {:?}", token)))
+    }
+
+    fn cell(&mut self) -> Result<Cell, MachineError> {
+        let token = self.token("cell")?;
+        self.cell_from(self.column, token)
+    }
+
+    fn name(&mut self) -> Result<String, MachineError> {
+        self.token("name").map(String::from)
+    }
+
+    fn finish(mut self) -> Result<(), MachineError> {
+        match self.tokens.next() {
+            Some((column, token)) => Err(error_at(
+                self.line,
+                column,
+                &format!("unexpected {:?}", token),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Parser<'s> {
+    lines: Vec<Line<'s>>, // Without comments or blank lines
+    next: usize,
+    label: Option<&'s str>, // Named by the jump just read, until its body resolves it
+}
+
+fn label(line: &str) -> Option<&str> {
+    (line.strip_suffix(':')).filter(|label| !label.is_empty() && !label.contains(' '))
+}
+
+impl<'s> Parser<'s> {
+    fn line(&mut self) -> Option<Line<'s>> {
+        let line = self.lines.get(self.next).copied();
+        self.next += 1;
+        line
+    }
+
+    fn last_line(&self) -> usize {
+        self.lines.last().map_or(0, |line| line.number)
+    }
+
+    // Reads instructions up to the `}` closing the body, or to the end of the
+    // text for the top level.
+    fn body(&mut self, nested: bool) -> Result<Vec<Instruction>, MachineError> {
+        let mut program = Vec::new();
+        let mut labels = HashMap::new();
+        let mut jumps = Vec::new(); // Index, line and label of each jump to a label
+        loop {
+            match self.line() {
+                Some(Line { code: "}", .. }) if nested => break,
+                Some(Line {
+                    number, code: "}", ..
+                }) => return Err(error(number, "unmatched }")),
+                Some(line) => match label(line.code) {
+                    Some(label) => {
+                        if labels.insert(label, program.len()).is_some() {
+                            let reason = format!("duplicate label {:?}", label);
+                            return Err(error_at(line.number, line.column, &reason));
+                        }
+                    }
+                    None => {
+                        program.push(self.instruction(line)?);
+                        if let Some(label) = self.label.take() {
+                            jumps.push((program.len() - 1, line.number, label));
+                        }
+                    }
+                },
+                None if nested => return Err(error(self.last_line(), "unclosed {")),
+                None => break,
+            }
+        }
+
+        for (index, number, label) in jumps {
+            let target = *(labels.get(label))
+                .ok_or_else(|| error(number, &format!("unknown label {:?}", label)))?;
+            if let Instruction::Jump(jump)
+            | Instruction::JumpIfZero(_, jump)
+            | Instruction::JumpIfNotZero(_, jump) = &mut program[index]
+            {
+                *jump = target;
+            }
+        }
+        Ok(program)
+    }
+
+    // Reads a jump target. A label is left for `body` to resolve once it has
+    // read the whole body.
+    fn target(&mut self, operands: &mut Operands<'s>) -> Result<Address, MachineError> {
+        let token = operands.token("target")?;
+        if let Ok(target) = token.parse() {
+            return Ok(target);
+        }
+        self.label = Some(token);
+        Ok(0)
+    }
+
+    fn switch_bodies(
+        &mut self,
+        number: usize,
+    ) -> Result<(Vec<Vec<Instruction>>, Vec<Instruction>), MachineError> {
+        let mut cases = Vec::new();
+        loop {
+            let Some(Line { number, code, .. }) = self.line() else {
+                return Err(error(number, "unclosed switch"));
+            };
+            let header: Vec<_> = code.split_whitespace().collect();
+            match header[..] {
+                ["case", index, "{"] if index.parse() == Ok(cases.len()) => {
+                    cases.push(self.body(true)?);
+                }
+                ["default", "{"] => break,
+                _ => return Err(error(number, "expected the next case or default")),
+            }
+        }
+
+        let default = self.body(true)?;
+        self.close(number, "switch")?;
+        Ok((cases, default))
+    }
+
+    fn branch_bodies(
+        &mut self,
+        number: usize,
+    ) -> Result<(Vec<Instruction>, Vec<Instruction>), MachineError> {
+        let mut bodies = Vec::new();
+        for label in ["then", "else"] {
+            match self.line() {
+                Some(line) if line.code.split_whitespace().eq([label, "{"]) => {
+                    bodies.push(self.body(true)?);
+                }
+                Some(Line { number, .. }) => {
+                    return Err(error(number, &format!("expected the {} body", label)));
+                }
+                None => return Err(error(number, "unclosed brz")),
+            }
+        }
+
+        self.close(number, "brz")?;
+        let otherwise = bodies.pop().unwrap_or_default();
+        Ok((bodies.pop().unwrap_or_default(), otherwise))
+    }
+
+    // Reads the `}` closing the instruction on line `number` after its bodies.
+    fn close(&mut self, number: usize, mnemonic: &str) -> Result<(), MachineError> {
+        match self.line() {
+            Some(Line { code: "}", .. }) => Ok(()),
+            Some(Line { number, .. }) => {
+                Err(error(number, &format!("expected }} to close {}", mnemonic)))
+            }
+            None => Err(error(number, &format!("unclosed {}", mnemonic))),
+        }
+    }
+
+    fn instruction(&mut self, mut line: Line<'s>) -> Result<Instruction, MachineError> {
+        use Instruction::*;
+
+        let number = line.number;
+        let end = line.column + line.code.len();
+        let opens_body = match line.code.strip_suffix('{') {
+            Some(code) => {
+                line.code = code;
+                true
+            }
+            None => false,
+        };
+        let mut tokens = line.tokens().into_iter();
+        let (column, mnemonic) = tokens
+            .next()
+            .ok_or_else(|| error(number, "missing mnemonic"))?;
+        let opcode = Opcode::from_mnemonic(mnemonic)
+            .ok_or_else(|| error_at(number, column, &format!("unknown mnemonic {:?}", mnemonic)))?;
+        let mut operands = Operands {
+            tokens,
+            line: number,
+            column,
+            end,
+        };
+
+        let has_body = matches!(
+            opcode,
+            Opcode::Block | Opcode::Succeeds | Opcode::Switch | Opcode::BranchIfZero
+        );
+        if opens_body != has_body {
+            let reason = match has_body {
+                true => format!("{} needs a body", mnemonic),
+                false => format!("{} takes no body", mnemonic),
+            };
+            return Err(error(number, &reason));
+        }
+
+        let instruction = if let Some(op) = NullaryOp::from_opcode(opcode) {
+            AluNullary(op)
+        } else if let Some(op) = UnaryOpImm::from_opcode(opcode) {
+            AluUnaryImm(op, operands.number("immediate")?)
+        } else if let Some(op) = UnaryOpCell::from_opcode(opcode) {
+            AluUnaryCell(op, operands.cell()?)
+        } else if let Some(op) = BinaryOp::from_opcode(opcode) {
+            AluBinary(op, operands.cell()?, operands.cell()?)
+        } else if let Some(op) = FunctionOp::from_opcode(opcode) {
+            let name = operands.name()?;
+            match name.strip_suffix(':') {
+                Some(name) if op == FunctionOp::FunctionDefine => AluFunction(op, name.into()),
+                _ => AluFunction(op, name),
+            }
+        } else {
+            match opcode {
+                Opcode::Block => Block(Vec::new()),
+                Opcode::Succeeds => Succeeds(Vec::new()),
+                Opcode::Switch => Switch {
+                    selector: operands.cell()?,
+                    cases: Vec::new(),
+                    default: Vec::new(),
+                },
+                Opcode::BranchIfZero => BranchIfZero(operands.cell()?, Vec::new(), Vec::new()),
+                Opcode::PushRange => PushRange {
+                    start: operands.number("start")?,
+                    count: operands.number("count")?,
+                },
+                Opcode::AssertEq => AssertEq {
+                    a: operands.cell()?,
+                    b: operands.cell()?,
+                },
+                Opcode::Clamp => Clamp {
+                    value: operands.cell()?,
+                    lo: operands.cell()?,
+                    hi: operands.cell()?,
+                },
+                Opcode::SetImm => SetImm {
+                    dest: operands.cell()?,
+                    value: operands.number("immediate")?,
+                },
+                Opcode::TestBit => TestBit {
+                    value: operands.cell()?,
+                    bit: operands.cell()?,
+                },
+                Opcode::HostCall => {
+                    let name = operands.name()?;
+                    let args = std::mem::take(&mut operands.tokens)
+                        .map(|(column, token)| operands.cell_from(column, token))
+                        .collect::<Result<_, _>>()?;
+                    HostCall { name, args }
+                }
+                Opcode::Input => Input(operands.name()?),
+                Opcode::SumTop => SumTop(operands.number("count")?),
+                Opcode::DivMod => DivMod {
+                    a: operands.cell()?,
+                    b: operands.cell()?,
+                },
+                Opcode::HostUnary => HostUnary {
+                    name: operands.name()?,
+                    arg: operands.cell()?,
+                },
+                Opcode::PopDynamic => PopDynamic(operands.cell()?),
+                Opcode::Store => Store(operands.cell()?, operands.cell()?),
+                Opcode::Load => Load(operands.cell()?),
+                Opcode::ReverseTop => ReverseTop(operands.number("count")?),
+                Opcode::Average => Average {
+                    a: operands.cell()?,
+                    b: operands.cell()?,
+                },
+                Opcode::Fma => Fma {
+                    a: operands.cell()?,
+                    x: operands.cell()?,
+                    b: operands.cell()?,
+                },
+                Opcode::HashRange => HashRange {
+                    start: operands.cell()?,
+                    len: operands.number("length")?,
+                },
+                Opcode::MatchMask => MatchMask {
+                    value: operands.cell()?,
+                    start: operands.cell()?,
+                    len: operands.number("length")?,
+                },
+                Opcode::FunctionDefineArity => {
+                    FunctionDefineArity(operands.name()?, operands.number("arity")?)
+                }
+                Opcode::Jump => Jump(self.target(&mut operands)?),
+                Opcode::Return => Return,
+                Opcode::ReturnCell => ReturnCell(operands.cell()?),
+                Opcode::Assume => Assume(operands.cell()?),
+                Opcode::Assert => Assert(operands.cell()?),
+                Opcode::JumpIfZero => JumpIfZero(operands.cell()?, self.target(&mut operands)?),
+                Opcode::JumpIfNotZero => {
+                    JumpIfNotZero(operands.cell()?, self.target(&mut operands)?)
+                }
+                _ => unreachable!("operator opcodes are parsed above"),
+            }
+        };
+        operands.finish()?;
+
+        Ok(match instruction {
+            Block(_) => Block(self.body(true)?),
+            Succeeds(_) => Succeeds(self.body(true)?),
+            Switch { selector, .. } => {
+                let (cases, default) = self.switch_bodies(number)?;
+                Switch {
+                    selector,
+                    cases,
+                    default,
+                }
+            }
+            BranchIfZero(cell, ..) => {
+                let (then, otherwise) = self.branch_bodies(number)?;
+                BranchIfZero(cell, then, otherwise)
+            }
+            instruction => instruction,
+        })
+    }
+}
+
+/*
+ * Assembles `src` into a program. Fails with an `InstructionError` giving the
+ * line number of the first line that isn't a valid instruction, and the
+ * column of the word at fault when there is one.
+ */
+pub fn parse(src: &str) -> Result<Vec<Instruction>, MachineError> {
+    let lines = (src.lines().enumerate())
+        .map(|(index, line)| {
+            let code = line.split(';').next().unwrap_or_default();
+            let indent = code.len() - code.trim_start().len();
+            Line {
+                number: index + 1,
+                column: indent + 1,
+                code: code.trim(),
+            }
+        })
+        .filter(|line| !line.code.is_empty())
+        .collect();
+
+    let mut parser = Parser {
+        lines,
+        next: 0,
+        label: None,
+    };
+    parser.body(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Machine,
+        macros::{add_instr, make_block},
+        pretty::pretty_print,
+    };
+
+    #[test]
+    fn test_square_add_42() {
+        let program = parse(
+            "
+            push 5 ; Argument
+            block {
+                readrev r0  ; r0 <- x
+                rebase
+                mul r0 r0   ; r1 <- x ^ 2
+                push 42     ; r2 <- 42
+                mul r0 r2   ; r3 <- 42x
+                add r1 r3   ; r4 <- x^2 + 42x
+            }
+            ",
+        )
+        .unwrap();
+
+        let mut machine = Machine::new();
+        machine.load_program(&program);
+        assert_eq!(machine.run().unwrap(), Some(&235));
+        assert_eq!(machine.cells, vec![5, 235]);
+    }
+
+    #[test]
+    fn test_functions() {
+        let program = parse(
+            "
+            fn square:
+            block {
+                readrev r0
+                mul r1, r1
+            }
+            push 5
+            call square
+            ",
+        )
+        .unwrap();
+        assert_eq!(Machine::load_and_run(&program).unwrap(), Some(25));
+    }
+
+    #[test]
+    fn test_labels() {
+        let program = parse(
+            "
+            push -1
+            push 5
+            push 0
+            loop:
+                add r1, r2
+                add r0, r1
+                revtop 4
+                pop 2
+                jnz r1, loop
+            jmp end
+            push 7
+            end:
+            ",
+        )
+        .unwrap();
+        assert_eq!(program[7], Instruction::JumpIfNotZero(1, 3));
+        assert_eq!(program[8], Instruction::Jump(10));
+        assert_eq!(Machine::load_and_run(&program).unwrap(), Some(15));
+    }
+
+    #[test]
+    fn test_reads_pretty_printed_programs() {
+        use Instruction::*;
+
+        let program = vec![
+            add_instr!(fun FunctionDefine, String::from("f")),
+            make_block!(add_instr!(Push, -1)),
+            Switch {
+                selector: 0,
+                cases: vec![vec![], vec![add_instr!(R Neg, 0)]],
+                default: vec![Succeeds(vec![add_instr!(Div, 0, 1)])],
+            },
+            HostCall {
+                name: String::from("host"),
+                args: vec![0, 1],
+            },
+            SetImm { dest: 2, value: -7 },
+            HashRange { start: 1, len: 2 },
+            BranchIfZero(1, vec![add_instr!(Pop, 1)], vec![]),
+            JumpIfZero(2, 0),
+        ];
+
+        let parsed = parse(&pretty_print(&program, 4)).unwrap();
+        assert_eq!(parsed, program);
+    }
+
+    #[test]
+    fn test_errors_report_the_line() {
+        for (src, line) in [
+            ("push 1\n\n; comment\nfrobnicate r0", 4),
+            ("push 1\nadd r0", 2),
+            ("push x", 1),
+            ("push 1 2", 1),
+            ("block {\n  push 1\n", 2),
+            ("push 1\n}", 2),
+            ("block", 1),
+            ("push 1 {", 1),
+            ("jmp 0\nblock {\n  jz r0, out\n}\nout:", 3),
+            ("a:\npush 1\na:", 3),
+        ] {
+            match parse(src) {
+                Err(MachineError::InstructionError(message)) => {
+                    let rest = message.strip_prefix(&format!("line {}", line));
+                    assert!(
+                        rest.is_some_and(|rest| rest.starts_with([':', ','])),
+                        "{:?} for {:?}",
+                        message,
+                        src
+                    );
+                }
+                result => panic!("{:?} parsed as {:?}", src, result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors_report_the_column() {
+        for (src, expected) in [
+            (
+                "push 1\n  frobnicate r0",
+                "line 2, column 3: unknown mnemonic \"frobnicate\"",
+            ),
+            ("add r0,  rx", "line 1, column 10: invalid cell \"rx\""),
+            ("push 1 2 ; two", "line 1, column 8: unexpected \"2\""),
+            ("    add r0", "line 1, column 11: missing cell"),
+            ("hostcall f r0 q", "line 1, column 15: invalid cell \"q\""),
+            ("block {\n  push 1\n", "line 2: unclosed {"),
+        ] {
+            match parse(src) {
+                Err(MachineError::InstructionError(message)) => assert_eq!(message, expected),
+                result => panic!("{:?} parsed as {:?}", src, result),
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+/*
+ * vm-repl: an interactive session with a single machine.
+ *
+ * Every line of assembly is assembled and run on the same machine, and the
+ * cells are printed after each instruction. A line opening a body is run
+ * once its closing `}` has been read, and a function definition along with
+ * the body on the line after it. Lines starting with `:` are commands;
+ * `:help` lists them.
+ */
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use virtual_machine::{Instruction, Machine, Opcode, asm};
+
+const HELP: &str = "\
+:cells         print the cells
+:funcs         list the defined functions
+:reset         start over with a new machine
+:load <file>   run an assembly file
+:help          print this
+:quit          leave, as does the end of input";
+
+// Runs `src` on the machine one top-level instruction at a time.
+fn run(machine: &mut Machine<'static>, src: &str) {
+    let program = match asm::parse(src) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return;
+        }
+    };
+    // Functions keep pointing into the program that defined them, so every
+    // program lives as long as the session does.
+    let program: &'static [Instruction] = Vec::leak(program);
+    machine.load_program(program);
+    machine.reset_pc();
+    while !machine.is_finished() {
+        if let Err(e) = machine.step() {
+            eprintln!("error: {}", e);
+            return;
+        }
+        println!("{:?}", machine.cells());
+    }
+}
+
+fn command(machine: &mut Machine<'static>, line: &str) -> bool {
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    match (command, argument.trim()) {
+        (":cells", "") => println!("{:?}", machine.cells()),
+        (":funcs", "") => {
+            for name in machine.function_names() {
+                println!("{}", name);
+            }
+        }
+        (":reset", "") => *machine = Machine::new(),
+        (":load", "") => eprintln!("error: :load needs a file"),
+        (":load", path) => match fs::read_to_string(path) {
+            Ok(src) => run(machine, &src),
+            Err(e) => eprintln!("error: {}: {}", path, e),
+        },
+        (":help", "") => println!("{}", HELP),
+        (":quit", "") => return false,
+        _ => eprintln!("error: unknown command {:?}, see :help", line),
+    }
+    true
+}
+
+// Whether `code` defines a function, whose body is the next instruction.
+fn defines_function(code: &str) -> bool {
+    let mnemonic = code.split_whitespace().next().unwrap_or_default();
+    matches!(
+        Opcode::from_mnemonic(mnemonic),
+        Some(Opcode::FunctionDefine | Opcode::FunctionDefineArity)
+    )
+}
+
+fn prompt(depth: usize) {
+    print!("{}", if depth == 0 { "> " } else { ". " });
+    let _ = io::stdout().flush();
+}
+
+fn main() {
+    let mut machine = Machine::new();
+    let mut pending = String::new(); // Lines of a body not yet closed
+    let mut depth = 0;
+    let mut defining = false; // Whether a definition waits for its body
+
+    prompt(depth);
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let code = line.split(';').next().unwrap_or_default().trim();
+        if code.is_empty() {
+            // Nothing to run, and no reason to cut a definition short
+        } else if depth == 0 && !defining && code.starts_with(':') {
+            if !command(&mut machine, code) {
+                return;
+            }
+        } else {
+            pending.push_str(&line);
+            pending.push('\n');
+            if code.ends_with('{') {
+                depth += 1;
+            } else if code == "}" && depth > 0 {
+                depth -= 1;
+            }
+            if depth == 0 {
+                defining = defines_function(code);
+            }
+            if depth == 0 && !defining {
+                run(&mut machine, &pending);
+                pending.clear();
+            }
+        }
+        prompt(depth + usize::from(defining));
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * vm-run: runs a program file and prints how the run ended.
+ *
+ *     vm-run [--fuel N] [--trace] FILE [CELL...]
+ *
+ * FILE holds either bytecode, as written by `bytecode::encode_program`, or
+ * assembly text. The machine starts with the CELL values given, in order.
+ * `--fuel` stops the run once it has spent N fuel, one per instruction, and
+ * `--trace` logs every instruction to stderr as it runs.
+ *
+ * Exits with 0 if the run succeeded, 1 if it failed, and 2 if the program
+ * couldn't be loaded or the arguments are wrong.
+ */
+
+use std::{fs, process::ExitCode};
+
+use virtual_machine::{Instruction, Machine, StderrTracer, asm, bytecode};
+
+const USAGE: &str = "usage: vm-run [--fuel N] [--trace] FILE [CELL...]";
+
+struct Options {
+    path: String,
+    cells: Vec<i64>,
+    fuel: Option<u64>,
+    trace: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut path = None;
+    let mut cells = Vec::new();
+    let mut fuel = None;
+    let mut trace = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fuel" => {
+                let value = args.next().ok_or("--fuel needs a value")?;
+                fuel = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid fuel {:?}", value))?,
+                );
+            }
+            "--trace" => trace = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => cells.push(arg.parse().map_err(|_| format!("invalid cell {:?}", arg))?),
+        }
+    }
+    Ok(Options {
+        path: path.ok_or("missing FILE")?,
+        cells,
+        fuel,
+        trace,
+    })
+}
+
+// Bytecode starts with its magic bytes; anything else is assembled.
+fn load(path: &str) -> Result<Vec<Instruction>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if bytes.starts_with(bytecode::MAGIC) {
+        return bytecode::decode_program(&bytes).map_err(|e| format!("{}: {}", path, e));
+    }
+    let src =
+        String::from_utf8(bytes).map_err(|_| format!("{}: neither bytecode nor assembly", path))?;
+    asm::parse(&src).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let program = match load(&options.path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut machine = Machine::from(options.cells);
+    if let Some(fuel) = options.fuel {
+        machine.set_fuel(fuel);
+    }
+    if options.trace {
+        machine.set_tracer(Box::new(StderrTracer));
+    }
+    machine.load_program(&program);
+
+    let result = machine.run().map(|last| last.copied());
+    println!("cells: {:?}", machine.cells());
+    if let Some(fuel) = machine.fuel() {
+        println!("fuel left: {}", fuel);
+    }
+    match result {
+        Ok(Some(value)) => {
+            println!("result: {}", value);
+            ExitCode::SUCCESS
+        }
+        Ok(None) => {
+            println!("result: none");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,522 @@
+/*
+ * Binary encoding of programs.
+ *
+ * An encoded program starts with `MAGIC` and a version byte, then holds its
+ * instruction count followed by each instruction: its opcode byte, then its
+ * operands in order. Integers are LEB128 varints, so small ones take a single
+ * byte; immediates are zigzag-encoded first, so small negative ones do too.
+ * Strings are a length followed by UTF-8 bytes, and bodies an instruction
+ * count followed by the instructions.
+ *
+ * The encoding ends with a CRC-32 of everything before it, so that corrupted
+ * or tampered programs are rejected by `decode_program` instead of running.
+ * Programs of a version this decoder doesn't know fail with
+ * `UnsupportedVersion`.
+ */
+
+use crate::{
+    BinaryOp, Cell, FunctionOp, Instruction, MachineError, NullaryOp, Opcode, UnaryOpCell,
+    UnaryOpImm,
+};
+
+// CRC-32 as used by zlib and PNG (reflected, polynomial 0x04c11db7).
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+pub const MAGIC: &[u8; 4] = b"VMBC";
+pub const VERSION: u8 = 1;
+
+fn malformed(reason: &str) -> MachineError {
+    MachineError::InstructionError(format!("Malformed bytecode: {}", reason))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    // Seven bits a byte, low bits first, the top bit set on all but the last.
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    fn cell(&mut self, cell: Cell) {
+        self.varint(cell.into());
+    }
+
+    // Zigzag-encoded: 0, -1, 1, -2, ... become 0, 1, 2, 3, ...
+    fn immediate(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn length(&mut self, length: usize) {
+        self.varint(length as u64);
+    }
+
+    fn target(&mut self, target: usize) {
+        self.varint(target as u64);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.length(string.len());
+        self.bytes.extend(string.as_bytes());
+    }
+
+    fn program(&mut self, program: &[Instruction]) {
+        self.length(program.len());
+        for instruction in program {
+            self.instruction(instruction);
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        use Instruction::*;
+
+        self.bytes.push(instruction.opcode() as u8);
+        match instruction {
+            AluNullary(_) | Return => {}
+            AluUnaryImm(_, value) => self.immediate(*value),
+            AluUnaryCell(_, cell)
+            | PopDynamic(cell)
+            | ReturnCell(cell)
+            | Assume(cell)
+            | Assert(cell) => self.cell(*cell),
+            AluBinary(_, a, b) | AssertEq { a, b } | DivMod { a, b } | Average { a, b } => {
+                self.cell(*a);
+                self.cell(*b);
+            }
+            AluFunction(_, name) | Input(name) => self.string(name),
+            Block(body) | Succeeds(body) => self.program(body),
+            PushRange { start, count } => {
+                self.immediate(*start);
+                self.varint((*count).into());
+            }
+            Clamp { value, lo, hi } => {
+                self.cell(*value);
+                self.cell(*lo);
+                self.cell(*hi);
+            }
+            Jump(target) => self.target(*target),
+            JumpIfZero(cell, target) | JumpIfNotZero(cell, target) => {
+                self.cell(*cell);
+                self.target(*target);
+            }
+            FunctionDefineArity(name, arity) => {
+                self.string(name);
+                self.varint((*arity).into());
+            }
+            HashRange { start, len } => {
+                self.cell(*start);
+                self.varint((*len).into());
+            }
+            MatchMask { value, start, len } => {
+                self.cell(*value);
+                self.cell(*start);
+                self.varint((*len).into());
+            }
+            Fma { a, x, b } => {
+                self.cell(*a);
+                self.cell(*x);
+                self.cell(*b);
+            }
+            SetImm { dest, value } => {
+                self.cell(*dest);
+                self.immediate(*value);
+            }
+            TestBit { value, bit } => {
+                self.cell(*value);
+                self.cell(*bit);
+            }
+            HostCall { name, args } => {
+                self.string(name);
+                self.length(args.len());
+                for arg in args {
+                    self.cell(*arg);
+                }
+            }
+            SumTop(count) | ReverseTop(count) => self.cell(*count),
+            HostUnary { name, arg } => {
+                self.string(name);
+                self.cell(*arg);
+            }
+            Switch {
+                selector,
+                cases,
+                default,
+            } => {
+                self.cell(*selector);
+                self.length(cases.len());
+                for body in cases {
+                    self.program(body);
+                }
+                self.program(default);
+            }
+            Store(value, address) => {
+                self.cell(*value);
+                self.cell(*address);
+            }
+            Load(address) => self.cell(*address),
+            BranchIfZero(cell, then, otherwise) => {
+                self.cell(*cell);
+                self.program(then);
+                self.program(otherwise);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], MachineError> {
+        let (taken, rest) = self
+            .bytes
+            .split_first_chunk()
+            .ok_or_else(|| malformed("unexpected end of input"))?;
+        self.bytes = rest;
+        Ok(*taken)
+    }
+
+    fn varint(&mut self) -> Result<u64, MachineError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let [byte] = self.take()?;
+            let bits = u64::from(byte & 0x7f);
+            if bits << shift >> shift != bits {
+                break;
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(malformed("varint out of range"))
+    }
+
+    // A varint that must fit in `T`, described by `what` if it doesn't.
+    fn small<T: TryFrom<u64>>(&mut self, what: &str) -> Result<T, MachineError> {
+        T::try_from(self.varint()?).map_err(|_| malformed(&format!("{} out of range", what)))
+    }
+
+    fn cell(&mut self) -> Result<Cell, MachineError> {
+        self.small("cell")
+    }
+
+    fn immediate(&mut self) -> Result<i64, MachineError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn length(&mut self) -> Result<usize, MachineError> {
+        // Every item takes at least a byte, which bounds preallocations.
+        self.small("length")
+            .ok()
+            .filter(|&length| length <= self.bytes.len())
+            .ok_or_else(|| malformed("length exceeds the input"))
+    }
+
+    fn target(&mut self) -> Result<usize, MachineError> {
+        self.small("jump target")
+    }
+
+    fn string(&mut self) -> Result<String, MachineError> {
+        let length = self.length()?;
+        let (string, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        String::from_utf8(string.to_vec()).map_err(|_| malformed("invalid UTF-8 in a name"))
+    }
+
+    fn program(&mut self) -> Result<Vec<Instruction>, MachineError> {
+        let length = self.length()?;
+        let mut program = Vec::with_capacity(length);
+        for _ in 0..length {
+            program.push(self.instruction()?);
+        }
+        Ok(program)
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, MachineError> {
+        use Instruction::*;
+
+        let [byte] = self.take()?;
+        let opcode =
+            Opcode::from_byte(byte).ok_or_else(|| malformed(&format!("opcode {:#04x}", byte)))?;
+
+        if let Some(op) = NullaryOp::from_opcode(opcode) {
+            return Ok(AluNullary(op));
+        }
+        if let Some(op) = UnaryOpImm::from_opcode(opcode) {
+            return Ok(AluUnaryImm(op, self.immediate()?));
+        }
+        if let Some(op) = UnaryOpCell::from_opcode(opcode) {
+            return Ok(AluUnaryCell(op, self.cell()?));
+        }
+        if let Some(op) = BinaryOp::from_opcode(opcode) {
+            return Ok(AluBinary(op, self.cell()?, self.cell()?));
+        }
+        if let Some(op) = FunctionOp::from_opcode(opcode) {
+            return Ok(AluFunction(op, self.string()?));
+        }
+
+        Ok(match opcode {
+            Opcode::Block => Block(self.program()?),
+            Opcode::PushRange => PushRange {
+                start: self.immediate()?,
+                count: self.small("count")?,
+            },
+            Opcode::AssertEq => AssertEq {
+                a: self.cell()?,
+                b: self.cell()?,
+            },
+            Opcode::Clamp => Clamp {
+                value: self.cell()?,
+                lo: self.cell()?,
+                hi: self.cell()?,
+            },
+            Opcode::SetImm => SetImm {
+                dest: self.cell()?,
+                value: self.immediate()?,
+            },
+            Opcode::TestBit => TestBit {
+                value: self.cell()?,
+                bit: self.cell()?,
+            },
+            Opcode::HostCall => {
+                let name = self.string()?;
+                let count = self.length()?;
+                let args = (0..count).map(|_| self.cell()).collect::<Result<_, _>>()?;
+                HostCall { name, args }
+            }
+            Opcode::Input => Input(self.string()?),
+            Opcode::Succeeds => Succeeds(self.program()?),
+            Opcode::SumTop => SumTop(self.cell()?),
+            Opcode::Switch => {
+                let selector = self.cell()?;
+                let count = self.length()?;
+                let cases = (0..count)
+                    .map(|_| self.program())
+                    .collect::<Result<_, _>>()?;
+                Switch {
+                    selector,
+                    cases,
+                    default: self.program()?,
+                }
+            }
+            Opcode::BranchIfZero => BranchIfZero(self.cell()?, self.program()?, self.program()?),
+            Opcode::PopDynamic => PopDynamic(self.cell()?),
+            Opcode::Store => Store(self.cell()?, self.cell()?),
+            Opcode::Load => Load(self.cell()?),
+            Opcode::ReverseTop => ReverseTop(self.cell()?),
+            Opcode::HostUnary => HostUnary {
+                name: self.string()?,
+                arg: self.cell()?,
+            },
+            Opcode::DivMod => DivMod {
+                a: self.cell()?,
+                b: self.cell()?,
+            },
+            Opcode::Average => Average {
+                a: self.cell()?,
+                b: self.cell()?,
+            },
+            Opcode::Fma => Fma {
+                a: self.cell()?,
+                x: self.cell()?,
+                b: self.cell()?,
+            },
+            Opcode::HashRange => HashRange {
+                start: self.cell()?,
+                len: self.small("length")?,
+            },
+            Opcode::MatchMask => MatchMask {
+                value: self.cell()?,
+                start: self.cell()?,
+                len: self.small("length")?,
+            },
+            Opcode::FunctionDefineArity => {
+                FunctionDefineArity(self.string()?, self.small("arity")?)
+            }
+            Opcode::Jump => Jump(self.target()?),
+            Opcode::Return => Return,
+            Opcode::ReturnCell => ReturnCell(self.cell()?),
+            Opcode::Assume => Assume(self.cell()?),
+            Opcode::Assert => Assert(self.cell()?),
+            Opcode::JumpIfZero => JumpIfZero(self.cell()?, self.target()?),
+            Opcode::JumpIfNotZero => JumpIfNotZero(self.cell()?, self.target()?),
+            _ => unreachable!("operator opcodes are decoded above"),
+        })
+    }
+}
+
+// Encodes `program` after the header, followed by a checksum over both.
+pub fn encode_program(program: &[Instruction]) -> Vec<u8> {
+    let mut writer = Writer {
+        bytes: MAGIC.to_vec(),
+    };
+    writer.bytes.push(VERSION);
+    writer.program(program);
+
+    let checksum = crc32(&writer.bytes);
+    writer.bytes.extend(checksum.to_le_bytes());
+    writer.bytes
+}
+
+/*
+ * Decodes a program produced by `encode_program`. Fails with
+ * `UnsupportedVersion` if it was encoded by another version of the format,
+ * with `ChecksumMismatch` if the bytes were altered after encoding, and with
+ * an `InstructionError` if they don't describe a program at all.
+ */
+pub fn decode_program(bytes: &[u8]) -> Result<Vec<Instruction>, MachineError> {
+    let Some([version, rest @ ..]) = bytes.strip_prefix(MAGIC) else {
+        return Err(malformed("missing header"));
+    };
+    if *version != VERSION {
+        return Err(MachineError::UnsupportedVersion(*version));
+    }
+    let checksummed = &bytes[..bytes.len().saturating_sub(4)];
+    let (_, checksum) = rest
+        .split_last_chunk()
+        .ok_or_else(|| malformed("missing checksum"))?;
+    if crc32(checksummed) != u32::from_le_bytes(*checksum) {
+        return Err(MachineError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader {
+        bytes: &checksummed[MAGIC.len() + 1..],
+    };
+    let program = reader.program()?;
+    if !reader.bytes.is_empty() {
+        return Err(malformed("trailing bytes after the program"));
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Instruction::*,
+        Machine,
+        macros::{add_instr, make_block},
+    };
+
+    fn sample_program() -> Vec<Instruction> {
+        vec![
+            add_instr!(fun FunctionDefine, String::from("square")),
+            make_block!(add_instr!(R ReadReverse, 0), add_instr!(Mul, 0, 0)),
+            add_instr!(Push, -5),
+            add_instr!(fun FunctionCall, String::from("square")),
+            Switch {
+                selector: 1,
+                cases: vec![vec![add_instr!(Push, 1)], vec![]],
+                default: vec![add_instr!(Nop)],
+            },
+            HostCall {
+                name: String::from("host"),
+                args: vec![0, 1],
+            },
+            Clamp {
+                value: 1,
+                lo: 0,
+                hi: 1,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = sample_program();
+        let decoded = decode_program(&encode_program(&program)).unwrap();
+        assert_eq!(decoded, program);
+
+        let program = decoded[..4].to_vec();
+        assert_eq!(Machine::load_and_run(&program).unwrap(), Some(25));
+    }
+
+    #[test]
+    fn test_compact_integers() {
+        let program = vec![
+            add_instr!(Push, -1),
+            add_instr!(Push, 63),
+            add_instr!(Push, 64),
+            add_instr!(Push, i64::MIN),
+            add_instr!(Push, i64::MAX),
+            add_instr!(Add, 127, 128),
+            Jump(300),
+            FunctionDefineArity(String::from("f"), u16::MAX),
+            PushRange {
+                start: 0,
+                count: u32::MAX,
+            },
+        ];
+        let bytes = encode_program(&program);
+        assert_eq!(decode_program(&bytes).unwrap(), program);
+
+        // The header, the count, then an opcode and a byte for each small push.
+        let push = Opcode::Push as u8;
+        assert_eq!(
+            bytes[..11],
+            [b'V', b'M', b'B', b'C', 1, 9, push, 1, push, 126, push]
+        );
+        assert_eq!(bytes[11..13], [0x80, 0x01]); // 64 takes a second byte
+        assert_eq!(encode_program(&[]).len(), MAGIC.len() + 2 + 4);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut bytes = encode_program(&sample_program());
+        bytes[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(
+            decode_program(&bytes),
+            Err(MachineError::UnsupportedVersion(2))
+        ));
+
+        bytes[0] = b'X';
+        assert!(matches!(
+            decode_program(&bytes),
+            Err(MachineError::InstructionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_corruption_detected() {
+        let bytes = encode_program(&sample_program());
+        for index in MAGIC.len() + 1..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[index] ^= 0x10;
+            assert!(
+                matches!(
+                    decode_program(&corrupted),
+                    Err(MachineError::ChecksumMismatch)
+                ),
+                "flipped byte {} went unnoticed",
+                index
+            );
+        }
+
+        assert!(matches!(
+            decode_program(&bytes[..2]),
+            Err(MachineError::InstructionError(_))
+        ));
+    }
+}
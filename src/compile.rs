@@ -0,0 +1,152 @@
+/*
+ * Programs checked once and run many times.
+ *
+ * `compile` rejects programs that are bound to fail no matter what cells they
+ * start from, and keeps its own copy of the program, so running it again only
+ * costs setting up a machine.
+ */
+
+use std::collections::HashSet;
+
+use crate::{FunctionOp, Instruction, Machine, MachineError, UnaryOpImm, bind_definitions};
+
+#[derive(Debug, Clone)]
+pub struct CompiledProgram {
+    program: Vec<Instruction>,
+}
+
+impl CompiledProgram {
+    // Runs the program on a fresh machine whose cells start as `initial_cells`.
+    pub fn run(&self, initial_cells: Vec<i64>) -> Result<Option<i64>, MachineError> {
+        let mut machine = Machine::from(initial_cells);
+        machine.load_program(&self.program);
+        machine.run().map(|last| last.copied())
+    }
+
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+}
+
+pub(crate) fn bodies(instruction: &Instruction) -> Vec<&[Instruction]> {
+    match instruction {
+        Instruction::Block(body) | Instruction::Succeeds(body) => vec![body],
+        Instruction::Switch { cases, default, .. } => {
+            cases.iter().chain([default]).map(Vec::as_slice).collect()
+        }
+        Instruction::BranchIfZero(_, then, otherwise) => vec![then, otherwise],
+        _ => Vec::new(),
+    }
+}
+
+fn collect_definitions<'p>(program: &'p [Instruction], names: &mut HashSet<&'p str>) {
+    for instruction in program {
+        if let Some((name, _)) = instruction.definition() {
+            names.insert(name);
+        }
+        for body in bodies(instruction) {
+            collect_definitions(body, names);
+        }
+    }
+}
+
+fn check(program: &[Instruction], defined: &HashSet<&str>) -> Result<(), MachineError> {
+    for (index, instruction) in program.iter().enumerate() {
+        if let Some(target) = (instruction.jump_target()).filter(|&target| target > program.len()) {
+            return Err(MachineError::InvalidJumpTarget(target));
+        }
+        match instruction {
+            Instruction::AluFunction(FunctionOp::FunctionDefine, _)
+            | Instruction::FunctionDefineArity(..)
+                if index == 0 || program[index - 1].definition().is_none() =>
+            {
+                bind_definitions(program, index)?;
+            }
+            Instruction::AluFunction(FunctionOp::FunctionCall | FunctionOp::Tail, name)
+                if !defined.contains(name.as_str()) =>
+            {
+                return Err(MachineError::FunctionUndefined);
+            }
+            Instruction::AluUnaryImm(UnaryOpImm::Pop, count) if *count < 0 => {
+                return Err(MachineError::InvalidCell);
+            }
+            _ => {}
+        }
+        for body in bodies(instruction) {
+            check(body, defined)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Checks `program` and prepares it for repeated runs. Fails on definitions
+ * without a block, calls to functions the program never defines and negative
+ * pops, with the error running the program would eventually produce.
+ */
+pub fn compile(program: &[Instruction]) -> Result<CompiledProgram, MachineError> {
+    let mut defined = HashSet::new();
+    collect_definitions(program, &mut defined);
+    check(program, &defined)?;
+
+    Ok(CompiledProgram {
+        program: program.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp,
+        Instruction::*,
+        UnaryOpCell,
+        macros::{add_instr, make_block},
+    };
+
+    #[test]
+    fn test_compile_once_run_many() {
+        let program = vec![
+            add_instr!(fun FunctionDefine, String::from("square")),
+            make_block!(add_instr!(R ReadReverse, 0), add_instr!(Mul, 3, 3)),
+            add_instr!(Add, 0, 1),
+            add_instr!(fun FunctionCall, String::from("square")),
+        ];
+        let compiled = compile(&program).unwrap();
+
+        for (cells, expected) in [(vec![1, 2], 9), (vec![3, 4], 49), (vec![-5, 5], 0)] {
+            assert_eq!(compiled.run(cells).unwrap(), Some(expected));
+        }
+        assert!(matches!(
+            compiled.run(vec![1]),
+            Err(MachineError::InvalidCell)
+        ));
+    }
+
+    #[test]
+    fn test_compile_rejects_invalid_programs() {
+        let undefined = vec![make_block!(add_instr!(fun FunctionCall, String::from("f")))];
+        assert!(matches!(
+            compile(&undefined),
+            Err(MachineError::FunctionUndefined)
+        ));
+
+        let no_body = vec![add_instr!(fun FunctionDefine, String::from("f"))];
+        assert!(matches!(
+            compile(&no_body),
+            Err(MachineError::InstructionError(_))
+        ));
+
+        let negative_pop = vec![add_instr!(Push, 1), add_instr!(Pop, -1)];
+        assert!(matches!(
+            compile(&negative_pop),
+            Err(MachineError::InvalidCell)
+        ));
+
+        let far_jump = vec![make_block!(Jump(1)), Jump(3)];
+        assert!(matches!(
+            compile(&far_jump),
+            Err(MachineError::InvalidJumpTarget(3))
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Concolic testing: finding inputs that take a program down new paths.
+ *
+ * A `ConcolicRunner` runs the program on concrete inputs, pushed as symbols
+ * before the first instruction, and collects the path constraints of the
+ * run. Negating one of them, while keeping those before it, describes a run
+ * that goes the same way up to that branch and the other way there; the
+ * solver's values for it are new inputs, which are run in turn. Each run
+ * only negates the constraints after the one its inputs were made from, so
+ * that no branch is flipped twice along the same prefix.
+ */
+
+use std::{
+    collections::{HashSet, VecDeque},
+    rc::Rc,
+};
+
+use crate::{
+    Instruction, Machine, MachineError,
+    solver::{Solver, solve},
+    symbolic::Expr,
+};
+
+pub struct ConcolicRunner<'a, S: Solver> {
+    program: &'a [Instruction],
+    solver: S,
+    max_steps: Option<usize>,
+    queue: VecDeque<(Vec<i64>, usize)>, // Inputs to run, with the first constraint to negate
+    ready: VecDeque<Vec<i64>>,          // Inputs found but not yet yielded
+    seen: HashSet<Vec<i64>>,
+}
+
+impl<'a, S: Solver> ConcolicRunner<'a, S> {
+    // Starts from `inputs`, which aren't yielded themselves.
+    pub fn new(program: &'a [Instruction], inputs: Vec<i64>, solver: S) -> Self {
+        ConcolicRunner {
+            program,
+            solver,
+            max_steps: None,
+            queue: VecDeque::from([(inputs.clone(), 0)]),
+            ready: VecDeque::new(),
+            seen: HashSet::from([inputs]),
+        }
+    }
+
+    // Stops each run after `max_steps` instructions, keeping the constraints
+    // collected until then.
+    pub fn with_step_limit(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    // Runs the program on `inputs`. Runs that fail still went down a path.
+    fn run(&self, inputs: &[i64]) -> Result<Vec<Rc<Expr>>, MachineError> {
+        let mut machine = Machine::new();
+        for &value in inputs {
+            machine.push_symbol(value)?;
+        }
+        machine.load_program(self.program);
+        let _ = match self.max_steps {
+            Some(max_steps) => machine.run_with_budget(max_steps).map(|_| ()),
+            None => machine.run().map(|_| ()),
+        };
+        Ok(machine.take_path_constraints().iter().cloned().collect())
+    }
+
+    // Inputs that satisfy `kept` but not `negated`, if the solver finds any.
+    fn solve(
+        &mut self,
+        inputs: &[i64],
+        kept: &[Rc<Expr>],
+        negated: &Rc<Expr>,
+    ) -> Result<Option<Vec<i64>>, MachineError> {
+        let model = solve(&mut self.solver, kept, std::slice::from_ref(negated))?;
+        // Symbols the constraints don't mention keep their values.
+        Ok(model.map(|model| {
+            (inputs.iter().enumerate())
+                .map(|(symbol, &value)| model.get(symbol).unwrap_or(value))
+                .collect()
+        }))
+    }
+
+    // Runs the next queued inputs, queueing the new inputs it leads to.
+    fn expand(&mut self, inputs: Vec<i64>, bound: usize) -> Result<(), MachineError> {
+        let constraints = self.run(&inputs)?;
+        for index in bound..constraints.len() {
+            let Some(found) = self.solve(&inputs, &constraints[..index], &constraints[index])?
+            else {
+                continue;
+            };
+            if self.seen.insert(found.clone()) {
+                self.queue.push_back((found.clone(), index + 1));
+                self.ready.push_back(found);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Yields each new input vector as it's found, or the solver's error.
+impl<S: Solver> Iterator for ConcolicRunner<'_, S> {
+    type Item = Result<Vec<i64>, MachineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(inputs) = self.ready.pop_front() {
+                return Some(Ok(inputs));
+            }
+            let (inputs, bound) = self.queue.pop_front()?;
+            if let Err(e) = self.expand(inputs, bound) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp,
+        Instruction::*,
+        NullaryOp, UnaryOpImm,
+        macros::{add_instr, make_block},
+        solver::BoundedSolver,
+    };
+
+    #[test]
+    fn test_concolic_inputs() {
+        // Pushes 1 if s0 == 3, then 2 if s1 < s0 as well.
+        let program = vec![
+            add_instr!(Push, 3),
+            add_instr!(SetEqual, 0, 2),
+            add_instr!(Cond),
+            make_block!(
+                add_instr!(Push, 1),
+                add_instr!(SetLessThan, 1, 0),
+                BranchIfZero(4, vec![], vec![add_instr!(Push, 2)])
+            ),
+        ];
+
+        let runner = ConcolicRunner::new(&program, vec![0, 0], BoundedSolver::new(-5..=5));
+        let found: Vec<_> = runner.map(Result::unwrap).collect();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0], vec![3, 0]); // s1 keeps its value
+        assert_eq!(found[1][0], 3);
+        assert!(found[1][1] >= 3);
+    }
+
+    #[test]
+    fn test_concolic_step_limit() {
+        // Loops forever unless s0 is 3.
+        let program = vec![
+            add_instr!(Push, 3),
+            add_instr!(Sub, 0, 1),
+            JumpIfNotZero(2, 2),
+        ];
+
+        let runner = ConcolicRunner::new(&program, vec![0], BoundedSolver::new(0..=9));
+        let found: Vec<_> = runner.with_step_limit(20).map(Result::unwrap).collect();
+        assert_eq!(found, vec![vec![3]]);
+    }
+}
@@ -0,0 +1,780 @@
+/*
+ * Exploring every path through a program.
+ *
+ * An `Explorer` runs a machine holding symbols one instruction at a time.
+ * Whenever a branch decides on a symbolic cell, the machine as it was before
+ * the branch is forked: the fork asks the solver for values of the symbols
+ * under which the branch goes another way, and if there are any, recomputes
+ * its cells from them and is explored later on. A branch with more than two
+ * ways, like `Switch`, forks again from the fork, each time excluding the
+ * ways already taken, until the solver finds no way left.
+ *
+ * Every path ends in a `TerminalState`: the machine at the end of the program
+ * or after an error, except those ending at an `Assume` that doesn't hold.
+ * `Assume` and `Assert` fork like branches, so a path failing an assertion
+ * ends with the values that make it fail. So do divisions by a symbolic cell,
+ * which fail on the path where it's zero. A `SearchStrategy` decides which
+ * path goes on after each fork, depth first unless the explorer is given
+ * another. `find_input_reaching` explores until some path runs a given
+ * instruction.
+ */
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+};
+
+use crate::{
+    Address, BinaryOp, Cell, Instruction, Machine, MachineError, NullaryOp, StepResult,
+    compile::bodies,
+    solver::{BoundedSolver, Model, Solver, solve},
+    symbolic::Expr,
+};
+
+// A machine at the end of a path.
+#[derive(Debug, Clone)]
+pub struct TerminalState<'a> {
+    pub machine: Machine<'a>,
+    pub result: Result<Option<i64>, MachineError>, // Like `Machine::run`'s
+}
+
+// A path waiting to go on.
+#[derive(Debug, Clone)]
+pub struct PendingState<'a> {
+    machine: Machine<'a>,
+    excluded: Vec<Rc<Expr>>, // Constraints the next branch must not satisfy
+}
+
+impl<'a> PendingState<'a> {
+    pub fn machine(&self) -> &Machine<'a> {
+        &self.machine
+    }
+
+    // How many branches on symbols the path has gone through.
+    pub fn depth(&self) -> usize {
+        self.machine.path.len()
+    }
+}
+
+/*
+ * Picks the path to go on with. The explorer adds every path it forks,
+ * along with the one that forked, and runs whichever `next_state` returns
+ * until it forks again or ends.
+ */
+pub trait SearchStrategy<'a> {
+    fn add_state(&mut self, state: PendingState<'a>);
+
+    fn next_state(&mut self) -> Option<PendingState<'a>>;
+
+    // Called with the machine about to run each instruction.
+    fn visit(&mut self, _machine: &Machine<'a>) {}
+}
+
+// Goes on with the path added last: the path that forked, then its forks.
+#[derive(Debug, Default)]
+pub struct DepthFirst<'a> {
+    states: Vec<PendingState<'a>>,
+}
+
+impl<'a> SearchStrategy<'a> for DepthFirst<'a> {
+    fn add_state(&mut self, state: PendingState<'a>) {
+        self.states.push(state);
+    }
+
+    fn next_state(&mut self) -> Option<PendingState<'a>> {
+        self.states.pop()
+    }
+}
+
+// Goes on with the path added first, taking turns between the paths.
+#[derive(Debug, Default)]
+pub struct BreadthFirst<'a> {
+    states: VecDeque<PendingState<'a>>,
+}
+
+impl<'a> SearchStrategy<'a> for BreadthFirst<'a> {
+    fn add_state(&mut self, state: PendingState<'a>) {
+        self.states.push_back(state);
+    }
+
+    fn next_state(&mut self) -> Option<PendingState<'a>> {
+        self.states.pop_front()
+    }
+}
+
+/*
+ * Goes on with a path picked at random, as if by walking down the tree of
+ * forks from the root, choosing a side at random at each fork: a path that
+ * went through one branch fewer is twice as likely. Deep paths, such as
+ * those stuck in a loop, don't crowd out the others.
+ */
+#[derive(Debug)]
+pub struct RandomPath<'a> {
+    states: Vec<PendingState<'a>>,
+    seed: u64,
+}
+
+impl RandomPath<'_> {
+    // The same seed picks the same paths.
+    pub fn new(seed: u64) -> Self {
+        RandomPath {
+            states: Vec::new(),
+            seed: seed.max(1), // Xorshift never leaves zero
+        }
+    }
+
+    // A random number in [0, 1), from xorshift64*.
+    fn random(&mut self) -> f64 {
+        self.seed ^= self.seed >> 12;
+        self.seed ^= self.seed << 25;
+        self.seed ^= self.seed >> 27;
+        (self.seed.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl<'a> SearchStrategy<'a> for RandomPath<'a> {
+    fn add_state(&mut self, state: PendingState<'a>) {
+        self.states.push(state);
+    }
+
+    fn next_state(&mut self) -> Option<PendingState<'a>> {
+        let shallowest = self.states.iter().map(PendingState::depth).min()?;
+        let weight =
+            |state: &PendingState| 0.5f64.powi((state.depth() - shallowest).min(64) as i32);
+        let total: f64 = self.states.iter().map(weight).sum();
+        let mut pick = self.random() * total;
+        let index = (self.states.iter())
+            .position(|state| {
+                pick -= weight(state);
+                pick < 0.0
+            })
+            .unwrap_or(self.states.len() - 1);
+        Some(self.states.swap_remove(index))
+    }
+}
+
+/*
+ * Goes on with the path about to run the instruction that has run least
+ * often so far, across all paths, to reach instructions no path has run yet.
+ * Ties go to the path added first.
+ */
+#[derive(Debug, Default)]
+pub struct CoverageGuided<'a> {
+    states: Vec<PendingState<'a>>,
+    runs: HashMap<(usize, Address), usize>, // Keyed by the body's address and the instruction's
+}
+
+// Where in which body the machine is about to run an instruction.
+fn location(machine: &Machine) -> (usize, Address) {
+    let body = machine
+        .program
+        .map_or(0, |program| program.as_ptr() as usize);
+    (body, machine.pc)
+}
+
+impl<'a> SearchStrategy<'a> for CoverageGuided<'a> {
+    fn add_state(&mut self, state: PendingState<'a>) {
+        self.states.push(state);
+    }
+
+    fn next_state(&mut self) -> Option<PendingState<'a>> {
+        let runs = |state: &PendingState| {
+            let location = location(&state.machine);
+            self.runs.get(&location).copied().unwrap_or(0)
+        };
+        let (index, _) = (self.states.iter().enumerate()).min_by_key(|(_, state)| runs(state))?;
+        Some(self.states.remove(index))
+    }
+
+    fn visit(&mut self, machine: &Machine<'a>) {
+        *self.runs.entry(location(machine)).or_default() += 1;
+    }
+}
+
+pub struct Explorer<'a, S: Solver> {
+    start: Option<Machine<'a>>, // Added to the strategy on the first call to `next`
+    pub(crate) origin: Option<Machine<'a>>, // `start`, once it's been added
+    strategy: Box<dyn SearchStrategy<'a> + 'a>,
+    solver: S,
+}
+
+impl<'a, S: Solver> Explorer<'a, S> {
+    // Explores `machine`, which has its program loaded and symbols pushed,
+    // depth first.
+    pub fn new(machine: Machine<'a>, solver: S) -> Self {
+        Self::with_strategy(machine, solver, Box::new(DepthFirst::default()))
+    }
+
+    pub fn with_strategy(
+        machine: Machine<'a>,
+        solver: S,
+        strategy: Box<dyn SearchStrategy<'a> + 'a>,
+    ) -> Self {
+        Explorer {
+            start: Some(machine),
+            origin: None,
+            strategy,
+            solver,
+        }
+    }
+
+    // Explores the paths from here on with `strategy`, handing it the paths
+    // waiting to go on.
+    pub fn set_strategy(&mut self, mut strategy: Box<dyn SearchStrategy<'a> + 'a>) {
+        while let Some(state) = self.strategy.next_state() {
+            strategy.add_state(state);
+        }
+        self.strategy = strategy;
+    }
+
+    // Limits each path to `max_steps` instructions, ending longer ones with
+    // `StepLimitExceeded`, so that symbolic loops can't go on forever.
+    pub fn with_step_limit(mut self, max_steps: usize) -> Self {
+        if let Some(machine) = &mut self.start {
+            machine.budget = Some(max_steps);
+        }
+        self
+    }
+
+    // Runs `state` until it forks, adding it and its fork to the strategy,
+    // or reaches the end of its path.
+    fn explore(
+        &mut self,
+        mut state: PendingState<'a>,
+    ) -> Result<Option<TerminalState<'a>>, MachineError> {
+        loop {
+            let before = match branches_on_symbol(&mut state.machine) {
+                Ok(true) => Some(state.machine.clone()),
+                Ok(false) => None,
+                Err(e) => return Ok(Some(end(state, Err(e)))),
+            };
+            self.strategy.visit(&state.machine);
+            let decided = state.machine.path.len();
+            let step = state.machine.step_into();
+
+            // Forks even when the step failed, as `Assume` and `Assert` do
+            // once they've recorded the way they went.
+            let mut excluded = std::mem::take(&mut state.excluded);
+            let taken = state.machine.path.iter().nth(decided).cloned();
+            let forked = match (before, taken) {
+                (Some(before), Some(taken)) => {
+                    excluded.push(taken);
+                    self.fork(before, excluded)?;
+                    true
+                }
+                _ => false,
+            };
+            match step {
+                Ok(StepResult::Stepped { .. }) if forked => {
+                    self.strategy.add_state(state);
+                    return Ok(None);
+                }
+                Ok(StepResult::Stepped { .. }) => {}
+                Ok(StepResult::Finished) => {
+                    let result = state.machine.finish().map(|last| last.copied());
+                    return Ok(Some(end(state, result)));
+                }
+                Err(e) => return Ok(Some(end(state, Err(e)))),
+            }
+        }
+    }
+
+    // Queues `machine` to go another way than the `excluded` ones at the
+    // branch it's about to run, if it can.
+    fn fork(
+        &mut self,
+        mut machine: Machine<'a>,
+        excluded: Vec<Rc<Expr>>,
+    ) -> Result<(), MachineError> {
+        if let Some(model) = solve(&mut self.solver, machine.path.iter(), &excluded)?
+            && machine.concretize(&model)
+        {
+            self.strategy.add_state(PendingState { machine, excluded });
+        }
+        Ok(())
+    }
+}
+
+// Yields the end of each feasible path, or the solver's error.
+impl<'a, S: Solver> Iterator for Explorer<'a, S> {
+    type Item = Result<TerminalState<'a>, MachineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(machine) = self.start.take() {
+            self.origin = Some(machine.clone());
+            let excluded = Vec::new();
+            if machine.path.holds(&machine.symbol_values()) {
+                self.strategy.add_state(PendingState { machine, excluded });
+            } else if let Err(e) = self.fork(machine, excluded) {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let state = self.strategy.next_state()?;
+            match self.explore(state) {
+                Ok(None) => {}
+                // Paths that break an assumption aren't paths of the program.
+                Ok(Some(TerminalState {
+                    result: Err(MachineError::AssumptionViolated),
+                    ..
+                })) => {}
+                Ok(Some(terminal)) => return Some(Ok(terminal)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn end(state: PendingState, result: Result<Option<i64>, MachineError>) -> TerminalState {
+    TerminalState {
+        machine: state.machine,
+        result,
+    }
+}
+
+// Whether the next instruction is a branch deciding on a symbolic cell.
+fn branches_on_symbol(machine: &mut Machine) -> Result<bool, MachineError> {
+    let cell = match machine.next_instruction()? {
+        Some(
+            Instruction::BranchIfZero(cell, ..)
+            | Instruction::JumpIfZero(cell, _)
+            | Instruction::JumpIfNotZero(cell, _)
+            | Instruction::Assume(cell)
+            | Instruction::Assert(cell),
+        ) => *cell,
+        Some(Instruction::Switch { selector, .. }) => *selector,
+        // Division goes on or fails depending on whether the divisor is zero.
+        Some(
+            Instruction::AluBinary(BinaryOp::Div | BinaryOp::Mod, _, divisor)
+            | Instruction::DivMod { b: divisor, .. },
+        ) => *divisor,
+        Some(Instruction::AluNullary(NullaryOp::Cond)) => {
+            match machine.visible().len().checked_sub(1).map(Cell::try_from) {
+                Some(Ok(top)) => top,
+                _ => return Ok(false),
+            }
+        }
+        _ => return Ok(false),
+    };
+    Ok(machine.expression(cell).is_some())
+}
+
+// How far `find_input_reaching` looks: the paths it explores, the
+// instructions each may run, and the values it tries for each input.
+const REACHING_MAX_PATHS: usize = 1000;
+const REACHING_MAX_STEPS: usize = 10_000;
+const REACHING_RANGE: std::ops::RangeInclusive<i64> = -64..=64;
+
+/*
+ * Values for the named inputs in `symbolic` under which `program` runs the
+ * instruction at `target_index`, counting the instructions of each body
+ * right after the one holding it. Inputs not named stay unset. `None` if no
+ * path explored reaches the target.
+ */
+pub fn find_input_reaching(
+    program: &[Instruction],
+    target_index: usize,
+    symbolic: &[&str],
+) -> Result<Option<HashMap<String, i64>>, MachineError> {
+    let mut index = target_index;
+    let Some(target) = flattened(program, &mut index) else {
+        return Ok(None);
+    };
+    let mut machine = Machine::new();
+    let inputs: Vec<_> = (symbolic.iter())
+        .map(|&name| (name, machine.set_symbolic_input(name, 0)))
+        .collect();
+    machine.load_program(program);
+
+    let found = Rc::new(RefCell::new(None));
+    let strategy = Reaching {
+        inner: DepthFirst::default(),
+        target,
+        found: found.clone(),
+    };
+    let solver = BoundedSolver::new(REACHING_RANGE);
+    let mut explorer = Explorer::with_strategy(machine, solver, Box::new(strategy))
+        .with_step_limit(REACHING_MAX_STEPS);
+    for _ in 0..REACHING_MAX_PATHS {
+        if found.borrow().is_some() || explorer.next().transpose()?.is_none() {
+            break;
+        }
+    }
+
+    let Some(model) = found.take() else {
+        return Ok(None);
+    };
+    let values = (inputs.into_iter())
+        .map(|(name, symbol)| (name.to_string(), model.get(symbol).unwrap_or(0)))
+        .collect();
+    Ok(Some(values))
+}
+
+// The instruction `index` more along, counting from the start of `program`,
+// and the instructions of a body right after the one holding it.
+fn flattened<'p>(program: &'p [Instruction], index: &mut usize) -> Option<&'p Instruction> {
+    for instruction in program {
+        if *index == 0 {
+            return Some(instruction);
+        }
+        *index -= 1;
+        for body in bodies(instruction) {
+            if let Some(found) = flattened(body, index) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+// Explores depth first, keeping the values of the symbols of the first path
+// about to run `target`.
+struct Reaching<'a, 'p> {
+    inner: DepthFirst<'a>,
+    target: &'p Instruction,
+    found: Rc<RefCell<Option<Model>>>,
+}
+
+impl<'a> SearchStrategy<'a> for Reaching<'a, '_> {
+    fn add_state(&mut self, state: PendingState<'a>) {
+        self.inner.add_state(state);
+    }
+
+    fn next_state(&mut self) -> Option<PendingState<'a>> {
+        self.inner.next_state()
+    }
+
+    // Paths keep to the values of their symbols, so those of a path running
+    // the target drive a concrete run there too.
+    fn visit(&mut self, machine: &Machine<'a>) {
+        let next = machine.program.and_then(|program| program.get(machine.pc));
+        if next.is_some_and(|next| std::ptr::eq(next, self.target)) {
+            self.found
+                .borrow_mut()
+                .get_or_insert_with(|| machine.symbol_values());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp,
+        Instruction::*,
+        UnaryOpImm,
+        macros::{add_instr, make_block},
+        solver::BoundedSolver,
+        symbolic::SymValue,
+    };
+
+    fn results(explorer: Explorer<BoundedSolver>) -> Vec<Result<Option<i64>, MachineError>> {
+        explorer.map(|state| state.unwrap().result).collect()
+    }
+
+    #[test]
+    fn test_explore_branches() {
+        // Pushes 10 + s0 if s0 is zero, and 20 + s0 * s0 otherwise.
+        let program = vec![BranchIfZero(
+            0,
+            vec![add_instr!(Push, 10), add_instr!(Add, 0, 1)],
+            vec![make_block!(
+                add_instr!(Mul, 0, 0),
+                add_instr!(Push, 20),
+                add_instr!(Add, 1, 2)
+            )],
+        )];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(3).unwrap();
+        machine.load_program(&program);
+
+        let explorer = Explorer::new(machine, BoundedSolver::new(-4..=4));
+        let states: Vec<_> = explorer.map(Result::unwrap).collect();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].result.as_ref().unwrap(), &Some(29));
+        assert_eq!(
+            states[0].machine.path_constraints().to_string(),
+            "(s0 != 0)"
+        );
+
+        // The other path runs as if s0 had been zero all along.
+        assert_eq!(states[1].result.as_ref().unwrap(), &Some(10));
+        assert_eq!(
+            states[1].machine.path_constraints().to_string(),
+            "(s0 == 0)"
+        );
+        let SymValue::Sym(sum) = states[1].machine.symbolic_cell(1).unwrap() else {
+            panic!("the sum should be symbolic");
+        };
+        assert_eq!(sum.to_string(), "(s0 + 10)");
+    }
+
+    #[test]
+    fn test_explore_switch_and_jumps() {
+        let program = vec![
+            Switch {
+                selector: 0,
+                cases: vec![vec![add_instr!(Push, 100)], vec![add_instr!(Push, 101)]],
+                default: vec![add_instr!(Push, 102)],
+            },
+            JumpIfZero(0, 3),
+            add_instr!(Push, 7),
+        ];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(1).unwrap();
+        machine.load_program(&program);
+
+        let mut found = results(Explorer::new(machine, BoundedSolver::new(-2..=2)));
+        found.sort_by_key(|result| format!("{:?}", result));
+        // s0 = 0 skips the last push.
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0].as_ref().unwrap(), &Some(100));
+        assert_eq!(found[1].as_ref().unwrap(), &Some(7));
+        assert_eq!(found[2].as_ref().unwrap(), &Some(7));
+    }
+
+    #[test]
+    fn test_find_input_reaching() {
+        let program = vec![
+            Input(String::from("x")),
+            add_instr!(Push, 0),
+            add_instr!(SetGreaterThan, 0, 1),
+            BranchIfZero(
+                2,
+                vec![add_instr!(Push, 1)],
+                vec![add_instr!(Push, 2), add_instr!(Push, 3)], // Runs if x > 0
+            ),
+        ];
+
+        let inputs = find_input_reaching(&program, 6, &["x"]).unwrap().unwrap();
+        assert!(inputs["x"] > 0);
+        assert_eq!(
+            find_input_reaching(&program, 4, &["x"]).unwrap().unwrap()["x"],
+            0
+        );
+
+        assert_eq!(find_input_reaching(&program, 7, &["x"]).unwrap(), None);
+
+        // Checking x > 0 again once it wasn't.
+        let program = vec![
+            Input(String::from("x")),
+            add_instr!(Push, 0),
+            add_instr!(SetGreaterThan, 0, 1),
+            BranchIfZero(
+                2,
+                vec![
+                    add_instr!(SetGreaterThan, 0, 1),
+                    BranchIfZero(3, vec![add_instr!(Push, 1)], vec![add_instr!(Push, 2)]),
+                ],
+                vec![],
+            ),
+        ];
+        assert!(find_input_reaching(&program, 6, &["x"]).unwrap().is_some());
+        assert_eq!(find_input_reaching(&program, 7, &["x"]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_explore_division() {
+        fn explore(program: &[Instruction]) -> Vec<TerminalState<'_>> {
+            let mut machine = Machine::new();
+            machine.push_symbol(7).unwrap();
+            machine.push_symbol(2).unwrap();
+            machine.load_program(program);
+            let explorer = Explorer::new(machine, BoundedSolver::new(-4..=4));
+            explorer.map(Result::unwrap).collect::<Vec<_>>()
+        }
+
+        // s1 == 0 is a path of its own, ending with the witness.
+        let states = explore(&[add_instr!(Div, 0, 1)]);
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].result.as_ref().unwrap(), &Some(3));
+        assert_eq!(
+            states[0].machine.path_constraints().to_string(),
+            "(s1 != 0)"
+        );
+        assert!(matches!(
+            states[1].result,
+            Err(MachineError::DivisionByZero)
+        ));
+        assert_eq!(states[1].machine.symbol_values().get(1), Some(0));
+
+        // Once s1 can't be zero, the division only goes on.
+        let states = explore(&[Assume(1), add_instr!(Mod, 0, 1)]);
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].result.as_ref().unwrap(), &Some(1));
+    }
+
+    #[test]
+    fn test_global_constraints() {
+        // Pushes 1 if x < 0, and 2 otherwise.
+        let program = vec![
+            Input(String::from("x")),
+            add_instr!(Push, 0),
+            add_instr!(SetLessThan, 0, 1),
+            BranchIfZero(2, vec![add_instr!(Push, 2)], vec![add_instr!(Push, 1)]),
+        ];
+        let explore = |value: i64| {
+            let mut machine = Machine::new();
+            let x = machine.set_symbolic_input("x", value);
+            let positive = Expr::Binary(
+                BinaryOp::SetGreaterThan,
+                Rc::new(Expr::Symbol(x)),
+                Rc::new(Expr::Concrete(0)),
+            );
+            machine.add_global_constraint(Rc::new(positive));
+            machine.load_program(&program);
+            let explorer = Explorer::new(machine, BoundedSolver::new(-4..=4));
+            explorer.map(Result::unwrap).collect::<Vec<_>>()
+        };
+
+        // x < 0 can't hold, so only one path is left.
+        let states = explore(3);
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].result.as_ref().unwrap(), &Some(2));
+        assert_eq!(
+            states[0].machine.path_constraints().to_string(),
+            "(s0 > 0) && ((s0 < 0) == 0)"
+        );
+
+        // Starting from a value breaking the constraint starts from another.
+        let states = explore(-3);
+        assert_eq!(states.len(), 1);
+        assert!(states[0].machine.symbol_values().get(0) > Some(0));
+    }
+
+    #[test]
+    fn test_explore_step_limit() {
+        // Loops forever unless s0 is 3.
+        let program = vec![
+            add_instr!(Push, 3),
+            add_instr!(Sub, 0, 1),
+            JumpIfNotZero(2, 2),
+        ];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(2).unwrap();
+        machine.load_program(&program);
+
+        let explorer = Explorer::new(machine, BoundedSolver::new(0..=5)).with_step_limit(20);
+        let found = results(explorer);
+        assert_eq!(found.len(), 2);
+        assert!(matches!(found[0], Err(MachineError::StepLimitExceeded)));
+        assert_eq!(found[1].as_ref().unwrap(), &Some(0));
+    }
+
+    #[test]
+    fn test_search_strategies() {
+        // Loops forever unless s0 is 3, forking at every iteration.
+        let program = vec![
+            add_instr!(Push, 3),
+            add_instr!(Sub, 0, 1),
+            JumpIfNotZero(2, 0),
+        ];
+        fn explore<'a>(
+            program: &'a [Instruction],
+            strategy: Box<dyn SearchStrategy<'a> + 'a>,
+        ) -> Vec<Result<Option<i64>, MachineError>> {
+            let mut machine = Machine::new();
+            machine.push_symbol(0).unwrap();
+            machine.load_program(program);
+            let explorer = Explorer::with_strategy(machine, BoundedSolver::new(0..=5), strategy);
+            results(explorer.with_step_limit(50))
+        }
+
+        // Depth first stays in the loop until the limit.
+        let found = explore(&program, Box::new(DepthFirst::default()));
+        assert!(matches!(found[0], Err(MachineError::StepLimitExceeded)));
+        assert_eq!(found[1].as_ref().unwrap(), &Some(0));
+
+        for strategy in [
+            Box::new(BreadthFirst::default()) as Box<dyn SearchStrategy>,
+            Box::new(CoverageGuided::default()),
+        ] {
+            let found = explore(&program, strategy);
+            assert_eq!(found.len(), 2);
+            assert_eq!(found[0].as_ref().unwrap(), &Some(0));
+            assert!(matches!(found[1], Err(MachineError::StepLimitExceeded)));
+        }
+
+        let mut found = explore(&program, Box::new(RandomPath::new(7)));
+        assert_eq!(found.len(), 2);
+        found.sort_by_key(Result::is_err);
+        assert_eq!(found[0].as_ref().unwrap(), &Some(0));
+    }
+
+    #[test]
+    fn test_exploration_order() {
+        // Pushes 10 or 20 as s0 is zero or not, then adds 1 or 2 as s1 is.
+        let program = vec![
+            BranchIfZero(0, vec![add_instr!(Push, 10)], vec![add_instr!(Push, 20)]),
+            BranchIfZero(
+                1,
+                vec![add_instr!(Push, 1), add_instr!(Add, 2, 3)],
+                vec![add_instr!(Push, 2), add_instr!(Add, 2, 3)],
+            ),
+        ];
+        fn explore<'a>(
+            program: &'a [Instruction],
+            strategy: Box<dyn SearchStrategy<'a> + 'a>,
+        ) -> Vec<i64> {
+            let mut machine = Machine::new();
+            machine.push_symbol(1).unwrap();
+            machine.push_symbol(1).unwrap();
+            machine.load_program(program);
+            let mut explorer = Explorer::new(machine, BoundedSolver::new(-2..=2));
+            explorer.set_strategy(strategy);
+            (explorer.map(|state| state.unwrap().result.unwrap().unwrap())).collect()
+        }
+
+        // Depth first ends the path it started on, then goes back to the
+        // latest fork: s1 == 0 first, then s0 == 0 and its own fork.
+        assert_eq!(
+            explore(&program, Box::new(DepthFirst::default())),
+            vec![22, 21, 12, 11]
+        );
+        // Breadth first takes turns, so the two paths from the first branch
+        // end before the two forked at the second.
+        assert_eq!(
+            explore(&program, Box::new(BreadthFirst::default())),
+            vec![22, 12, 21, 11]
+        );
+        // No path runs an instruction more often than the others here, so
+        // coverage goes in the order paths were added, as breadth first does.
+        assert_eq!(
+            explore(&program, Box::new(CoverageGuided::default())),
+            vec![22, 12, 21, 11]
+        );
+        assert_eq!(
+            explore(&program, Box::new(RandomPath::new(7))),
+            vec![22, 11, 12, 21]
+        );
+    }
+
+    #[test]
+    fn test_explore_assumptions() {
+        // Assumes s0 > 5, then asserts s0 < 8.
+        let program = vec![
+            add_instr!(Push, 5),
+            add_instr!(SetGreaterThan, 0, 1),
+            Assume(2),
+            add_instr!(Push, 8),
+            add_instr!(SetLessThan, 0, 3),
+            Assert(4),
+        ];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(6).unwrap();
+        machine.load_program(&program);
+
+        let found = results(Explorer::new(machine, BoundedSolver::new(-10..=10)));
+        assert_eq!(found.len(), 2); // Paths with s0 <= 5 are dropped
+        assert_eq!(found[0].as_ref().unwrap(), &Some(1));
+        let Err(MachineError::AssertionFailure(model)) = &found[1] else {
+            panic!("s0 >= 8 should fail the assertion");
+        };
+        assert_eq!(model.get(0), Some(8));
+    }
+}
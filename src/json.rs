@@ -0,0 +1,90 @@
+/*
+ * JSON encoding of programs, for programs that should stay readable (and
+ * editable) once saved. `bytecode` is the compact alternative. Test cases
+ * generated by exploration are saved the same way.
+ *
+ * Instructions are encoded the way serde derives them: a unit variant as its
+ * name, any other variant as an object with the variant name as its only key.
+ */
+
+use crate::{Instruction, MachineError, testgen::TestCase};
+
+pub fn encode_program(program: &[Instruction]) -> Vec<u8> {
+    serde_json::to_vec(program).expect("programs are always serializable")
+}
+
+// Decodes a program produced by `encode_program`, failing with an
+// `InstructionError` if the bytes don't describe one.
+pub fn decode_program(bytes: &[u8]) -> Result<Vec<Instruction>, MachineError> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| MachineError::InstructionError(format!("Malformed program: {}", e)))
+}
+
+pub fn encode_tests(tests: &[TestCase]) -> Vec<u8> {
+    serde_json::to_vec(tests).expect("test cases are always serializable")
+}
+
+pub fn decode_tests(bytes: &[u8]) -> Result<Vec<TestCase>, MachineError> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| MachineError::InstructionError(format!("Malformed test cases: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp, FunctionOp,
+        Instruction::*,
+        Machine, UnaryOpCell, UnaryOpImm,
+        macros::{add_instr, make_block},
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let program = vec![
+            add_instr!(fun FunctionDefine, String::from("square \"twice\"")),
+            make_block!(
+                add_instr!(R ReadReverse, 0),
+                make_block!(add_instr!(Mul, 0, 0)),
+                Block(vec![])
+            ),
+            add_instr!(Push, -5),
+            add_instr!(fun FunctionCall, String::from("square \"twice\"")),
+            Block(vec![]),
+        ];
+
+        let decoded = decode_program(&encode_program(&program)).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", program));
+        assert_eq!(Machine::load_and_run(&decoded).unwrap(), Some(25));
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        for bytes in [&b""[..], b"[", b"[{\"Block\": 3}]", b"[{\"Nope\": []}]"] {
+            assert!(matches!(
+                decode_program(bytes),
+                Err(MachineError::InstructionError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_tests_round_trip() {
+        let tests = vec![
+            TestCase {
+                cells: vec![3, -1],
+                inputs: vec![(String::from("x"), 7)],
+                max_steps: None,
+                expected: Ok(vec![2]),
+            },
+            TestCase {
+                cells: vec![],
+                inputs: vec![],
+                max_steps: Some(10),
+                expected: Err(String::from("step limit exceeded")),
+            },
+        ];
+        assert_eq!(decode_tests(&encode_tests(&tests)).unwrap(), tests);
+        assert!(decode_tests(b"[{\"cells\": []}]").is_err());
+    }
+}
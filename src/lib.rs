@@ -14,7 +14,34 @@
  * However, pop can be used to free up cells when needed.
  */
 
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug, Display},
+    rc::Rc,
+};
+
+pub mod analysis;
+pub mod asm;
+pub mod bytecode;
+pub mod compile;
+pub mod concolic;
+pub mod explore;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod llvm;
+pub mod minimize;
+pub mod pretty;
+pub mod replay;
+pub mod repro;
+pub mod scan;
+pub mod solver;
+pub mod symbolic;
+pub mod taint;
+pub mod testgen;
+pub mod trace;
+#[cfg(feature = "z3")]
+pub mod z3;
 
 pub type Cell = u16;
 pub type Immediate = i64;
@@ -34,69 +61,335 @@ pub enum MachineError {
     InstructionError(String),
     OtherError(String),
     ProgramNotLoaded,
+    AssertionFailed(i64, i64),
+    InvalidRange,
+    NoResult,
+    DivisionOverflow,
+    ChecksumMismatch,
+    InternalInconsistency,
+    ImmutableCell,
+    UnbalancedScopes,
+    IntegerOverflow,
+    StepLimitExceeded,
+    RecursionLimitExceeded,
+    InvalidAddress,
+    InvalidJumpTarget(Address),
+    SolverError(String),
+    OutOfFuel,
+    AssumptionViolated,
+    AssertionFailure(solver::Model), // The values of the symbols that make the assertion fail
+    UnsupportedVersion(u8),          // Of the bytecode format
 }
 
-#[derive(Debug, Clone)]
-pub enum NullaryOp {
-    Nop,
-    Rebase,
-    Cond,
-}
+impl Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use MachineError::*;
 
-#[derive(Debug, Clone)]
-pub enum UnaryOpCell {
-    Not,
-    Read,
-    ReadReverse,
-    Tail, // Tail-call a function.
+        match self {
+            StackUnderflow => write!(f, "stack underflow"),
+            InvalidCell => write!(f, "invalid cell"),
+            DivisionByZero => write!(f, "division by zero"),
+            NoSavedCells => write!(f, "no saved cells to restore"),
+            RebaseError => write!(f, "rebase outside of the current cells"),
+            NoRebasedCells => write!(f, "no rebased cells"),
+            FunctionRedefinition => write!(f, "function redefined"),
+            FunctionUndefined => write!(f, "call to an undefined function"),
+            FunctionCallError => write!(f, "function call failed"),
+            InstructionError(message) | OtherError(message) => write!(f, "{}", message),
+            ProgramNotLoaded => write!(f, "no program loaded"),
+            AssertionFailed(a, b) => write!(f, "assertion failed: {} != {}", a, b),
+            InvalidRange => write!(f, "invalid range"),
+            NoResult => write!(f, "the run left no result"),
+            DivisionOverflow => write!(f, "division overflow"),
+            ChecksumMismatch => write!(f, "checksum mismatch"),
+            InternalInconsistency => write!(f, "internal inconsistency"),
+            ImmutableCell => write!(f, "write to an immutable cell"),
+            UnbalancedScopes => write!(f, "run ended with scopes still open"),
+            IntegerOverflow => write!(f, "integer overflow"),
+            StepLimitExceeded => write!(f, "step limit exceeded"),
+            RecursionLimitExceeded => write!(f, "recursion limit exceeded"),
+            InvalidAddress => write!(f, "invalid memory address"),
+            InvalidJumpTarget(target) => write!(f, "invalid jump target {}", target),
+            SolverError(message) => write!(f, "solver: {}", message),
+            OutOfFuel => write!(f, "out of fuel"),
+            AssumptionViolated => write!(f, "assumption violated"),
+            AssertionFailure(model) if model.is_empty() => write!(f, "assertion failed"),
+            AssertionFailure(model) => write!(f, "assertion failed for {}", model),
+            UnsupportedVersion(version) => write!(f, "unsupported bytecode version {}", version),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-pub enum UnaryOpImm {
-    Push,
-    Pop,
-}
+impl std::error::Error for MachineError {}
 
-#[derive(Debug, Clone)]
-pub enum BinaryOp {
-    // Arithmetic instructions
-    Add,
-    Mul,
-    Div,
-    // Bitwise instructions
-    And,
-    Or,
-    Xor,
-    // Shifting
-    ShiftLeftLogical,
-    ShiftRightLogical,
-    ShiftRightArithmetic,
-    // Comparison ; Good enough for early stage of development
-    SetEqual,
-    SetNotEqual,
-    SetLessThan,
-    SetLessThanOrEqual,
-    SetGreaterThan,
-    SetGreaterThanOrEqual,
-}
+/*
+ * The instruction set is described once, in the `instruction_set!` table below.
+ * From it we generate the `Instruction` enum and every operator enum, the
+ * `Opcode` enum that names each kind of `Instruction` along with
+ * `Instruction::opcode`, and the mnemonic <-> opcode mappings shared by
+ * anything that reads or writes programs (assembler, disassembler, encoder).
+ *
+ * Operators are grouped by the `Instruction` variant that carries them, given
+ * with the operands it holds besides the operator. The `standalone` group
+ * lists the variants that don't carry an operator enum, with their fields.
+ */
+macro_rules! instruction_set {
+    (
+        $(
+            enum $enum:ident in $carrier:ident ( $( $carrier_field:ty ),* ) {
+                $( $(#[$variant_meta:meta])* $variant:ident = $opcode:literal => $mnemonic:literal, )*
+            }
+        )*
+        standalone {
+            $(
+                $standalone:ident
+                $( ( $( $tuple_field:ty ),* ) )?
+                $( { $( $field:ident : $field_type:ty ),* $(,)? } )?
+                = $standalone_opcode:literal => $standalone_mnemonic:literal,
+            )*
+        }
+    ) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub enum Instruction {
+            $( $carrier($enum, $( $carrier_field ),*), )*
+            $(
+                $standalone
+                $( ( $( $tuple_field ),* ) )?
+                $( { $( $field: $field_type ),* } )?,
+            )*
+        }
 
-#[derive(Debug, Clone)]
-pub enum FunctionOp {
-    FunctionDefine,
-    FunctionCall,
+        impl Instruction {
+            pub fn opcode(&self) -> Opcode {
+                match self {
+                    $( Instruction::$carrier(op, ..) => op.opcode(), )*
+                    $( Instruction::$standalone { .. } => Opcode::$standalone, )*
+                }
+            }
+        }
+
+        $(
+            #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+            #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+            pub enum $enum {
+                $( $(#[$variant_meta])* $variant, )*
+            }
+
+            impl $enum {
+                pub const ALL: &'static [$enum] = &[$( $enum::$variant, )*];
+
+                pub fn opcode(&self) -> Opcode {
+                    match self {
+                        $( $enum::$variant => Opcode::$variant, )*
+                    }
+                }
+
+                pub fn mnemonic(&self) -> &'static str {
+                    self.opcode().mnemonic()
+                }
+
+                pub fn from_opcode(opcode: Opcode) -> Option<Self> {
+                    match opcode {
+                        $( Opcode::$variant => Some($enum::$variant), )*
+                        _ => None,
+                    }
+                }
+
+                pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+                    match mnemonic {
+                        $( $mnemonic => Some($enum::$variant), )*
+                        _ => None,
+                    }
+                }
+            }
+        )*
+
+        #[repr(u8)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Opcode {
+            $( $( $variant = $opcode, )* )*
+            $( $standalone = $standalone_opcode, )*
+        }
+
+        impl Opcode {
+            pub const ALL: &'static [Opcode] = &[
+                $( $( Opcode::$variant, )* )*
+                $( Opcode::$standalone, )*
+            ];
+
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    $( $( Opcode::$variant => $mnemonic, )* )*
+                    $( Opcode::$standalone => $standalone_mnemonic, )*
+                }
+            }
+
+            pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+                match mnemonic {
+                    $( $( $mnemonic => Some(Opcode::$variant), )* )*
+                    $( $standalone_mnemonic => Some(Opcode::$standalone), )*
+                    _ => None,
+                }
+            }
+
+            pub fn from_byte(byte: u8) -> Option<Self> {
+                match byte {
+                    $( $( $opcode => Some(Opcode::$variant), )* )*
+                    $( $standalone_opcode => Some(Opcode::$standalone), )*
+                    _ => None,
+                }
+            }
+        }
+    };
 }
 
-#[derive(Debug, Clone)]
-pub enum Instruction {
-    AluNullary(NullaryOp),
-    AluUnaryImm(UnaryOpImm, Immediate),
-    AluUnaryCell(UnaryOpCell, Cell),
-    AluBinary(BinaryOp, Cell, Cell),
-    Block(Vec<Instruction>),
-    AluFunction(FunctionOp, String),
+instruction_set! {
+    enum NullaryOp in AluNullary() {
+        Nop = 0x00 => "nop",
+        Rebase = 0x01 => "rebase",
+        Cond = 0x02 => "cond",
+        DupWindow = 0x03 => "dupwin", // Pushes a copy of every cell from base to the top.
+        InstructionCount = 0x04 => "icount", // Pushes the number of instructions run so far.
+    }
+    enum UnaryOpImm in AluUnaryImm(Immediate) {
+        Push = 0x10 => "push",
+        Pop = 0x11 => "pop",
+    }
+    enum UnaryOpCell in AluUnaryCell(Cell) {
+        Not = 0x20 => "not",
+        Read = 0x21 => "read",
+        ReadReverse = 0x22 => "readrev",
+        ToBool = 0x24 => "bool", // 1 if the cell is nonzero, 0 otherwise.
+        Neg = 0x25 => "neg",
+    }
+    enum BinaryOp in AluBinary(Cell, Cell) {
+        // Arithmetic instructions
+        Add = 0x40 => "add",
+        Mul = 0x41 => "mul",
+        Div = 0x42 => "div",
+        Sub = 0x43 => "sub",
+        Mod = 0x44 => "mod", // Remainder, with the sign of the dividend.
+        // Bitwise instructions
+        And = 0x48 => "and",
+        Or = 0x49 => "or",
+        Xor = 0x4a => "xor",
+        // Shifting
+        ShiftLeftLogical = 0x50 => "sll",
+        ShiftRightLogical = 0x51 => "srl",
+        ShiftRightArithmetic = 0x52 => "sra",
+        // Comparison ; Good enough for early stage of development
+        SetEqual = 0x58 => "seq",
+        SetNotEqual = 0x59 => "sne",
+        SetLessThan = 0x5a => "slt",
+        SetLessThanOrEqual = 0x5b => "sle",
+        SetGreaterThan = 0x5c => "sgt",
+        SetGreaterThanOrEqual = 0x5d => "sge",
+        // The same comparisons, reading both cells as u64
+        SetLessThanUnsigned = 0x5e => "sltu",
+        SetLessThanOrEqualUnsigned = 0x5f => "sleu",
+        SetGreaterThanUnsigned = 0x60 => "sgtu",
+        SetGreaterThanOrEqualUnsigned = 0x61 => "sgeu",
+    }
+    enum FunctionOp in AluFunction(String) {
+        FunctionDefine = 0x80 => "fn",
+        FunctionCall = 0x81 => "call",
+        Tail = 0x82 => "tail", // Calls a function in place of the current one.
+    }
+    standalone {
+        Block(Vec<Instruction>) = 0xa0 => "block",
+        // Pushes start, start + 1, ..., start + count - 1
+        PushRange { start: Immediate, count: u32 } = 0xa1 => "pushrange",
+        // Errors unless both cells hold the same value
+        AssertEq { a: Cell, b: Cell } = 0xa2 => "asserteq",
+        // Pushes value clamped to [lo, hi]
+        Clamp { value: Cell, lo: Cell, hi: Cell } = 0xa3 => "clamp",
+        // Overwrites an existing cell in place
+        SetImm { dest: Cell, value: Immediate } = 0xa4 => "setimm",
+        // Pushes bit (bit & 63) of value, as 0 or 1
+        TestBit { value: Cell, bit: Cell } = 0xa5 => "testbit",
+        // Pushes the result of a registered host function
+        HostCall { name: String, args: Vec<Cell> } = 0xa6 => "hostcall",
+        // Pushes the named input, 0 if it wasn't set
+        Input(String) = 0xa7 => "input",
+        // Pushes 1 if the body runs without error, else 0
+        Succeeds(Vec<Instruction>) = 0xa8 => "succeeds",
+        // Pops the top n cells and pushes their sum
+        SumTop(u16) = 0xa9 => "sumtop",
+        // Runs cases[selector], or default if out of range, like a block
+        Switch {
+            selector: Cell,
+            cases: Vec<Vec<Instruction>>,
+            default: Vec<Instruction>,
+        } = 0xaa => "switch",
+        // Pushes a / b, then a % b
+        DivMod { a: Cell, b: Cell } = 0xab => "divmod",
+        // Pushes the result of a registered unary host function
+        HostUnary { name: String, arg: Cell } = 0xac => "hostunary",
+        // Pops as many cells as the given cell holds
+        PopDynamic(Cell) = 0xad => "popdyn",
+        // Reverses the top n cells in place. Unlike other instructions it changes
+        // cells that were already pushed, so a cell's value isn't fixed anymore.
+        ReverseTop(u16) = 0xae => "revtop",
+        // Pushes the midpoint of a and b, rounded toward a, without overflowing
+        Average { a: Cell, b: Cell } = 0xaf => "avg",
+        // Pushes a * x + b, with the product kept exact until the addition
+        Fma { a: Cell, x: Cell, b: Cell } = 0xb0 => "fma",
+        // Pushes a 64-bit FNV-1a hash of the cells start..start + len
+        HashRange { start: Cell, len: u16 } = 0xb1 => "hash",
+        // Pushes a mask with bit i set when cell start + i equals value, for up to
+        // 64 cells
+        MatchMask { value: Cell, start: Cell, len: u16 } = 0xb2 => "matchmask",
+        // Runs the first body if the cell is zero, else the second, like a block
+        BranchIfZero(Cell, Vec<Instruction>, Vec<Instruction>) = 0xb3 => "brz",
+        // Writes the first cell's value to memory at the address in the second
+        Store(Cell, Cell) = 0xb4 => "store",
+        // Pushes the memory word at the address in the cell
+        Load(Cell) = 0xb5 => "load",
+        // Defines a function like `FunctionDefine`, whose body only sees the given
+        // number of cells from the top of the caller's
+        FunctionDefineArity(String, u16) = 0xb6 => "fnarity",
+        // Continues the running body at the instruction with the given index;
+        // jumping to its length ends the body
+        Jump(Address) = 0xb7 => "jmp",
+        // Jumps like `Jump` if the cell is zero
+        JumpIfZero(Cell, Address) = 0xb8 => "jz",
+        // Jumps like `Jump` if the cell is nonzero
+        JumpIfNotZero(Cell, Address) = 0xb9 => "jnz",
+        // Leaves the innermost function, and the blocks inside it, without a
+        // result; outside functions, ends the program
+        Return = 0xba => "ret",
+        // Leaves like `Return`, pushing the cell's value to the caller
+        ReturnCell(Cell) = 0xbb => "retcell",
+        // Stops the run with `AssumptionViolated` if the cell is zero; a symbolic
+        // cell's condition joins the path constraints instead
+        Assume(Cell) = 0xbc => "assume",
+        // Fails with `AssertionFailure` if the cell is zero, or, with a solver
+        // set, if it could be zero on the path the run took
+        Assert(Cell) = 0xbd => "assert",
+    }
 }
 
 impl<'a> Instruction {
+    // Where a jump may continue the body it's in.
+    pub(crate) fn jump_target(&self) -> Option<Address> {
+        match self {
+            Instruction::Jump(target)
+            | Instruction::JumpIfZero(_, target)
+            | Instruction::JumpIfNotZero(_, target) => Some(*target),
+            _ => None,
+        }
+    }
+
+    // The name bound by a function definition, with its arity when declared.
+    pub(crate) fn definition(&self) -> Option<(&String, Option<u16>)> {
+        match self {
+            Instruction::AluFunction(FunctionOp::FunctionDefine, name) => Some((name, None)),
+            Instruction::FunctionDefineArity(name, arity) => Some((name, Some(*arity))),
+            _ => None,
+        }
+    }
+
     fn eval(&'a self, machine: &mut Machine<'a>) -> Result<(), MachineError> {
         use Instruction::*;
 
@@ -106,31 +399,229 @@ impl<'a> Instruction {
             AluUnaryCell(unop_reg, reg) => unop_reg.eval(machine, *reg)?,
             AluBinary(binop, reg1, reg2) => binop.eval(machine, (*reg1, *reg2))?,
             Block(instructions) => {
-                /* NOTE:
-                 * Since it is likely that more pops than pushes occur, we must
-                 * save the ENTIRE state of cells, copying it twice.
-                 */
+                // The body runs in a new frame, saving the caller's cells only as it changes them.
+                machine.enter(FrameKind::Block, instructions)?;
+            }
+            AluFunction(function_op, name) => {
+                function_op.eval(machine, name.clone())?;
+            }
+            FunctionDefineArity(..) => define_functions(machine)?,
+            Jump(target) => machine.jump(*target)?,
+            Return => machine.return_from(None)?,
+            ReturnCell(cell) => machine.return_from(Some(*cell))?,
+            Assume(cell) => {
+                let value = machine.read(*cell)?;
+                machine.constrain_zero(*cell, value);
+                if value == 0 {
+                    return Err(MachineError::AssumptionViolated);
+                }
+            }
+            Assert(cell) => machine.assert(*cell)?,
+            JumpIfZero(cell, target) | JumpIfNotZero(cell, target) => {
+                let value = machine.read(*cell)?;
+                machine.constrain_zero(*cell, value);
+                if (value == 0) == matches!(self, JumpIfZero(..)) {
+                    machine.jump(*target)?;
+                }
+            }
+            PushRange { start, count } => {
+                if *count == 0 {
+                    return Ok(());
+                }
 
-                let mut block_machine = Machine::from(machine.cells.clone());
-                block_machine.load_program(instructions);
-                block_machine.function_data = machine.function_data.clone();
-                block_machine.base_stack.push(machine.base);
-                block_machine.base = block_machine.cells.len();
+                let last = start.checked_add(Immediate::from(*count) - 1).ok_or(
+                    MachineError::InstructionError(String::from("PushRange overflows i64")),
+                )?;
 
-                let block_result = block_machine.run()?;
+                for value in *start..=last {
+                    machine.push(value)?;
+                }
+            }
+            AssertEq { a, b } => machine.assert_eq(*a, *b)?,
+            Clamp { value, lo, hi } => {
+                let value = (machine.read(*value)?, machine.expression(*value));
+                let lo = (machine.read(*lo)?, machine.expression(*lo));
+                let hi = (machine.read(*hi)?, machine.expression(*hi));
 
-                if let Some(val) = block_result {
-                    machine.push(*val)?;
+                if lo.0 > hi.0 {
+                    return Err(MachineError::InvalidRange);
                 }
 
-                machine.base = block_machine
-                    .base_stack
-                    .pop()
-                    .ok_or(MachineError::RebaseError)?
-                    .clone();
+                let clamped = value.0.clamp(lo.0, hi.0);
+                machine.set_expression(symbolic::clamp(value, lo, hi));
+                machine.push(clamped)?;
             }
-            AluFunction(function_op, name) => {
-                function_op.eval(machine, name.clone())?;
+            SetImm { dest, value } => {
+                machine.write(*dest, *value)?;
+            }
+            TestBit { value, bit } => {
+                let value = machine.read(*value)?;
+                let bit = machine.read(*bit)? & 63;
+                machine.push((value >> bit) & 1)?;
+            }
+            HostCall { name, args } => {
+                let function = machine
+                    .host_functions
+                    .0
+                    .get(name)
+                    .cloned()
+                    .ok_or(MachineError::FunctionUndefined)?;
+                let args = args
+                    .iter()
+                    .map(|&arg| machine.read(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                machine.push(function(&args)?)?;
+            }
+            Input(name) => {
+                let value = machine.inputs.get(name).copied().unwrap_or(0);
+                machine.set_expression(machine.symbolic_input(name));
+                machine.push(value)?;
+            }
+            Succeeds(instructions) => {
+                machine.enter(FrameKind::Succeeds, instructions)?;
+            }
+            SumTop(n) => {
+                let len = machine.visible().len();
+                let start = len
+                    .checked_sub(usize::from(*n))
+                    .ok_or(MachineError::StackUnderflow)?;
+                let mut sum: i64 = 0;
+                for reg in start..len {
+                    let reg = Cell::try_from(reg).map_err(|_| MachineError::InvalidCell)?;
+                    let value = machine.read(reg)?;
+                    sum = machine
+                        .overflow_checked(sum.checked_add(value), sum.wrapping_add(value))?;
+                }
+                machine.multi_pop(Immediate::from(*n))?;
+                machine.push(sum)?;
+            }
+            Switch {
+                selector,
+                cases,
+                default,
+            } => {
+                let cell = *selector;
+                let selector = machine.read(cell)?;
+                let case = usize::try_from(selector)
+                    .ok()
+                    .and_then(|index| cases.get(index));
+                match case {
+                    Some(_) => machine.constrain(cell, BinaryOp::SetEqual, selector),
+                    None => {
+                        // Negative selectors are huge as u64.
+                        let count = i64::try_from(cases.len()).unwrap_or(i64::MAX);
+                        machine.constrain(cell, BinaryOp::SetGreaterThanOrEqualUnsigned, count);
+                    }
+                }
+                machine.enter(FrameKind::Block, case.unwrap_or(default))?;
+            }
+            BranchIfZero(cell, then, otherwise) => {
+                let value = machine.read(*cell)?;
+                machine.constrain_zero(*cell, value);
+                let body = match value {
+                    0 => then,
+                    _ => otherwise,
+                };
+                machine.enter(FrameKind::Block, body)?;
+            }
+            Store(value, address) => {
+                let value = machine.read(*value)?;
+                let address = machine.address(*address)?;
+                machine.memory[address] = value;
+            }
+            Load(address) => {
+                let address = machine.address(*address)?;
+                machine.push(machine.memory[address])?;
+            }
+            DivMod { a, b: divisor } => {
+                let a = machine.read(*a)?;
+                let b = machine.read(*divisor)?;
+                machine.constrain_zero(*divisor, b);
+                if b == 0 {
+                    return Err(MachineError::DivisionByZero);
+                }
+
+                let quotient = a.checked_div(b).ok_or(MachineError::DivisionOverflow)?;
+                machine.push(quotient)?;
+                machine.push(a % b)?;
+            }
+            Average { a, b } => {
+                let a = i128::from(machine.read(*a)?);
+                let b = i128::from(machine.read(*b)?);
+                // Halving truncates toward zero, so toward a; the midpoint
+                // always lies between a and b and fits back into an i64.
+                let midpoint = a + (b - a) / 2;
+                machine.push(midpoint as i64)?;
+            }
+            Fma { a, x, b } => {
+                let product = i128::from(machine.read(*a)?) * i128::from(machine.read(*x)?);
+                let sum = product + i128::from(machine.read(*b)?);
+                // Only the final result can overflow.
+                let sum = machine.overflow_checked(i64::try_from(sum).ok(), sum as i64)?;
+                machine.push(sum)?;
+            }
+            HashRange { start, len } => {
+                let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+                for offset in 0..*len {
+                    let cell = start.checked_add(offset).ok_or(MachineError::InvalidCell)?;
+                    for byte in machine.read(cell)?.to_le_bytes() {
+                        hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+                    }
+                }
+                machine.push(hash as i64)?;
+            }
+            MatchMask { value, start, len } => {
+                if *len > 64 {
+                    return Err(MachineError::InstructionError(format!(
+                        "MatchMask window of {} cells exceeds 64",
+                        len
+                    )));
+                }
+                let value = machine.read(*value)?;
+                let mut mask: u64 = 0;
+                for offset in 0..*len {
+                    let cell = start.checked_add(offset).ok_or(MachineError::InvalidCell)?;
+                    if machine.read(cell)? == value {
+                        mask |= 1 << offset;
+                    }
+                }
+                machine.push(mask as i64)?;
+            }
+            ReverseTop(n) => {
+                let start = machine
+                    .visible()
+                    .len()
+                    .checked_sub(usize::from(*n))
+                    .ok_or(MachineError::StackUnderflow)?;
+                if (machine.immutable.iter()).any(|&cell| usize::from(cell) >= start) {
+                    return Err(MachineError::ImmutableCell);
+                }
+                let start = machine.window + start;
+                machine.preserve(start);
+                machine.cells[start..].reverse();
+                if let Some(taint) = &mut machine.taint {
+                    taint.reverse_from(start);
+                }
+                if let Some(symbolic) = &mut machine.symbolic {
+                    symbolic.reverse_from(start);
+                }
+            }
+            PopDynamic(cell) => {
+                let count = machine.read(*cell)?;
+                let len = machine.visible().len();
+                if count > Immediate::try_from(len).unwrap_or(Immediate::MAX) {
+                    return Err(MachineError::StackUnderflow);
+                }
+                machine.multi_pop(count)?;
+            }
+            HostUnary { name, arg } => {
+                let function = *machine
+                    .unary_functions
+                    .get(name)
+                    .ok_or(MachineError::FunctionUndefined)?;
+                let value = machine.read(*arg)?;
+                machine.push(function(value))?;
             }
         }
 
@@ -156,6 +647,14 @@ impl Operator for NullaryOp {
                 machine.rebase()?;
             }
             Cond => {
+                let top = machine.symbolic.as_ref().and_then(symbolic::Symbolic::last);
+                if let (Some(expr), Some(&value)) = (top, machine.visible().last()) {
+                    let op = match value {
+                        1 => BinaryOp::SetEqual,
+                        _ => BinaryOp::SetNotEqual,
+                    };
+                    machine.path.push(expr, op, 1);
+                }
                 match machine.pop() {
                     Some(1) => {}
                     Some(_) => {
@@ -164,6 +663,32 @@ impl Operator for NullaryOp {
                     None => return Err(MachineError::StackUnderflow),
                 }
             }
+            DupWindow => {
+                // The window starts at the base of the current block, or at
+                // the bottom of the stack once the block has rebased.
+                let start = machine.window + machine.base;
+                let end = machine.cells.len();
+                if start > end {
+                    return Err(MachineError::RebaseError);
+                }
+                // Each copy keeps the taint and expression of its cell.
+                for position in start..end {
+                    if let Some(taint) = &mut machine.taint {
+                        taint.set_pending(false);
+                        taint.read(position);
+                    }
+                    let expr = machine
+                        .symbolic
+                        .as_ref()
+                        .and_then(|symbolic| symbolic.get(position));
+                    machine.set_expression(expr);
+                    machine.push(machine.cells[position])?;
+                }
+            }
+            InstructionCount => {
+                let count = Immediate::try_from(machine.executed).unwrap_or(Immediate::MAX);
+                machine.push(count)?;
+            }
         }
         Ok(())
     }
@@ -174,26 +699,41 @@ impl Operator for UnaryOpCell {
     fn eval(&self, machine: &mut Machine, arg: Self::ArgType) -> Result<(), MachineError> {
         use UnaryOpCell::*;
 
+        let expr = machine.expression(arg).map(|expr| match self {
+            Read | ReadReverse => expr,
+            _ => Rc::new(symbolic::Expr::Unary(*self, expr)),
+        });
+        machine.set_expression(expr);
+
         match self {
             Not => {
-                let val = !*machine.read(arg)?;
+                let val = !machine.read(arg)?;
                 machine.push(val)?;
             }
             Read => {
-                let val = *machine.read(arg)?;
+                let val = machine.read(arg)?;
                 machine.push(val)?;
             }
             ReadReverse => {
                 // like python's negative indexing.
-                let index = u16::try_from(machine.cells.len())
+                let index = u16::try_from(machine.visible().len())
                     .ok()
                     .and_then(|len| len.checked_sub(1))
                     .and_then(|len| len.checked_sub(arg))
                     .ok_or(MachineError::InvalidCell)?;
-                let val = *machine.read(index)?;
+                let val = machine.read(index)?;
+                machine.set_expression(machine.expression(index));
+                machine.push(val)?;
+            }
+            ToBool => {
+                let val = i64::from(machine.read(arg)? != 0);
+                machine.push(val)?;
+            }
+            Neg => {
+                let val = machine.read(arg)?;
+                let val = machine.overflow_checked(val.checked_neg(), val.wrapping_neg())?;
                 machine.push(val)?;
             }
-            Tail => todo!(), // TODO: Implement tail call
         }
         Ok(())
     }
@@ -215,6 +755,44 @@ impl Operator for UnaryOpImm {
         Ok(())
     }
 }
+impl BinaryOp {
+    // Computes the result a second way, through i128 arithmetic, for machines
+    // checking their own results and for evaluating symbolic expressions.
+    // Callers rule out division by zero first.
+    fn recompute(&self, a: i64, b: i64) -> i64 {
+        use BinaryOp::*;
+
+        let (wide_a, wide_b) = (i128::from(a), i128::from(b));
+        let unsigned_a = wide_a & i128::from(u64::MAX);
+        let unsigned_b = wide_b & i128::from(u64::MAX);
+        let shift = b & 63;
+        let wide = match self {
+            Add => wide_a + wide_b,
+            Mul => wide_a * wide_b,
+            Div => wide_a / wide_b,
+            Sub => wide_a - wide_b,
+            Mod => wide_a % wide_b,
+            And => wide_a & wide_b,
+            Or => wide_a | wide_b,
+            Xor => wide_a ^ wide_b,
+            ShiftLeftLogical => wide_a << shift,
+            ShiftRightLogical => (wide_a & i128::from(u64::MAX)) >> shift,
+            ShiftRightArithmetic => wide_a >> shift,
+            SetEqual => i128::from(wide_a == wide_b),
+            SetNotEqual => i128::from(wide_a != wide_b),
+            SetLessThan => i128::from(wide_a < wide_b),
+            SetLessThanOrEqual => i128::from(wide_a <= wide_b),
+            SetGreaterThan => i128::from(wide_a > wide_b),
+            SetGreaterThanOrEqual => i128::from(wide_a >= wide_b),
+            SetLessThanUnsigned => i128::from(unsigned_a < unsigned_b),
+            SetLessThanOrEqualUnsigned => i128::from(unsigned_a <= unsigned_b),
+            SetGreaterThanUnsigned => i128::from(unsigned_a > unsigned_b),
+            SetGreaterThanOrEqualUnsigned => i128::from(unsigned_a >= unsigned_b),
+        };
+        wide as i64 // Keeps the low 64 bits, like wrapping i64 arithmetic
+    }
+}
+
 impl Operator for BinaryOp {
     type ArgType = (Cell, Cell);
 
@@ -229,30 +807,127 @@ impl Operator for BinaryOp {
         let a = machine.read(reg1)?;
         let b = machine.read(reg2)?;
 
+        // A symbolic divisor decides between dividing and failing.
+        if matches!(self, Div | Mod) {
+            machine.constrain_zero(reg2, b);
+        }
+        // Shifts by 64 or more, or by a negative amount, overflow.
+        let shift = u32::try_from(b).ok();
         let calculated_value = match self {
-            Add => a + b,
-            Mul => a * b,
-            Div => a.checked_div(*b).ok_or(MachineError::DivisionByZero)?,
+            Add => machine.overflow_checked(a.checked_add(b), a.wrapping_add(b))?,
+            Mul => machine.overflow_checked(a.checked_mul(b), a.wrapping_mul(b))?,
+            Div | Mod if b == 0 => return Err(MachineError::DivisionByZero),
+            Div => machine.overflow_checked(a.checked_div(b), a.wrapping_div(b))?,
+            Sub => machine.overflow_checked(a.checked_sub(b), a.wrapping_sub(b))?,
+            Mod => machine.overflow_checked(a.checked_rem(b), a.wrapping_rem(b))?,
             And => a & b,
             Or => a | b,
             Xor => a ^ b,
-            ShiftLeftLogical => a << b,
-            ShiftRightLogical => ((*a as u64) >> b) as i64,
-            ShiftRightArithmetic => a >> b,
+            ShiftLeftLogical => machine.overflow_checked(
+                shift.and_then(|shift| a.checked_shl(shift)),
+                a.wrapping_shl(b as u32),
+            )?,
+            ShiftRightLogical => machine.overflow_checked(
+                shift.and_then(|shift| (a as u64).checked_shr(shift).map(|v| v as i64)),
+                (a as u64).wrapping_shr(b as u32) as i64,
+            )?,
+            ShiftRightArithmetic => machine.overflow_checked(
+                shift.and_then(|shift| a.checked_shr(shift)),
+                a.wrapping_shr(b as u32),
+            )?,
             SetEqual => from_bool(a == b),
             SetNotEqual => from_bool(a != b),
             SetLessThan => from_bool(a < b),
             SetLessThanOrEqual => from_bool(a <= b),
             SetGreaterThan => from_bool(a > b),
             SetGreaterThanOrEqual => from_bool(a >= b),
+            SetLessThanUnsigned => from_bool((a as u64) < (b as u64)),
+            SetLessThanOrEqualUnsigned => from_bool(a as u64 <= b as u64),
+            SetGreaterThanUnsigned => from_bool(a as u64 > b as u64),
+            SetGreaterThanOrEqualUnsigned => from_bool(a as u64 >= b as u64),
         };
 
+        if machine.redundant_check && self.recompute(a, b) != calculated_value {
+            return Err(MachineError::InternalInconsistency);
+        }
+        let expr = symbolic::binary(
+            *self,
+            (a, machine.expression(reg1)),
+            (b, machine.expression(reg2)),
+        );
+        machine.set_expression(expr);
         machine.push(calculated_value)?;
 
         Ok(())
     }
 }
 
+// The names and arities of the definitions following each other from `start`
+// on.
+fn defined_names(
+    program: &[Instruction],
+    start: usize,
+) -> impl Iterator<Item = (&String, Option<u16>)> {
+    (program.iter().skip(start)).map_while(Instruction::definition)
+}
+
+pub(crate) fn definition_count(program: &[Instruction], start: usize) -> usize {
+    defined_names(program, start).count()
+}
+
+/*
+ * A run of consecutive definitions binds each of its names, in order, to one
+ * of the blocks right after the run: `fn a; fn b; block A; block B` defines `a`
+ * as A and `b` as B. Returns every name of the run starting at `start` with
+ * its arity and body, or an `InstructionError` for the first name left without
+ * a block.
+ */
+// A defined name, its arity if declared, and its body.
+type Definition<'p> = (&'p String, Option<u16>, &'p Instruction);
+
+pub(crate) fn bind_definitions(
+    program: &[Instruction],
+    start: usize,
+) -> Result<Vec<Definition<'_>>, MachineError> {
+    let count = definition_count(program, start);
+    let bodies = (program.iter().skip(start + count).map(Some)).chain(std::iter::repeat(None));
+    (defined_names(program, start).zip(bodies))
+        .map(|((name, arity), body)| match body {
+            Some(body @ Instruction::Block(_)) => Ok((name, arity, body)),
+            _ => Err(MachineError::InstructionError(format!(
+                "Function {:?} has no body",
+                name
+            ))),
+        })
+        .collect()
+}
+
+// Runs the definitions starting at the one just executed.
+fn define_functions(machine: &mut Machine) -> Result<(), MachineError> {
+    // The pc already points past this definition, the first of its run.
+    let start = machine.pc - 1;
+    let definitions = bind_definitions(machine.get_program()?, start)?;
+
+    let function_table = &machine.function_data.function_table;
+    if (definitions.iter()).any(|(name, ..)| function_table.contains_key(*name)) {
+        return Err(MachineError::FunctionRedefinition);
+    }
+
+    let function_table = Rc::make_mut(&mut machine.function_data.function_table);
+    for &(name, _, body) in &definitions {
+        function_table.insert(name.clone(), std::slice::from_ref(body));
+    }
+    let arities = Rc::make_mut(&mut machine.function_data.arities);
+    for &(name, arity, _) in &definitions {
+        if let Some(arity) = arity {
+            arities.insert(name.clone(), arity);
+        }
+    }
+
+    machine.pc = start + 2 * definitions.len(); // The bodies only run when called
+    Ok(())
+}
+
 impl Operator for FunctionOp {
     type ArgType = String;
 
@@ -260,56 +935,28 @@ impl Operator for FunctionOp {
         use FunctionOp::*;
 
         match self {
-            FunctionDefine => {
-                if machine.function_data.function_table.contains_key(&arg) {
-                    return Err(MachineError::FunctionRedefinition);
-                }
-
-                let mut defenitions = Vec::new();
-                defenitions.push(arg.clone());
-                machine.pc += 1;
-
-                // Handles fallthrough to function body, which is the next non-fuction-defining
-                // instruction.
-                while let Some(Instruction::AluFunction(FunctionOp::FunctionDefine, name)) =
-                    machine.program.and_then(|prog| prog.get(machine.pc))
-                {
-                    defenitions.push(name.clone());
-                    machine.pc += 1;
-                }
-
-                let instruction = machine
-                    .program
-                    .ok_or(MachineError::ProgramNotLoaded)?
-                    .get(machine.pc)
-                    .map(std::slice::from_ref)
+            FunctionDefine => define_functions(machine)?,
+            FunctionCall => {
+                let instructions = *machine
+                    .function_data
+                    .function_table
+                    .get(&arg)
                     .ok_or(MachineError::FunctionUndefined)?;
+                let arguments = machine.arguments(&arg)?;
 
-                defenitions
-                    .iter()
-                    .map(|name| {
-                        machine
-                            .function_data
-                            .function_table
-                            .insert(name.clone(), instruction);
-                    })
-                    .for_each(drop);
+                machine.enter(FrameKind::Function(arg), instructions)?;
+                machine.show_only(arguments);
             }
-            FunctionCall => {
+            Tail => {
                 let instructions = *machine
                     .function_data
                     .function_table
                     .get(&arg)
                     .ok_or(MachineError::FunctionUndefined)?;
+                let arguments = machine.arguments(&arg)?;
 
-                let mut function_machine = Machine::from(machine.cells.clone());
-                function_machine.function_data = machine.function_data.clone();
-                function_machine.load_program(instructions);
-                let function_result = function_machine.run()?;
-
-                if let Some(val) = function_result {
-                    machine.push(*val)?;
-                }
+                machine.tail_call(arg, instructions, arguments)?;
+                machine.show_only(arguments);
             }
         }
 
@@ -317,11 +964,243 @@ impl Operator for FunctionOp {
     }
 }
 
+// What a debugger step did. `depth` counts the blocks and function calls the
+// machine is inside of after the step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Stepped { depth: usize },
+    Finished,
+}
+
+// Iterator returned by `Machine::into_iter`.
+#[derive(Debug, Clone)]
+pub struct Steps<'a> {
+    machine: Machine<'a>,
+    done: bool,
+}
+
+impl<'a> Steps<'a> {
+    pub fn machine(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
+
+impl Iterator for Steps<'_> {
+    type Item = Result<Option<i64>, MachineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.machine.step_instruction() {
+            Ok(true) => Some(Ok(self.machine.visible().last().copied())),
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// The table is shared between a machine and the children it spawns, and only
+// copied when one of them defines a new function.
 #[derive(Debug, Clone, Default)]
 pub struct FunctionData<'a> {
-    function_table: HashMap<String, &'a [Instruction]>,
+    function_table: Rc<HashMap<String, &'a [Instruction]>>,
+    // Functions defined with `FunctionDefineArity`, and their arities
+    arities: Rc<HashMap<String, u16>>,
+}
+
+impl FunctionData<'_> {
+    // Bytes used by the table: its entries plus the names they own. Bodies
+    // belong to the loaded program and aren't counted.
+    fn estimate_memory(&self) -> usize {
+        let entry = std::mem::size_of::<(String, &[Instruction])>();
+        let arity = std::mem::size_of::<(String, u16)>();
+        self.function_table.capacity() * entry
+            + self.arities.capacity() * arity
+            + (self.function_table.keys().chain(self.arities.keys()))
+                .map(|name| name.capacity())
+                .sum::<usize>()
+    }
+}
+
+pub type HostFn = Box<dyn Fn(&[i64]) -> Result<i64, MachineError>>;
+
+// Native functions callable through `HostCall`. Unlike VM functions they're
+// registered by the host before running, so every scope sees the same table.
+#[derive(Clone, Default)]
+struct HostFunctions(HashMap<String, Rc<HostFn>>);
+
+impl Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+// Reported to the call hook around every function call. `depth` counts the
+// calls already in progress, so a call made from the top level has depth 0.
+// `result` is the body's last cell, or None if it left none, failed or handed
+// over to another function with a tail call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallEvent {
+    Enter {
+        name: String,
+        depth: usize,
+    },
+    Exit {
+        name: String,
+        depth: usize,
+        result: Option<i64>,
+    },
 }
 
+pub type CallHookFn = Box<dyn FnMut(CallEvent)>;
+
+#[derive(Clone)]
+struct CallHook(Rc<RefCell<CallHookFn>>);
+
+impl Debug for CallHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CallHook")
+    }
+}
+
+// Watches a machine run, seeing every instruction just before it runs,
+// including those inside blocks and function bodies, along with the values
+// it pushes and pops. Each method does nothing unless implemented.
+pub trait Tracer {
+    fn on_instruction(&mut self, _instr: &Instruction, _cells: &[i64]) {}
+
+    fn on_push(&mut self, _value: i64) {}
+
+    fn on_pop(&mut self, _value: i64) {}
+
+    // Sees values written over a cell in place, by its index among the
+    // visible cells.
+    fn on_write(&mut self, _cell: Cell, _value: i64) {}
+
+    fn on_call(&mut self, _event: &CallEvent) {}
+
+    // Sees every instruction that fails, even if a `Succeeds` body catches
+    // its error, and runs stopped by a step limit or lack of fuel.
+    fn on_error(&mut self, _error: &MachineError) {}
+}
+
+// Logs every instruction, with the cells it starts with, and every error
+// to stderr.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StderrTracer;
+
+impl Tracer for StderrTracer {
+    fn on_instruction(&mut self, instr: &Instruction, cells: &[i64]) {
+        eprintln!("{} {:?}", instr.opcode().mnemonic(), cells);
+    }
+
+    fn on_call(&mut self, event: &CallEvent) {
+        eprintln!("{:?}", event);
+    }
+
+    fn on_error(&mut self, error: &MachineError) {
+        eprintln!("error: {}", error);
+    }
+}
+
+#[derive(Clone)]
+struct SharedTracer(Rc<RefCell<Box<dyn Tracer>>>);
+
+impl Debug for SharedTracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Tracer")
+    }
+}
+
+// How much fuel running an instruction takes. A block or call is charged
+// when it's entered, and each instruction of its body on its own.
+pub trait CostModel {
+    fn cost(&self, instr: &Instruction) -> u64;
+}
+
+// Charges one unit for every instruction.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnitCost;
+
+impl CostModel for UnitCost {
+    fn cost(&self, _: &Instruction) -> u64 {
+        1
+    }
+}
+
+impl<F: Fn(&Instruction) -> u64> CostModel for F {
+    fn cost(&self, instr: &Instruction) -> u64 {
+        self(instr)
+    }
+}
+
+#[derive(Clone)]
+struct SharedCostModel(Rc<dyn CostModel>);
+
+impl Debug for SharedCostModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CostModel")
+    }
+}
+
+#[derive(Debug, Clone)]
+enum FrameKind {
+    Block,
+    Function(String),
+    Succeeds, // A block whose errors are caught, pushing a success flag instead of its result
+}
+
+impl FrameKind {
+    fn record(&self, trace: &mut Option<trace::ScopeTrace>, begin: bool) {
+        if let Some(trace) = trace {
+            match self {
+                FrameKind::Block => trace.record("block", "block", begin),
+                FrameKind::Function(name) => trace.record(name, "function", begin),
+                FrameKind::Succeeds => trace.record("succeeds", "block", begin),
+            }
+        }
+    }
+}
+
+/*
+ * A caller suspended while a block or function body runs. Blocks and calls
+ * don't recurse on the native stack: entering one saves the caller's state in
+ * a frame, and the machine resumes it once the body's program runs out.
+ *
+ * The body works on the caller's cell vector in place. Rather than a copy of
+ * the caller's cells, the frame keeps how many there were, and the values of
+ * those the body has since overwritten or popped: the caller's cells from
+ * `len - saved.len()` up to `len`, in reverse order so that saving one more
+ * below them is a push.
+ */
+#[derive(Debug, Clone)]
+struct Frame<'a> {
+    kind: FrameKind,
+    program: &'a [Instruction],
+    pc: Address, // Where the caller resumes
+    len: usize,
+    saved: Vec<i64>,
+    window: usize,
+    base: usize,
+    base_stack: Vec<usize>,
+    function_data: FunctionData<'a>,
+    origin: usize,
+    taint: Option<taint::Taint>,
+    symbolic: Option<Vec<Option<Rc<symbolic::Expr>>>>,
+}
+
+// Blocks and calls nest as frames rather than native recursion, so the limit
+// only stops runaway recursion, not a stack overflow.
+const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
 #[derive(Debug, Clone)]
 pub struct Machine<'a> {
     cells: Vec<i64>,
@@ -329,7 +1208,32 @@ pub struct Machine<'a> {
     base: usize,
     base_stack: Vec<usize>,
     function_data: FunctionData<'a>,
+    host_functions: HostFunctions,
+    unary_functions: HashMap<String, fn(i64) -> i64>,
+    inputs: HashMap<String, i64>,
+    immutable: HashSet<Cell>, // Cells that instructions may not overwrite in place
+    require_result: bool,     // Whether a run leaving no cells is an error
+    wrapping: bool,           // Whether overflowing arithmetic wraps instead of failing
+    strict_scopes: bool,      // Whether a run must end with every scope closed
+    redundant_check: bool,    // Whether binary operations double-check their results
+    executed: usize,          // Instructions run to completion, including those in bodies
+    budget: Option<usize>,    // Instructions left to run, during `run_with_budget`
+    fuel: Option<u64>,        // Fuel left, once metering is turned on; kept across runs
+    cost_model: SharedCostModel,
+    max_call_depth: usize, // How many blocks and calls may be entered at once
+    memory: Vec<i64>,      // Shared by every frame; failing bodies keep their stores
     pc: Address,
+    frames: Vec<Frame<'a>>,
+    window: usize, // Where the cells visible to the running body start; 0 outside bodies
+    origin: usize, // Absolute position of cells[0]; grows as Rebase drops the cells below it.
+    read_counts: Option<Vec<u64>>, // Reads per absolute cell position, when a heatmap is requested.
+    scope_trace: Option<trace::ScopeTrace>,
+    taint: Option<taint::Taint>, // Which cells are tainted, when taint tracking is requested.
+    symbolic: Option<symbolic::Symbolic>, // Expressions of the cells, once a symbol is pushed.
+    path: symbolic::PathConstraints,
+    call_hook: Option<CallHook>,
+    tracer: Option<SharedTracer>,         // Runs are silent without one
+    solver: Option<solver::SharedSolver>, // Lets `Assert` look for failing values
 }
 
 impl<'a> Machine<'a> {
@@ -340,10 +1244,53 @@ impl<'a> Machine<'a> {
             base: 0,
             base_stack: Vec::new(),
             function_data: FunctionData::default(),
+            host_functions: HostFunctions::default(),
+            unary_functions: HashMap::new(),
+            inputs: HashMap::new(),
+            immutable: HashSet::new(),
+            require_result: false,
+            wrapping: false,
+            strict_scopes: false,
+            redundant_check: false,
+            executed: 0,
+            budget: None,
+            fuel: None,
+            cost_model: SharedCostModel(Rc::new(UnitCost)),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            memory: Vec::new(),
             pc: 0,
+            frames: Vec::new(),
+            window: 0,
+            origin: 0,
+            read_counts: None,
+            scope_trace: None,
+            taint: None,
+            symbolic: None,
+            path: symbolic::PathConstraints::default(),
+            call_hook: None,
+            tracer: None,
+            solver: None,
         }
     }
 
+    /// Runs `program` on a fresh machine and returns the value of the last cell.
+    ///
+    /// ```
+    /// use virtual_machine::{BinaryOp, Instruction::*, Machine, UnaryOpImm};
+    ///
+    /// let program = [
+    ///     AluUnaryImm(UnaryOpImm::Push, 2),
+    ///     AluUnaryImm(UnaryOpImm::Push, 3),
+    ///     AluBinary(BinaryOp::Add, 0, 1),
+    /// ];
+    /// assert_eq!(Machine::load_and_run(&program).unwrap(), Some(5));
+    /// ```
+    pub fn load_and_run(program: &[Instruction]) -> Result<Option<i64>, MachineError> {
+        let mut machine = Machine::new();
+        machine.load_program(program);
+        machine.run().map(|last| last.copied())
+    }
+
     pub fn load_program(&mut self, program: &'a [Instruction]) {
         self.program = Some(program);
     }
@@ -352,17 +1299,262 @@ impl<'a> Machine<'a> {
         self.pc = 0;
     }
 
-    pub fn get_program(&mut self) -> Result<&'a [Instruction], MachineError> {
-        self.program.ok_or(MachineError::ProgramNotLoaded)
+    pub fn pc(&self) -> Address {
+        self.pc
     }
 
-    fn push(&mut self, value: i64) -> Result<(), MachineError> {
-        self.cells.push(value);
-        Ok(())
+    pub fn cells(&self) -> &[i64] {
+        self.visible()
     }
 
-    fn pop(&mut self) -> Option<i64> {
-        self.cells.pop()
+    // The value of a cell, addressed the way instructions address it.
+    pub fn get_cell(&self, index: Cell) -> Result<i64, MachineError> {
+        (self.visible().get(usize::from(index)).copied()).ok_or(MachineError::InvalidCell)
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.visible().len()
+    }
+
+    // The names of the functions defined so far, sorted.
+    pub fn function_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = (self.function_data.function_table.keys())
+            .map(String::as_str)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    // The cells the running body can address, from r0 up.
+    fn visible(&self) -> &[i64] {
+        &self.cells[self.window..]
+    }
+
+    // Saves the caller's cells from `position` up in the innermost frame,
+    // before the body overwrites or pops them.
+    fn preserve(&mut self, position: usize) {
+        let Some(frame) = self.frames.last_mut() else {
+            return;
+        };
+        let saved_from = frame.len - frame.saved.len();
+        if position < saved_from {
+            frame
+                .saved
+                .extend(self.cells[position..saved_from].iter().rev());
+        }
+    }
+
+    pub fn get_program(&mut self) -> Result<&'a [Instruction], MachineError> {
+        self.program.ok_or(MachineError::ProgramNotLoaded)
+    }
+
+    // Makes `f` callable from programs with `HostCall`, replacing any host
+    // function previously registered under the same name.
+    pub fn register_host_fn(&mut self, name: &str, f: HostFn) {
+        self.host_functions.0.insert(name.to_string(), Rc::new(f));
+    }
+
+    // Makes `f` callable from programs with `HostUnary`. Meant for cheap,
+    // pure transforms; anything needing state or errors goes through
+    // `register_host_fn`.
+    pub fn register_unary(&mut self, name: &str, f: fn(i64) -> i64) {
+        self.unary_functions.insert(name.to_string(), f);
+    }
+
+    // Sets the value pushed by `Input` instructions reading `name`.
+    pub fn set_input(&mut self, name: &str, value: i64) {
+        self.inputs.insert(name.to_string(), value);
+    }
+
+    // Runs `f` with the host's `cells` in place of the machine's own. The
+    // buffer is swapped in rather than copied, so everything `f` does to the
+    // cells, including growing them, is left in the host's vector, and the
+    // machine gets its own cells back afterwards.
+    pub fn with_external_cells<R>(
+        &mut self,
+        cells: &mut Vec<i64>,
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        std::mem::swap(&mut self.cells, cells);
+        let result = f(self);
+        std::mem::swap(&mut self.cells, cells);
+        result
+    }
+
+    // When enabled, a run that ends without any cells fails with `NoResult`
+    // instead of returning `Ok(None)`.
+    pub fn set_require_result(&mut self, require: bool) {
+        self.require_result = require;
+    }
+
+    // When enabled, arithmetic overflowing an i64 wraps around instead of
+    // failing with `IntegerOverflow`.
+    pub fn set_wrapping(&mut self, wrapping: bool) {
+        self.wrapping = wrapping;
+    }
+
+    // Settles a result that may not fit an i64: `checked` is the result if it
+    // fits, and `wrapped` its low 64 bits either way.
+    fn overflow_checked(&self, checked: Option<i64>, wrapped: i64) -> Result<i64, MachineError> {
+        match checked {
+            Some(value) => Ok(value),
+            None if self.wrapping => Ok(wrapped),
+            None => Err(MachineError::IntegerOverflow),
+        }
+    }
+
+    // When enabled, a run that ends with scopes still open on the base stack,
+    // which a correct machine never leaves behind, fails with
+    // `UnbalancedScopes`.
+    pub fn set_strict_scopes(&mut self, strict: bool) {
+        self.strict_scopes = strict;
+    }
+
+    // Limits how deeply blocks and function calls may nest; entering one more
+    // fails with `RecursionLimitExceeded`. Tail calls don't nest any deeper.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    // Gives the machine `size` words of memory for `Load` and `Store`, keeping
+    // the words that fit and zeroing new ones. Machines start without memory.
+    pub fn set_memory_size(&mut self, size: usize) {
+        self.memory.resize(size, 0);
+    }
+
+    pub fn memory(&self) -> &[i64] {
+        &self.memory
+    }
+
+    // The memory address held by `cell`, if it's within memory.
+    fn address(&mut self, cell: Cell) -> Result<usize, MachineError> {
+        let address = self.read(cell)?;
+        usize::try_from(address)
+            .ok()
+            .filter(|&address| address < self.memory.len())
+            .ok_or(MachineError::InvalidAddress)
+    }
+
+    // When enabled, every binary operation is computed a second time through
+    // independent (i128) arithmetic, and a mismatch fails the run with
+    // `InternalInconsistency`. Mismatches would mean a bug in the machine.
+    pub fn set_redundant_check(&mut self, check: bool) {
+        self.redundant_check = check;
+    }
+
+    // Makes instructions that overwrite `cell` in place, like `SetImm`, fail with
+    // `ImmutableCell` instead.
+    pub fn mark_immutable(&mut self, cell: Cell) {
+        self.immutable.insert(cell);
+    }
+
+    // Calls `f` every time a function is entered and exited.
+    pub fn set_call_hook(&mut self, f: CallHookFn) {
+        self.call_hook = Some(CallHook(Rc::new(RefCell::new(f))));
+    }
+
+    /*
+     * Meters runs: each instruction takes the fuel the cost model says from
+     * `fuel`, and once there's too little left for the next one, the run
+     * fails with `OutOfFuel` without starting it. The machine is left as it
+     * was before the top-level instruction that ran out, so after a refill
+     * the run can be resumed.
+     */
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    // The fuel left, or `None` if runs aren't metered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Adds to the fuel left, turning metering on if it's off.
+    pub fn refill_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    // Stops metering runs.
+    pub fn remove_fuel(&mut self) {
+        self.fuel = None;
+    }
+
+    // Charges instructions according to `model` instead of one unit each.
+    pub fn set_cost_model(&mut self, model: impl CostModel + 'static) {
+        self.cost_model = SharedCostModel(Rc::new(model));
+    }
+
+    // Shows every instruction to `tracer` before running it.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(SharedTracer(Rc::new(RefCell::new(tracer))));
+    }
+
+    // Passes an event for a function frame to the call hook, if one is set.
+    // Also shows it to the tracer, if one is set.
+    fn report_call(&self, kind: &FrameKind, event: impl FnOnce(String, usize) -> CallEvent) {
+        let FrameKind::Function(name) = kind else {
+            return;
+        };
+        if self.call_hook.is_none() && self.tracer.is_none() {
+            return;
+        }
+        let depth = (self.frames.iter())
+            .filter(|frame| matches!(frame.kind, FrameKind::Function(_)))
+            .count();
+        let event = event(name.clone(), depth);
+        if let Some(tracer) = &self.tracer {
+            tracer.0.borrow_mut().on_call(&event);
+        }
+        if let Some(hook) = &self.call_hook {
+            (hook.0.borrow_mut())(event);
+        }
+    }
+
+    fn report_error(&self, error: &MachineError) {
+        if let Some(tracer) = &self.tracer {
+            tracer.0.borrow_mut().on_error(error);
+        }
+    }
+
+    fn push(&mut self, value: i64) -> Result<(), MachineError> {
+        self.cells.push(value);
+        if let Some(tracer) = &self.tracer {
+            tracer.0.borrow_mut().on_push(value);
+        }
+        if let Some(taint) = &mut self.taint {
+            taint.push();
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            symbolic.push();
+        }
+        Ok(())
+    }
+
+    // Pushes every value of `values`, in order, as if by `Push`.
+    pub fn push_all(&mut self, values: &[i64]) -> Result<(), MachineError> {
+        self.cells.reserve(values.len());
+        for &value in values {
+            self.push(value)?;
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<i64> {
+        if self.cells.len() == self.window {
+            return None;
+        }
+        self.preserve(self.cells.len() - 1);
+        if let Some(taint) = &mut self.taint {
+            taint.pop();
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            symbolic.pop();
+        }
+        let value = self.cells.pop();
+        if let (Some(tracer), Some(value)) = (&self.tracer, value) {
+            tracer.0.borrow_mut().on_pop(value);
+        }
+        value
     }
 
     fn multi_pop(&mut self, n: Immediate) -> Result<(), MachineError> {
@@ -376,538 +1568,3085 @@ impl<'a> Machine<'a> {
         Ok(())
     }
 
-    fn read(&self, reg: Cell) -> Result<&i64, MachineError> {
-        match self.cells.get::<usize>(reg.into()) {
-            Some(value) => Ok(value),
-            None => Err(MachineError::InvalidCell),
+    fn read(&mut self, reg: Cell) -> Result<i64, MachineError> {
+        let value = match self.visible().get::<usize>(reg.into()) {
+            Some(value) => *value,
+            None => return Err(MachineError::InvalidCell),
+        };
+
+        if let Some(counts) = &mut self.read_counts {
+            let position = self.origin + usize::from(reg);
+            if counts.len() <= position {
+                counts.resize(position + 1, 0);
+            }
+            counts[position] += 1;
+        }
+        if let Some(taint) = &mut self.taint {
+            taint.read(self.window + usize::from(reg));
+        }
+
+        Ok(value)
+    }
+
+    fn write(&mut self, reg: Cell, value: i64) -> Result<(), MachineError> {
+        if self.immutable.contains(&reg) {
+            return Err(MachineError::ImmutableCell);
+        }
+        let position = self.window + usize::from(reg);
+        if position >= self.cells.len() {
+            return Err(MachineError::InvalidCell);
+        }
+        self.preserve(position);
+        self.cells[position] = value;
+        if let Some(tracer) = &self.tracer {
+            tracer.0.borrow_mut().on_write(reg, value);
+        }
+        if let Some(taint) = &mut self.taint {
+            taint.write(position);
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            symbolic.write(position);
+        }
+        Ok(())
+    }
+
+    fn rebase(&mut self) -> Result<(), MachineError> {
+        if self.base > self.visible().len() {
+            return Err(MachineError::RebaseError);
+        }
+
+        // Inside a body the cells below the base still belong to the caller,
+        // so they're only hidden.
+        if self.frames.is_empty() {
+            self.cells = self.cells.split_off(self.base);
+            if let Some(taint) = &mut self.taint {
+                taint.rebase(self.base);
+            }
+            if let Some(symbolic) = &mut self.symbolic {
+                symbolic.rebase(self.base);
+            }
+        } else {
+            self.window += self.base;
+        }
+        self.origin += self.base;
+        self.base = 0;
+
+        Ok(())
+    }
+
+    // Starts running `program` in a new frame. The body sees the caller's
+    // cells and every function the caller has defined; the caller gets its
+    // own state back, plus the body's last cell, when the body ends.
+    fn enter(&mut self, kind: FrameKind, program: &'a [Instruction]) -> Result<(), MachineError> {
+        if self.frames.len() >= self.max_call_depth {
+            return Err(MachineError::RecursionLimitExceeded);
+        }
+        let (base, base_stack) = match kind {
+            FrameKind::Block | FrameKind::Succeeds => (self.visible().len(), vec![self.base]),
+            FrameKind::Function(_) => (0, Vec::new()),
+        };
+
+        kind.record(&mut self.scope_trace, true);
+        self.report_call(&kind, |name, depth| CallEvent::Enter { name, depth });
+
+        let frame = Frame {
+            kind,
+            program: self.get_program()?,
+            pc: self.pc,
+            len: self.cells.len(),
+            saved: Vec::new(),
+            window: self.window,
+            base: std::mem::replace(&mut self.base, base),
+            base_stack: std::mem::replace(&mut self.base_stack, base_stack),
+            function_data: self.function_data.clone(),
+            origin: self.origin,
+            taint: self.taint.clone(),
+            symbolic: self.symbolic.as_ref().map(symbolic::Symbolic::save),
+        };
+        self.frames.push(frame);
+
+        self.program = Some(program);
+        self.pc = 0;
+        Ok(())
+    }
+
+    fn jump(&mut self, target: Address) -> Result<(), MachineError> {
+        if target > self.get_program()?.len() {
+            return Err(MachineError::InvalidJumpTarget(target));
+        }
+        self.pc = target;
+        Ok(())
+    }
+
+    /*
+     * Leaves every frame up to and including the innermost function's, the
+     * way `leave` leaves one, and pushes the value of `cell`, if any, to the
+     * function's caller. Outside functions, every frame is left and the
+     * program ends after the push.
+     */
+    fn return_from(&mut self, cell: Option<Cell>) -> Result<(), MachineError> {
+        let result = cell.map(|cell| self.read(cell)).transpose()?;
+        let expr = cell.and_then(|cell| self.expression(cell));
+        let tainted = self.taint.as_ref().is_some_and(taint::Taint::pending);
+
+        let function =
+            (self.frames.iter()).rposition(|frame| matches!(frame.kind, FrameKind::Function(_)));
+        while self.frames.len() > function.unwrap_or(0) {
+            let frame = self.frames.pop().ok_or(MachineError::RebaseError)?;
+            frame.kind.record(&mut self.scope_trace, false);
+            self.report_call(&frame.kind, |name, depth| CallEvent::Exit {
+                name,
+                depth,
+                result,
+            });
+            self.restore_frame(frame);
+        }
+        if function.is_none() {
+            self.pc = self.get_program()?.len();
+        }
+
+        if let Some(taint) = &mut self.taint {
+            taint.set_pending(tainted);
+        }
+        self.set_expression(expr);
+        if let Some(value) = result {
+            self.push(value)?;
+        }
+        Ok(())
+    }
+
+    // How many of the caller's top cells a call to `name` passes on, if its
+    // arity was declared. Those must all lie above the caller's base.
+    fn arguments(&self, name: &str) -> Result<Option<usize>, MachineError> {
+        let Some(&arity) = self.function_data.arities.get(name) else {
+            return Ok(None);
+        };
+        let arity = usize::from(arity);
+        if self.visible().len().saturating_sub(self.base) < arity {
+            return Err(MachineError::FunctionCallError);
+        }
+        Ok(Some(arity))
+    }
+
+    // Hides every cell but the top `arguments` from the body just entered.
+    fn show_only(&mut self, arguments: Option<usize>) {
+        if let Some(arguments) = arguments {
+            let hidden = self.visible().len() - arguments;
+            self.window += hidden;
+            self.origin += hidden;
+        }
+    }
+
+    /*
+     * Calls `program` in place of the function whose last instruction this
+     * is, reusing its frame, so that tail recursion runs in constant space.
+     * Only calls at the very end of a function's body, possibly through
+     * nested blocks, are tail calls; anywhere else this is an ordinary call.
+     *
+     * The body's own cells are dead once it tail-calls, so a callee taking
+     * `arguments` gets them moved down to where the body's cells started.
+     * A callee without an arity sees every cell its caller could, so those
+     * are kept.
+     */
+    fn tail_call(
+        &mut self,
+        name: String,
+        program: &'a [Instruction],
+        arguments: Option<usize>,
+    ) -> Result<(), MachineError> {
+        let function = self
+            .frames
+            .iter()
+            .rposition(|frame| matches!(frame.kind, FrameKind::Function(_)));
+        let Some(function) = function.filter(|&function| {
+            self.pc == self.program.map_or(0, <[Instruction]>::len)
+                && self.frames[function + 1..].iter().all(|frame| {
+                    matches!(frame.kind, FrameKind::Block) && frame.pc == frame.program.len()
+                })
+        }) else {
+            return self.enter(FrameKind::Function(name), program);
+        };
+
+        // The blocks in between would only pass the callee's result on. The
+        // caller's cells they saved are handed down to the function's frame.
+        while self.frames.len() > function + 1 {
+            if let Some(frame) = self.frames.pop() {
+                frame.kind.record(&mut self.scope_trace, false);
+                let saved_from = frame.len - frame.saved.len();
+                if let Some(outer) = self.frames.last_mut() {
+                    let outer_from = outer.len - outer.saved.len();
+                    let missing = outer_from.saturating_sub(saved_from);
+                    outer
+                        .saved
+                        .extend(frame.saved.iter().rev().take(missing).rev());
+                }
+            }
+        }
+
+        let mut frame = self.frames.pop().ok_or(MachineError::RebaseError)?;
+        if let Some(arguments) = arguments {
+            let end = self.cells.len().saturating_sub(arguments);
+            if end > frame.len {
+                self.cells.drain(frame.len..end);
+                if let Some(taint) = &mut self.taint {
+                    taint.remove(frame.len..end);
+                }
+                if let Some(symbolic) = &mut self.symbolic {
+                    symbolic.remove(frame.len..end);
+                }
+            }
+        }
+        frame.kind.record(&mut self.scope_trace, false);
+        self.report_exit_without_result(&frame.kind);
+        frame.kind = FrameKind::Function(name);
+        frame.kind.record(&mut self.scope_trace, true);
+        self.report_call(&frame.kind, |name, depth| CallEvent::Enter { name, depth });
+        self.frames.push(frame);
+
+        self.program = Some(program);
+        self.pc = 0;
+        self.base = 0;
+        self.base_stack = Vec::new();
+        Ok(())
+    }
+
+    // Returns from the innermost frame, pushing the body's result to the caller.
+    fn leave(&mut self) -> Result<(), MachineError> {
+        let frame = self.frames.pop().ok_or(MachineError::RebaseError)?;
+        let result = match frame.kind {
+            FrameKind::Succeeds => Some(1), // The body's cell effects are discarded
+            _ => self.visible().last().copied(),
+        };
+        let tainted = match frame.kind {
+            FrameKind::Succeeds => false,
+            _ => result.is_some() && self.taint.as_ref().is_some_and(|taint| taint.last()),
+        };
+        let expr = match frame.kind {
+            FrameKind::Succeeds => None,
+            _ => self.symbolic.as_ref().and_then(symbolic::Symbolic::last),
+        };
+
+        frame.kind.record(&mut self.scope_trace, false);
+        self.report_call(&frame.kind, |name, depth| CallEvent::Exit {
+            name,
+            depth,
+            result,
+        });
+
+        self.restore_frame(frame);
+
+        if let Some(taint) = &mut self.taint {
+            taint.set_pending(tainted);
+        }
+        self.set_expression(expr);
+        if let Some(val) = result {
+            self.push(val)?;
+        }
+        Ok(())
+    }
+
+    fn restore_frame(&mut self, frame: Frame<'a>) {
+        self.program = Some(frame.program);
+        self.pc = frame.pc;
+        self.cells.truncate(frame.len - frame.saved.len());
+        self.cells.extend(frame.saved.iter().rev());
+        self.window = frame.window;
+        self.base = frame.base;
+        self.base_stack = frame.base_stack;
+        self.function_data = frame.function_data;
+        self.origin = frame.origin;
+        self.taint = frame.taint;
+        if let (Some(symbolic), Some(cells)) = (&mut self.symbolic, frame.symbolic) {
+            symbolic.restore(cells);
+        }
+    }
+
+    fn report_exit_without_result(&self, kind: &FrameKind) {
+        self.report_call(kind, |name, depth| CallEvent::Exit {
+            name,
+            depth,
+            result: None,
+        });
+    }
+
+    // Catches an error inside a `Succeeds` body: drops the frames up to and
+    // including the innermost `Succeeds` frame and pushes 0 to its caller.
+    // Returns false, leaving the frames alone, if nothing catches the error.
+    fn recover(&mut self) -> Result<bool, MachineError> {
+        let Some(depth) = self
+            .frames
+            .iter()
+            .rposition(|frame| matches!(frame.kind, FrameKind::Succeeds))
+        else {
+            return Ok(false);
+        };
+
+        // Each frame only saved what its own body changed, so every one is
+        // restored in turn, ending with the state before the `Succeeds` body.
+        while self.frames.len() > depth {
+            let frame = self.frames.pop().ok_or(MachineError::RebaseError)?;
+            frame.kind.record(&mut self.scope_trace, false);
+            self.report_exit_without_result(&frame.kind);
+            self.restore_frame(frame);
+        }
+
+        if let Some(taint) = &mut self.taint {
+            taint.set_pending(false);
+        }
+        self.set_expression(None);
+        self.push(0)?;
+        Ok(true)
+    }
+
+    // Drops every frame after an error, leaving the machine as it was when the
+    // failing top-level instruction started.
+    fn unwind(&mut self) {
+        while let Some(frame) = self.frames.pop() {
+            frame.kind.record(&mut self.scope_trace, false);
+            self.report_exit_without_result(&frame.kind);
+            self.restore_frame(frame);
+
+            if self.frames.is_empty() {
+                self.pc -= 1;
+            }
+        }
+    }
+
+    // Leaves every frame whose body has run out, pushing each body's result.
+    fn leave_finished(&mut self) -> Result<(), MachineError> {
+        while !self.frames.is_empty() && self.get_program()?.get(self.pc).is_none() {
+            self.leave()?;
+        }
+        Ok(())
+    }
+
+    // The instruction the next step runs, once the bodies that have run out
+    // are left. `None` at the end of the program.
+    pub(crate) fn next_instruction(&mut self) -> Result<Option<&'a Instruction>, MachineError> {
+        self.leave_finished()?;
+        Ok(self.get_program()?.get(self.pc))
+    }
+
+    // Runs the next instruction, along with leaving the bodies it finishes.
+    // Returns false, without doing anything, once the program has run out.
+    fn step_instruction(&mut self) -> Result<bool, MachineError> {
+        self.leave_finished()?;
+        let program = self.get_program()?;
+        let Some(instr) = program.get(self.pc) else {
+            return Ok(false);
+        };
+
+        if let Some(budget) = &mut self.budget {
+            // Not an instruction's error, so no `Succeeds` body can catch it.
+            if *budget == 0 {
+                self.unwind();
+                self.report_error(&MachineError::StepLimitExceeded);
+                return Err(MachineError::StepLimitExceeded);
+            }
+            *budget -= 1;
+        }
+        if let Some(fuel) = &mut self.fuel {
+            let cost = self.cost_model.0.cost(instr);
+            if *fuel < cost {
+                self.unwind();
+                self.report_error(&MachineError::OutOfFuel);
+                return Err(MachineError::OutOfFuel);
+            }
+            *fuel -= cost;
+        }
+        if let Some(tracer) = &self.tracer {
+            tracer.0.borrow_mut().on_instruction(instr, self.visible());
+        }
+        let at = self.pc;
+        self.pc += 1;
+        if let Some(taint) = &mut self.taint {
+            taint.start_instruction();
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            symbolic.start_instruction();
+        }
+        if let Err(e) = instr.eval(self) {
+            self.report_error(&e);
+            if !self.recover()? {
+                self.pc = at;
+                self.unwind();
+                return Err(e);
+            }
+        } else {
+            self.executed += 1;
+        }
+
+        self.leave_finished()?;
+        Ok(true)
+    }
+
+    pub fn run(&mut self) -> Result<Option<&i64>, MachineError> {
+        self.executed = 0;
+        while self.step_instruction()? {}
+        self.finish()
+    }
+
+    // The result of a run that has reached the end of the program.
+    pub(crate) fn finish(&self) -> Result<Option<&i64>, MachineError> {
+        if self.strict_scopes && !self.base_stack.is_empty() {
+            return Err(MachineError::UnbalancedScopes);
+        }
+        if self.require_result && self.cells.is_empty() {
+            return Err(MachineError::NoResult);
+        }
+        Ok(self.cells.last())
+    }
+
+    // Runs the loaded program like `run`, but fails with `StepLimitExceeded`
+    // instead of starting instruction number `max_steps + 1`. Instructions in
+    // bodies count, as do instructions that fail.
+    pub fn run_with_budget(&mut self, max_steps: usize) -> Result<Option<&i64>, MachineError> {
+        self.budget = Some(max_steps);
+        let result = self.run().map(|_| ());
+        self.budget = None;
+        result.map(|()| self.cells.last())
+    }
+
+    // Runs the loaded program and returns the last cell after every top-level
+    // instruction, indexed like the program. Instructions that were skipped (a
+    // function body, or the instruction after a failed `Cond`) repeat the
+    // value of the one before them. Fails wherever `run` would, including at
+    // the end.
+    pub fn run_collecting_results(&mut self) -> Result<Vec<Option<i64>>, MachineError> {
+        self.executed = 0;
+        let mut results = Vec::new();
+        while self.step_instruction()? {
+            if self.frames.is_empty() {
+                results.resize(self.pc, self.cells.last().copied());
+            }
+        }
+        self.finish()?;
+        Ok(results)
+    }
+
+    // Turns the machine into an iterator running `program` one instruction per
+    // step, for hosts that interleave execution with other work. Each step
+    // yields the last cell once the instruction has run; iteration ends after
+    // the last instruction or the first error.
+    pub fn into_iter(mut self, program: &'a [Instruction]) -> Steps<'a> {
+        self.load_program(program);
+        self.reset_pc();
+        self.executed = 0;
+        Steps {
+            machine: self,
+            done: false,
+        }
+    }
+
+    // Runs the next instruction of the loaded program. A block or call only
+    // enters its body, so the following steps run the body one instruction at
+    // a time, returning to the caller after its last one.
+    pub fn step_into(&mut self) -> Result<StepResult, MachineError> {
+        Ok(match self.step_instruction()? {
+            true => StepResult::Stepped {
+                depth: self.frames.len(),
+            },
+            false => StepResult::Finished,
+        })
+    }
+
+    // Runs the next top-level instruction of the loaded program, along with
+    // the whole body of a block or call, and returns the last cell. Once the
+    // program has run out this does nothing and returns `None`; check
+    // `is_finished` to tell that apart from an empty machine.
+    pub fn step(&mut self) -> Result<Option<&i64>, MachineError> {
+        if !self.step_instruction()? {
+            return Ok(None);
+        }
+        while !self.frames.is_empty() && self.step_instruction()? {}
+        Ok(self.cells.last())
+    }
+
+    // Whether the loaded program has run to its end, outside of any body.
+    pub fn is_finished(&self) -> bool {
+        self.frames.is_empty() && self.program.is_none_or(|program| self.pc >= program.len())
+    }
+
+    // Runs the loaded program, returning how many instructions ran without error
+    // alongside the result, whether or not the run failed.
+    pub fn run_counted(&mut self) -> (Result<Option<i64>, MachineError>, usize) {
+        let result = self.run().map(|last| last.copied());
+        (result, self.executed)
+    }
+
+    // Runs the loaded program and reports how often each cell was read, indexed
+    // by absolute cell position. Reads made inside blocks and functions count
+    // towards the cells they actually touched.
+    pub fn run_with_heatmap(&mut self) -> Result<(Option<i64>, Vec<u64>), MachineError> {
+        self.read_counts = Some(Vec::new());
+        let result = self.run().map(|last| last.copied());
+        let mut counts = self.read_counts.take().unwrap_or_default();
+
+        let result = result?;
+        counts.resize(counts.len().max(self.origin + self.cells.len()), 0);
+        Ok((result, counts))
+    }
+
+    // Estimates the heap memory held by the machine, in bytes: its cells, the
+    // base stack, the function table and the state saved by suspended frames.
+    // The loaded program is borrowed, so it isn't included.
+    pub fn estimate_memory(&self) -> usize {
+        let cell = std::mem::size_of::<i64>();
+        let base = std::mem::size_of::<usize>();
+
+        let frames: usize = (self.frames.iter())
+            .map(|frame| frame.saved.capacity() * cell + frame.base_stack.capacity() * base)
+            .sum();
+
+        self.cells.capacity() * cell
+            + self.memory.capacity() * cell
+            + self.base_stack.capacity() * base
+            + self.function_data.estimate_memory()
+            + self.frames.capacity() * std::mem::size_of::<Frame>()
+            + frames
+    }
+
+    // Compares the state of two machines, e.g. after running two variants of a
+    // program. `self` is the left side of the diff.
+    pub fn diff(&self, other: &Machine) -> StateDiff {
+        let len = self.cells.len().max(other.cells.len());
+        let cells = (0..len)
+            .filter_map(|index| {
+                let left = self.cells.get(index).copied();
+                let right = other.cells.get(index).copied();
+                (left != right).then_some(CellDiff { index, left, right })
+            })
+            .collect();
+
+        StateDiff {
+            cells,
+            base: (self.base != other.base).then_some((self.base, other.base)),
+            base_stack: (self.base_stack != other.base_stack)
+                .then(|| (self.base_stack.clone(), other.base_stack.clone())),
+        }
+    }
+
+    // Captures the cells, memory, scopes, defined functions and position in
+    // the loaded program, for `restore` to roll the machine back to. Meant to
+    // be taken between runs or top-level steps, not from inside a body.
+    pub fn snapshot(&self) -> MachineSnapshot<'a> {
+        MachineSnapshot {
+            program: self.program,
+            pc: self.pc,
+            cells: self.cells.clone(),
+            memory: self.memory.clone(),
+            base: self.base,
+            base_stack: self.base_stack.clone(),
+            function_data: self.function_data.clone(),
+            origin: self.origin,
+            symbolic: self.symbolic.clone(),
+            path: self.path.clone(),
+        }
+    }
+
+    // Puts back the state captured by `snapshot`, forgetting any function
+    // defined since.
+    pub fn restore(&mut self, snapshot: MachineSnapshot<'a>) {
+        self.program = snapshot.program;
+        self.pc = snapshot.pc;
+        self.cells = snapshot.cells;
+        self.memory = snapshot.memory;
+        self.base = snapshot.base;
+        self.base_stack = snapshot.base_stack;
+        self.function_data = snapshot.function_data;
+        self.origin = snapshot.origin;
+        self.symbolic = snapshot.symbolic;
+        self.path = snapshot.path;
+    }
+}
+
+// The state of a machine saved by `Machine::snapshot`. The function table is
+// shared with the machine until either defines a new function.
+#[derive(Debug, Clone)]
+pub struct MachineSnapshot<'a> {
+    program: Option<&'a [Instruction]>,
+    pc: Address,
+    cells: Vec<i64>,
+    memory: Vec<i64>,
+    base: usize,
+    base_stack: Vec<usize>,
+    function_data: FunctionData<'a>,
+    origin: usize,
+    symbolic: Option<symbolic::Symbolic>,
+    path: symbolic::PathConstraints,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellDiff {
+    pub index: usize,
+    pub left: Option<i64>, // None when the cell only exists on the other side
+    pub right: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    pub cells: Vec<CellDiff>,
+    pub base: Option<(usize, usize)>,
+    pub base_stack: Option<(Vec<usize>, Vec<usize>)>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.base.is_none() && self.base_stack.is_none()
+    }
+}
+
+impl Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn show(value: Option<i64>) -> String {
+            value.map_or(String::from("<none>"), |value| value.to_string())
+        }
+
+        if self.is_empty() {
+            return writeln!(f, "machines are identical");
+        }
+
+        for CellDiff { index, left, right } in &self.cells {
+            writeln!(f, "cell {}: {} != {}", index, show(*left), show(*right))?;
+        }
+        if let Some((left, right)) = self.base {
+            writeln!(f, "base: {} != {}", left, right)?;
+        }
+        if let Some((left, right)) = &self.base_stack {
+            writeln!(f, "base_stack: {:?} != {:?}", left, right)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Machine<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Vec<i64>> for Machine<'_> {
+    fn from(value: Vec<i64>) -> Self {
+        let mut machine = Machine::new();
+        machine.cells = value;
+        machine
+    }
+}
+
+pub mod macros {
+    #[macro_export]
+    macro_rules! add_instr {
+        ($op:ident) => {
+            AluNullary(NullaryOp::$op)
+        };
+        ($op:ident, $a:expr) => {
+            // for immediate
+            AluUnaryImm(UnaryOpImm::$op, $a)
+        };
+        (R $op:ident, $a:expr) => {
+            // for register
+            AluUnaryCell(UnaryOpCell::$op, $a)
+        };
+        ($op:ident, $a:expr, $b:expr) => {
+            AluBinary(BinaryOp::$op, $a, $b)
+        };
+        (fun $op:ident, $name:expr) => {
+            AluFunction(FunctionOp::$op, $name)
+        };
+    }
+
+    #[macro_export]
+    macro_rules! make_block {
+        ($($instr:expr),+) => { // Variadic arguments, at least one
+            Block(vec![ $( $instr ),* ])
+        };
+    }
+
+    pub use add_instr;
+    pub use make_block;
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use Instruction::*;
+
+    macro_rules! test_binop {
+        ($name:ident, $a:expr, $b:expr, $op:ident => $expected:expr) => {
+            #[test]
+            fn $name() {
+                let program = vec![
+                    add_instr!(Push, $a),
+                    add_instr!(Push, $b),
+                    add_instr!($op, 0, 1),
+                ];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                machine.reset_pc();
+                let last = machine.run().unwrap();
+                assert_eq!(last, Some(&$expected));
+            }
+        };
+    }
+
+    macro_rules! test_unop {
+        ($name:ident, $a:expr, $op:ident => $expected:expr) => {
+            #[test]
+            fn $name() {
+                let program = vec![add_instr!(Push, $a), add_instr!(R $op, 0)];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                machine.reset_pc();
+                let last = machine.run().unwrap();
+                assert_eq!(last, Some(&$expected));
+            }
+        };
+    }
+
+    mod basic {
+        use super::*;
+
+        #[test]
+        fn test_get_cell() {
+            let program = vec![
+                add_instr!(Push, 6),
+                add_instr!(Push, 7),
+                add_instr!(Mul, 0, 1),
+                add_instr!(Add, 0, 1),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.run().unwrap();
+
+            assert_eq!(machine.cell_count(), 4);
+            assert_eq!(machine.get_cell(0).unwrap(), 6);
+            assert_eq!(machine.get_cell(2).unwrap(), 42);
+            assert_eq!(machine.get_cell(3).unwrap(), 13);
+            assert!(matches!(
+                machine.get_cell(4),
+                Err(MachineError::InvalidCell)
+            ));
+        }
+
+        #[test]
+        fn test_error_messages() {
+            let error = MachineError::InstructionError(String::from("bad opcode"));
+            assert_eq!(error.to_string(), "bad opcode");
+            assert_eq!(
+                MachineError::AssertionFailed(1, 2).to_string(),
+                "assertion failed: 1 != 2"
+            );
+            assert_eq!(MachineError::DivisionByZero.to_string(), "division by zero");
+
+            let error: Box<dyn std::error::Error> = Box::new(MachineError::StackUnderflow);
+            assert_eq!(error.to_string(), "stack underflow");
+        }
+
+        #[test]
+        fn test_push_pop() {
+            let program = vec![
+                add_instr!(Push, 1),
+                add_instr!(Push, 2),
+                add_instr!(Push, 3),
+                add_instr!(Push, 4),
+                add_instr!(Push, 5),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let _ = machine.run().unwrap();
+            assert_eq!(machine.cells[0], 1);
+            assert_eq!(machine.cells[1], 2);
+            assert_eq!(machine.cells[2], 3);
+            assert_eq!(machine.cells[3], 4);
+            assert_eq!(machine.cells[4], 5);
+            assert!(machine.cells.get(5).is_none()); // Ensure no extra cells exist
+
+            let prog = vec![add_instr!(Pop, -1)];
+            machine.load_program(&prog);
+            machine.reset_pc();
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::InvalidCell)));
+
+            let prog = vec![add_instr!(Pop, 1)];
+            machine.load_program(&prog);
+            machine.reset_pc();
+            let val = machine.run().unwrap();
+            assert_eq!(val, Some(&4));
+
+            let prog = vec![add_instr!(Pop, 2)];
+            machine.load_program(&prog);
+            machine.reset_pc();
+            let val = machine.run().unwrap();
+            assert_eq!(val, Some(&2));
+
+            let prog = vec![add_instr!(Pop, 2)];
+            machine.load_program(&prog);
+            machine.reset_pc();
+            let val = machine.run().unwrap();
+            assert_eq!(val, None);
+
+            let prog = vec![add_instr!(Pop, 1)];
+            machine.load_program(&prog);
+            machine.reset_pc();
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::StackUnderflow)));
+        }
+
+        #[test]
+        fn test_read() {
+            let program = vec![
+                add_instr!(Push, 100),
+                add_instr!(Push, 200),
+                add_instr!(R Read, 0),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&100));
+            assert_eq!(machine.cells[0], 100);
+            assert_eq!(machine.cells[1], 200);
+        }
+
+        #[test]
+        fn test_read_reverse() {
+            let program = vec![
+                add_instr!(Push, 10),
+                add_instr!(Push, 20),
+                add_instr!(Push, 30),
+                add_instr!(R ReadReverse, 1), // Should read 20
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&20));
+            assert_eq!(machine.cells[0], 10);
+            assert_eq!(machine.cells[1], 20);
+            assert_eq!(machine.cells[2], 30);
+        }
+
+        #[test]
+        fn test_push_range() {
+            let program = vec![PushRange { start: 0, count: 5 }];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&4));
+            assert_eq!(machine.cells, vec![0, 1, 2, 3, 4]);
+
+            let program = vec![PushRange {
+                start: i64::MAX,
+                count: 2,
+            }];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::InstructionError(_))));
+        }
+
+        #[test]
+        fn test_assert_eq() {
+            let program = vec![
+                add_instr!(Push, 7),
+                add_instr!(Push, 7),
+                AssertEq { a: 0, b: 1 },
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&7));
+            assert_eq!(machine.cells.len(), 2); // Assertions don't push anything
+
+            let program = vec![
+                add_instr!(Push, 7),
+                add_instr!(Push, 8),
+                AssertEq { a: 0, b: 1 },
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::AssertionFailed(7, 8))));
+        }
+
+        macro_rules! test_clamp {
+            ($name:ident, $value:expr => $expected:expr) => {
+                #[test]
+                fn $name() {
+                    let program = vec![
+                        add_instr!(Push, $value),
+                        add_instr!(Push, -10),
+                        add_instr!(Push, 10),
+                        Clamp {
+                            value: 0,
+                            lo: 1,
+                            hi: 2,
+                        },
+                    ];
+                    let mut machine = Machine::new();
+                    machine.load_program(&program);
+                    machine.reset_pc();
+                    let last = machine.run().unwrap();
+                    assert_eq!(last, Some(&$expected));
+                }
+            };
+        }
+
+        test_clamp!(test_clamp_below, -50 => -10);
+        test_clamp!(test_clamp_within, 3 => 3);
+        test_clamp!(test_clamp_above, 50 => 10);
+
+        #[test]
+        fn test_clamp_bad() {
+            let program = vec![
+                add_instr!(Push, 0),
+                add_instr!(Push, 10),
+                add_instr!(Push, -10),
+                Clamp {
+                    value: 0,
+                    lo: 1,
+                    hi: 2,
+                },
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run();
+            assert!(matches!(last, Err(MachineError::InvalidRange)));
+
+            let program = vec![Clamp {
+                value: 0,
+                lo: 1,
+                hi: 2,
+            }];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let last = machine.run();
+            assert!(matches!(last, Err(MachineError::InvalidCell)));
+        }
+
+        #[test]
+        fn test_empty_program() {
+            let program = vec![];
+            let mut machine = Machine::new();
+            assert!(matches!(machine.run(), Err(MachineError::ProgramNotLoaded)));
+
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), None);
+
+            machine.set_require_result(true);
+            assert!(matches!(machine.run(), Err(MachineError::NoResult)));
+
+            // Only a run ending with no cells at all is an error.
+            let program = vec![add_instr!(Push, 1), add_instr!(Pop, 1)];
+            machine.load_program(&program);
+            machine.reset_pc();
+            assert!(matches!(machine.run(), Err(MachineError::NoResult)));
+
+            let mut machine = Machine::from(vec![7]);
+            machine.set_require_result(true);
+            machine.load_program(&[]);
+            assert_eq!(machine.run().unwrap(), Some(&7));
+        }
+
+        #[test]
+        fn test_strict_scopes() {
+            let program = vec![make_block!(add_instr!(Push, 1)), add_instr!(Push, 2)];
+            let mut machine = Machine::new();
+            machine.set_strict_scopes(true);
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&2));
+
+            // A scope left open, as a bug in block handling would.
+            machine.base_stack.push(0);
+            machine.reset_pc();
+            assert!(matches!(machine.run(), Err(MachineError::UnbalancedScopes)));
+
+            machine.set_strict_scopes(false);
+            machine.reset_pc();
+            assert!(machine.run().is_ok());
+        }
+
+        #[test]
+        fn test_dup_window() {
+            let program = vec![PushRange { start: 1, count: 3 }, add_instr!(DupWindow)];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.run().unwrap();
+            assert_eq!(machine.cells, vec![1, 2, 3, 1, 2, 3]);
+
+            // Inside a block only the block's own cells are duplicated, and
+            // after a rebase they're all that's left.
+            let program = vec![
+                add_instr!(Push, 1),
+                make_block!(
+                    add_instr!(Push, 2),
+                    add_instr!(Push, 3),
+                    add_instr!(DupWindow),
+                    add_instr!(Rebase),
+                    add_instr!(DupWindow),
+                    AssertEq { a: 0, b: 6 },
+                    add_instr!(Add, 0, 7)
+                ),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&5));
+            assert_eq!(machine.cells, vec![1, 5]);
+        }
+
+        #[test]
+        fn test_sum_top() {
+            let program = vec![PushRange { start: 1, count: 4 }, SumTop(3)];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&9));
+            assert_eq!(machine.cells, vec![1, 9]);
+
+            let program = vec![add_instr!(Push, 1), SumTop(0)];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.run().unwrap();
+            assert_eq!(machine.cells, vec![1, 0]);
+
+            let program = vec![add_instr!(Push, 1), SumTop(2)];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::StackUnderflow)));
+            assert_eq!(machine.cells, vec![1]);
+        }
+
+        #[test]
+        fn test_div_mod() {
+            let program = vec![
+                add_instr!(Push, 17),
+                add_instr!(Push, 5),
+                DivMod { a: 0, b: 1 },
+                add_instr!(Push, -17),
+                DivMod { a: 4, b: 1 },
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.run().unwrap();
+            assert_eq!(machine.cells, vec![17, 5, 3, 2, -17, -3, -2]);
+
+            for (a, b, error) in [
+                (17, 0, MachineError::DivisionByZero),
+                (i64::MIN, -1, MachineError::DivisionOverflow),
+            ] {
+                let program = vec![
+                    add_instr!(Push, a),
+                    add_instr!(Push, b),
+                    DivMod { a: 0, b: 1 },
+                ];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                let result = machine.run();
+                assert_eq!(
+                    std::mem::discriminant(&result.unwrap_err()),
+                    std::mem::discriminant(&error)
+                );
+                assert_eq!(machine.cells, vec![a, b]);
+            }
+
+            // Wrapping doesn't apply to DivMod.
+            let mut machine = Machine::from(vec![i64::MIN, -1]);
+            machine.set_wrapping(true);
+            machine.load_program(&program[2..3]);
+            assert!(matches!(machine.run(), Err(MachineError::DivisionOverflow)));
+        }
+
+        #[test]
+        fn test_average() {
+            for (a, b, midpoint) in [
+                (i64::MAX, i64::MAX - 2, i64::MAX - 1),
+                (i64::MIN, i64::MAX, -1),
+                (1, 4, 2),
+                (4, 1, 3),
+                (-4, -1, -3),
+            ] {
+                let program = vec![
+                    add_instr!(Push, a),
+                    add_instr!(Push, b),
+                    Average { a: 0, b: 1 },
+                ];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                assert_eq!(machine.run().unwrap(), Some(&midpoint));
+            }
+        }
+
+        #[test]
+        fn test_hash_range() {
+            let program = vec![
+                HashRange { start: 0, len: 3 },
+                HashRange { start: 3, len: 3 },
+                SetImm { dest: 4, value: 5 },
+                HashRange { start: 3, len: 3 },
+                HashRange { start: 0, len: 0 },
+                HashRange { start: 6, len: 0 },
+            ];
+            let mut machine = Machine::from(vec![1, 2, 3, 1, 2, 3]);
+            machine.load_program(&program);
+            machine.run().unwrap();
+            let hashes = &machine.cells[6..];
+            assert_eq!(hashes[0], hashes[1]);
+            assert_ne!(hashes[0], hashes[2]);
+            assert_eq!(hashes[3], hashes[4]); // Empty windows hash alike
+
+            let program = vec![HashRange { start: 1, len: 2 }];
+            let mut machine = Machine::from(vec![1, 2]);
+            machine.load_program(&program);
+            assert!(matches!(machine.run(), Err(MachineError::InvalidCell)));
+        }
+
+        #[test]
+        fn test_match_mask() {
+            let program = vec![
+                add_instr!(Push, 20),
+                MatchMask {
+                    value: 4,
+                    start: 0,
+                    len: 4,
+                },
+            ];
+            let mut machine = Machine::from(vec![10, 20, 30, 20]);
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&0b1010));
+
+            for len in [4, 65] {
+                let program = vec![MatchMask {
+                    value: 0,
+                    start: 1,
+                    len,
+                }];
+                let mut machine = Machine::from(vec![1, 2, 3, 4]);
+                machine.load_program(&program);
+                assert!(machine.run().is_err());
+            }
+        }
+
+        #[test]
+        fn test_fma() {
+            for (a, x, b, expected) in [
+                (3, 4, 5, 17),
+                (-2, 7, 1, -13),
+                // a * x alone overflows, but the sum fits.
+                (i64::MAX, 2, -i64::MAX, i64::MAX),
+                (i64::MIN, -1, i64::MIN, 0),
+            ] {
+                let program = vec![
+                    add_instr!(Push, a),
+                    add_instr!(Push, x),
+                    add_instr!(Push, b),
+                    Fma { a: 0, x: 1, b: 2 },
+                ];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                assert_eq!(machine.run().unwrap(), Some(&expected));
+            }
+        }
+
+        #[test]
+        fn test_integer_overflow() {
+            for (a, op, b, wrapped) in [
+                (i64::MAX, BinaryOp::Add, 1, i64::MIN),
+                (i64::MIN, BinaryOp::Mul, -1, i64::MIN),
+                (1, BinaryOp::ShiftLeftLogical, 65, 2),
+                (-1, BinaryOp::ShiftRightLogical, -1, 1),
+            ] {
+                let program = vec![
+                    add_instr!(Push, a),
+                    add_instr!(Push, b),
+                    AluBinary(op, 0, 1),
+                ];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                assert!(matches!(machine.run(), Err(MachineError::IntegerOverflow)));
+                assert_eq!(machine.cells, vec![a, b]);
+
+                machine.set_wrapping(true);
+                machine.reset_pc();
+                assert_eq!(machine.run().unwrap(), Some(&wrapped));
+            }
+
+            // Shifting out set bits isn't an overflow.
+            let program = vec![
+                add_instr!(Push, i64::MIN),
+                add_instr!(Push, 1),
+                add_instr!(ShiftLeftLogical, 0, 1),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&0));
+        }
+
+        #[test]
+        fn test_input() {
+            let program = vec![Input(String::from("x")), Input(String::from("unset"))];
+            let mut machine = Machine::new();
+            machine.set_input("x", 42);
+            machine.load_program(&program);
+            machine.run().unwrap();
+            assert_eq!(machine.cells, vec![42, 0]);
+        }
+
+        #[test]
+        fn test_reverse_top() {
+            let program = vec![ReverseTop(3)];
+            let mut machine = Machine::from(vec![1, 2, 3, 4]);
+            machine.load_program(&program);
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&2));
+            assert_eq!(machine.cells, vec![1, 4, 3, 2]);
+
+            let program = vec![ReverseTop(0), ReverseTop(5)];
+            let mut machine = Machine::from(vec![1, 2, 3, 4]);
+            machine.load_program(&program);
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::StackUnderflow)));
+            assert_eq!(machine.cells, vec![1, 2, 3, 4]);
+        }
+
+        #[test]
+        fn test_pop_dynamic() {
+            let program = vec![PopDynamic(0)];
+            let mut machine = Machine::from(vec![2, 5, 9, 7]);
+            machine.load_program(&program);
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&5));
+            assert_eq!(machine.cells, vec![2, 5]);
+
+            // Too many or a negative number of cells leave the stack alone.
+            for count in [4, -1] {
+                let mut machine = Machine::from(vec![count, 1, 2]);
+                machine.load_program(&program);
+                assert!(machine.run().is_err());
+                assert_eq!(machine.cells, vec![count, 1, 2]);
+            }
+        }
+
+        #[test]
+        fn test_push_all() {
+            let program = vec![add_instr!(R Read, 1), add_instr!(Add, 0, 2)];
+            let mut machine = Machine::from(vec![1]);
+            machine.push_all(&[10, 20, 30]).unwrap();
+            assert_eq!(machine.cells, vec![1, 10, 20, 30]);
+
+            machine.load_program(&program);
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&21));
+            assert_eq!(machine.cells[4], 10);
+        }
+
+        #[test]
+        fn test_set_imm() {
+            let program = vec![SetImm { dest: 1, value: 42 }];
+            let mut machine = Machine::from(vec![0, 0, 0]);
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&0));
+            assert_eq!(machine.cells, vec![0, 42, 0]);
+
+            let program = vec![SetImm { dest: 3, value: 42 }];
+            machine.load_program(&program);
+            machine.reset_pc();
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::InvalidCell)));
+            assert_eq!(machine.cells, vec![0, 42, 0]);
+        }
+
+        #[test]
+        fn test_immutable_cells() {
+            let program = vec![SetImm { dest: 1, value: 7 }, SetImm { dest: 0, value: 7 }];
+            let mut machine = Machine::from(vec![1, 2, 3]);
+            machine.mark_immutable(0);
+            machine.load_program(&program);
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::ImmutableCell)));
+            assert_eq!(machine.cells, vec![1, 7, 3]);
+
+            let program = vec![ReverseTop(2), ReverseTop(3)];
+            machine.load_program(&program);
+            machine.reset_pc();
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::ImmutableCell)));
+            assert_eq!(machine.cells, vec![1, 3, 7]);
+        }
+
+        #[test]
+        fn test_test_bit() {
+            let pattern = 0b1010_0101;
+            for (bit, expected) in [
+                (0, 1),
+                (1, 0),
+                (2, 1),
+                (3, 0),
+                (5, 1),
+                (6, 0),
+                (7, 1),
+                (8, 0),
+            ] {
+                let program = vec![
+                    add_instr!(Push, pattern),
+                    add_instr!(Push, bit),
+                    TestBit { value: 0, bit: 1 },
+                ];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                machine.reset_pc();
+                let last = machine.run().unwrap();
+                assert_eq!(last, Some(&expected), "bit {}", bit);
+            }
+
+            // The sign bit, and bit indices wrapping around past 63.
+            let program = vec![
+                add_instr!(Push, i64::MIN),
+                add_instr!(Push, 63),
+                add_instr!(Push, 64),
+                TestBit { value: 0, bit: 1 },
+                TestBit { value: 0, bit: 2 },
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            machine.run().unwrap();
+            assert_eq!(machine.cells[3..], [1, 0]);
+        }
+
+        test_binop!(test_add, 10, 20, Add => 30);
+        test_binop!(test_add_neg, 10, -30, Add => -20);
+        test_binop!(test_mul, 10, 20, Mul => 200);
+        test_binop!(test_div, 20, 5, Div => 4);
+        test_binop!(test_sub, 10, 30, Sub => -20);
+        test_binop!(test_mod, 17, 5, Mod => 2);
+        test_binop!(test_mod_neg, -17, 5, Mod => -2);
+
+        #[test]
+        fn test_div_bad() {
+            let program = vec![
+                add_instr!(Push, 10),
+                add_instr!(Push, 0),
+                add_instr!(Div, 0, 1),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run();
+            assert!(matches!(last, Err(MachineError::DivisionByZero)));
+        }
+
+        #[test]
+        fn test_div_overflow() {
+            let program = vec![
+                add_instr!(Push, i64::MIN),
+                add_instr!(Push, -1),
+                add_instr!(Div, 0, 1),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert!(matches!(machine.run(), Err(MachineError::IntegerOverflow)));
+
+            let mut machine = Machine::new();
+            machine.set_wrapping(true);
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&i64::MIN));
+
+            // Even wrapping, there's no quotient to wrap.
+            let program = vec![
+                add_instr!(Push, 1),
+                add_instr!(Push, 0),
+                add_instr!(Div, 0, 1),
+            ];
+            let mut machine = Machine::new();
+            machine.set_wrapping(true);
+            machine.load_program(&program);
+            assert!(matches!(machine.run(), Err(MachineError::DivisionByZero)));
+        }
+
+        #[test]
+        fn test_mod_bad() {
+            for (b, error) in [
+                (0, MachineError::DivisionByZero),
+                (-1, MachineError::IntegerOverflow),
+            ] {
+                let program = vec![
+                    add_instr!(Push, i64::MIN),
+                    add_instr!(Push, b),
+                    add_instr!(Mod, 0, 1),
+                ];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                let last = machine.run();
+                assert_eq!(
+                    std::mem::discriminant(&last.unwrap_err()),
+                    std::mem::discriminant(&error)
+                );
+            }
+        }
+
+        test_binop!(test_and, 0b1100, 0b1010, And => 0b1000);
+        test_binop!(test_or, 0b1100, 0b1010, Or => 0b1110);
+        test_binop!(test_xor, 0b1100, 0b1010, Xor => 0b0110);
+
+        #[test]
+        fn test_not() {
+            let program = vec![add_instr!(Push, 0b1100), add_instr!(R Not, 0)];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&(!0b1100)));
+        }
+
+        test_unop!(test_to_bool_zero, 0, ToBool => 0);
+        test_unop!(test_to_bool_positive, 42, ToBool => 1);
+        test_unop!(test_to_bool_negative, -7, ToBool => 1);
+        test_unop!(test_neg, 42, Neg => -42);
+        test_unop!(test_neg_max, i64::MAX, Neg => -i64::MAX);
+
+        #[test]
+        fn test_neg_overflow() {
+            let program = vec![add_instr!(Push, i64::MIN), add_instr!(R Neg, 0)];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert!(matches!(machine.run(), Err(MachineError::IntegerOverflow)));
+
+            machine.set_wrapping(true);
+            machine.reset_pc();
+            assert_eq!(machine.run().unwrap(), Some(&i64::MIN));
+        }
+
+        test_binop!(test_slt, 10, 20, SetLessThan => 1);
+        test_binop!(test_sgt, 20, 10, SetGreaterThan => 1);
+        test_binop!(test_seq, 10, 10, SetEqual => 1);
+        test_binop!(test_sne, 10, 20, SetNotEqual => 1);
+        test_binop!(test_sle, 10, 10, SetLessThanOrEqual => 1);
+        test_binop!(test_sge, 20, 10, SetGreaterThanOrEqual => 1);
+
+        // -1 has every bit set, so it's the largest u64.
+        test_binop!(test_slt_signed, -1, 1, SetLessThan => 1);
+        test_binop!(test_sltu, -1, 1, SetLessThanUnsigned => 0);
+        test_binop!(test_sleu, -1, 1, SetLessThanOrEqualUnsigned => 0);
+        test_binop!(test_sgt_signed, -1, 1, SetGreaterThan => 0);
+        test_binop!(test_sgtu, -1, 1, SetGreaterThanUnsigned => 1);
+        test_binop!(test_sgeu, -1, 1, SetGreaterThanOrEqualUnsigned => 1);
+        test_binop!(test_sgeu_equal, -1, -1, SetGreaterThanOrEqualUnsigned => 1);
+
+        test_binop!(test_sll, 0b0001, 2, ShiftLeftLogical => 0b0100);
+        test_binop!(test_srl, 0b0100, 2, ShiftRightLogical => 0b0001);
+        test_binop!(test_sra, -8, 2, ShiftRightArithmetic => -2);
+
+        #[test]
+        fn nop() {
+            let program = vec![add_instr!(Nop)];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, None);
+        }
+
+        #[test]
+        fn math_with_read() {
+            let program = vec![
+                add_instr!(Push, 50),
+                add_instr!(Push, 70),
+                add_instr!(Push, 10),
+                add_instr!(Add, 0, 1), // 50 + 70 = 120
+                add_instr!(Div, 3, 2), // 120 / 10 = 12
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&12));
+
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let results = machine.run_collecting_results().unwrap();
+            assert_eq!(
+                results,
+                vec![Some(50), Some(70), Some(10), Some(120), Some(12)]
+            );
+        }
+
+        #[test]
+        fn results_with_skipped_instructions() {
+            let program = vec![
+                add_instr!(fun FunctionDefine, String::from("f")),
+                make_block!(add_instr!(Push, 1)),
+                add_instr!(Push, 0),
+                add_instr!(Cond),
+                add_instr!(Push, 2),
+                add_instr!(fun FunctionCall, String::from("f")),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let results = machine.run_collecting_results().unwrap();
+            assert_eq!(results, vec![None, None, Some(0), None, None, Some(1)]);
+        }
+
+        #[test]
+        fn results_checked_like_run() {
+            let program = vec![add_instr!(Push, 1), add_instr!(Pop, 1)];
+            let mut machine = Machine::new();
+            machine.set_require_result(true);
+            machine.load_program(&program);
+            assert!(matches!(
+                machine.run_collecting_results(),
+                Err(MachineError::NoResult)
+            ));
+
+            let mut machine = Machine::new();
+            machine.set_strict_scopes(true);
+            machine.load_program(&program);
+            machine.base_stack.push(0);
+            assert!(matches!(
+                machine.run_collecting_results(),
+                Err(MachineError::UnbalancedScopes)
+            ));
+        }
+    }
+
+    mod opcodes {
+        use super::*;
+        use std::collections::HashSet;
+
+        // One instruction for every opcode in the table. The match has no
+        // wildcard, so new table entries must be given a sample here.
+        fn sample(opcode: Opcode) -> Instruction {
+            use Opcode as O;
+
+            match opcode {
+                O::Nop => add_instr!(Nop),
+                O::Rebase => add_instr!(Rebase),
+                O::Cond => add_instr!(Cond),
+                O::DupWindow => add_instr!(DupWindow),
+                O::InstructionCount => add_instr!(InstructionCount),
+                O::Push => add_instr!(Push, 1),
+                O::Pop => add_instr!(Pop, 1),
+                O::Not => add_instr!(R Not, 0),
+                O::Read => add_instr!(R Read, 0),
+                O::ReadReverse => add_instr!(R ReadReverse, 0),
+                O::ToBool => add_instr!(R ToBool, 0),
+                O::Neg => add_instr!(R Neg, 0),
+                O::Add => add_instr!(Add, 0, 1),
+                O::Mul => add_instr!(Mul, 0, 1),
+                O::Div => add_instr!(Div, 0, 1),
+                O::Sub => add_instr!(Sub, 0, 1),
+                O::Mod => add_instr!(Mod, 0, 1),
+                O::And => add_instr!(And, 0, 1),
+                O::Or => add_instr!(Or, 0, 1),
+                O::Xor => add_instr!(Xor, 0, 1),
+                O::ShiftLeftLogical => add_instr!(ShiftLeftLogical, 0, 1),
+                O::ShiftRightLogical => add_instr!(ShiftRightLogical, 0, 1),
+                O::ShiftRightArithmetic => add_instr!(ShiftRightArithmetic, 0, 1),
+                O::SetEqual => add_instr!(SetEqual, 0, 1),
+                O::SetNotEqual => add_instr!(SetNotEqual, 0, 1),
+                O::SetLessThan => add_instr!(SetLessThan, 0, 1),
+                O::SetLessThanOrEqual => add_instr!(SetLessThanOrEqual, 0, 1),
+                O::SetGreaterThan => add_instr!(SetGreaterThan, 0, 1),
+                O::SetGreaterThanOrEqual => add_instr!(SetGreaterThanOrEqual, 0, 1),
+                O::SetLessThanUnsigned => add_instr!(SetLessThanUnsigned, 0, 1),
+                O::SetLessThanOrEqualUnsigned => add_instr!(SetLessThanOrEqualUnsigned, 0, 1),
+                O::SetGreaterThanUnsigned => add_instr!(SetGreaterThanUnsigned, 0, 1),
+                O::SetGreaterThanOrEqualUnsigned => {
+                    add_instr!(SetGreaterThanOrEqualUnsigned, 0, 1)
+                }
+                O::FunctionDefine => add_instr!(fun FunctionDefine, String::from("f")),
+                O::FunctionCall => add_instr!(fun FunctionCall, String::from("f")),
+                O::Tail => add_instr!(fun Tail, String::from("f")),
+                O::Block => make_block!(add_instr!(Nop)),
+                O::PushRange => PushRange { start: 0, count: 1 },
+                O::AssertEq => AssertEq { a: 0, b: 1 },
+                O::Clamp => Clamp {
+                    value: 0,
+                    lo: 1,
+                    hi: 2,
+                },
+                O::SetImm => SetImm { dest: 0, value: 1 },
+                O::TestBit => TestBit { value: 0, bit: 1 },
+                O::HostCall => HostCall {
+                    name: String::from("f"),
+                    args: vec![0],
+                },
+                O::Input => Input(String::from("x")),
+                O::Succeeds => Succeeds(vec![]),
+                O::SumTop => SumTop(2),
+                O::DivMod => DivMod { a: 0, b: 1 },
+                O::Average => Average { a: 0, b: 1 },
+                O::Fma => Fma { a: 0, x: 1, b: 0 },
+                O::HashRange => HashRange { start: 0, len: 2 },
+                O::MatchMask => MatchMask {
+                    value: 0,
+                    start: 0,
+                    len: 2,
+                },
+                O::BranchIfZero => BranchIfZero(0, vec![], vec![add_instr!(Push, 1)]),
+                O::Store => Store(0, 1),
+                O::Load => Load(1),
+                O::FunctionDefineArity => FunctionDefineArity(String::from("f"), 2),
+                O::Jump => Jump(1),
+                O::JumpIfZero => JumpIfZero(0, 0),
+                O::JumpIfNotZero => JumpIfNotZero(0, 1),
+                O::Return => Return,
+                O::ReturnCell => ReturnCell(0),
+                O::Assume => Assume(0),
+                O::Assert => Assert(0),
+                O::PopDynamic => PopDynamic(0),
+                O::ReverseTop => ReverseTop(2),
+                O::HostUnary => HostUnary {
+                    name: String::from("f"),
+                    arg: 0,
+                },
+                O::Switch => Switch {
+                    selector: 0,
+                    cases: vec![vec![]],
+                    default: vec![],
+                },
+            }
+        }
+
+        #[test]
+        fn test_table_covers_every_instruction() {
+            let mut mnemonics = HashSet::new();
+            let mut bytes = HashSet::new();
+
+            for &opcode in Opcode::ALL {
+                assert_eq!(sample(opcode).opcode(), opcode);
+                assert_eq!(Opcode::from_mnemonic(opcode.mnemonic()), Some(opcode));
+                assert_eq!(Opcode::from_byte(opcode as u8), Some(opcode));
+                assert!(mnemonics.insert(opcode.mnemonic()), "{:?}", opcode);
+                assert!(bytes.insert(opcode as u8), "{:?}", opcode);
+            }
+        }
+
+        #[test]
+        fn test_operator_mnemonics() {
+            for op in BinaryOp::ALL {
+                assert_eq!(BinaryOp::from_mnemonic(op.mnemonic()), Some(*op));
+            }
+            assert_eq!(
+                UnaryOpCell::from_mnemonic("readrev"),
+                Some(UnaryOpCell::ReadReverse)
+            );
+            assert_eq!(FunctionOp::FunctionDefine.mnemonic(), "fn");
+            assert_eq!(NullaryOp::from_mnemonic("block"), None);
+        }
+    }
+
+    mod blocks {
+        use super::*;
+
+        #[test]
+        fn test_no_arg() {
+            let program = vec![
+                add_instr!(Push, 10),
+                add_instr!(Push, 20),
+                add_instr!(Add, 0, 1),
+                make_block!(
+                    add_instr!(Push, 2),   // This push should be deleted after block ends
+                    add_instr!(Mul, 2, 3) // This is the last push, the result, and it should remain after block ends
+                ),
+                add_instr!(Add, 2, 3),
+            ];
+
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&90)); // (10 + 20) + ((10 + 20) * 2) = 90
+
+            assert_eq!(machine.cells[0], 10);
+            assert_eq!(machine.cells[1], 20);
+            assert_eq!(machine.cells[2], 30); // Result of first addition
+            assert_eq!(machine.cells[3], 60); // Result of multiplication inside block
+            assert_eq!(machine.cells[4], 90); // Final result
+            assert!(machine.cells.get(5).is_none()); // Ensure no extra cells exist
+            assert_eq!(machine.cells.len(), 5);
+        }
+
+        #[test]
+        fn test_nested() {
+            let program = vec![
+                add_instr!(Push, 3),
+                make_block!(
+                    add_instr!(Push, 4),
+                    make_block!(
+                        add_instr!(Push, 5),
+                        add_instr!(Mul, 1, 2) // 4 * 5 = 20
+                    ),
+                    add_instr!(Add, 0, 2) // 3 + 20 = 23
+                ),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&23));
+            assert_eq!(machine.cells[0], 3);
+            assert_eq!(machine.cells[1], 23);
+        }
+
+        #[test]
+        fn test_square_fn() {
+            let square_block = make_block!(
+                add_instr!(R ReadReverse, 0),
+                add_instr!(R ReadReverse, 0),
+                add_instr!(Rebase),
+                add_instr!(Mul, 0, 1) // Multiply input by 2
+            );
+
+            let program = vec![
+                add_instr!(Push, 2),
+                square_block.clone(),
+                square_block.clone(),
+            ];
+
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&16));
+        }
+
+        #[test]
+        fn test_with_pop() {
+            let block = make_block!(
+                add_instr!(Pop, 2) // Pop the 20, leaving only 30
+            );
+
+            let program = vec![
+                add_instr!(Push, 3),
+                add_instr!(Push, 5),
+                block,
+                add_instr!(Mul, 0, 1), // 3 * 5 = 15
+            ];
+
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&15));
+        }
+
+        #[test]
+        fn test_nested_rebase_1() {
+            let program = vec![
+                add_instr!(Push, 2),
+                make_block!(
+                    add_instr!(Push, 3),
+                    add_instr!(Rebase),
+                    make_block!(
+                        add_instr!(Push, 4),
+                        add_instr!(Mul, 0, 1) // 3 * 4 = 12
+                    ),
+                    add_instr!(Add, 0, 1) // 3 + 12 = 14
+                ),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&15));
+            assert_eq!(machine.cells[0], 2);
+            assert_eq!(machine.cells[1], 15);
+        }
+
+        #[test]
+        fn test_nested_rebase_2() {
+            let program = vec![
+                add_instr!(Push, 2),
+                make_block!(
+                    add_instr!(Push, 3),
+                    add_instr!(Rebase),
+                    make_block!(
+                        add_instr!(R ReadReverse, 0),
+                        add_instr!(Push, 4),
+                        add_instr!(Rebase),
+                        add_instr!(Mul, 0, 1) // 3 * 4 = 12
+                    ),
+                    add_instr!(Add, 0, 1) // 3 + 12 = 14
+                ),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&15));
+            assert_eq!(machine.cells[0], 2);
+            assert_eq!(machine.cells[1], 15);
+        }
+
+        #[test]
+        fn test_error_unwinds_frames() {
+            let program = vec![
+                add_instr!(Push, 1),
+                make_block!(add_instr!(Push, 0), make_block!(add_instr!(Div, 0, 1))),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::DivisionByZero)));
+            assert!(machine.frames.is_empty());
+            assert_eq!(machine.cells, vec![1]);
+            assert_eq!(machine.pc, 1); // The failing top-level instruction
+        }
+
+        #[test]
+        fn test_succeeds() {
+            let program = vec![
+                add_instr!(Push, 0),
+                add_instr!(Push, 6),
+                Succeeds(vec![
+                    add_instr!(Push, 3),
+                    make_block!(add_instr!(Div, 1, 0)), // Divides by zero
+                    add_instr!(Push, 4),
+                ]),
+                Succeeds(vec![add_instr!(Push, 3), add_instr!(Div, 1, 3)]),
+                Succeeds(vec![SetImm { dest: 0, value: 9 }]),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&1));
+            assert!(machine.frames.is_empty());
+            // The bodies' cell effects are discarded either way.
+            assert_eq!(machine.cells, vec![0, 6, 0, 1, 1]);
+        }
+
+        #[test]
+        fn test_succeeds_inside_function() {
+            let program = vec![
+                add_instr!(fun FunctionDefine, String::from("check")),
+                make_block!(
+                    add_instr!(Push, 0),
+                    Succeeds(vec![make_block!(add_instr!(Div, 1, 1))])
+                ),
+                add_instr!(Push, 5),
+                add_instr!(fun FunctionCall, String::from("check")),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&0));
+            assert_eq!(machine.cells, vec![5, 0]);
+        }
+
+        #[test]
+        fn test_switch() {
+            let switch = || Switch {
+                selector: 0,
+                cases: vec![
+                    vec![add_instr!(Push, 10)],
+                    vec![add_instr!(Push, 20), add_instr!(Add, 0, 1)],
+                ],
+                default: vec![add_instr!(Push, -1)],
+            };
+
+            for (selector, expected) in [(0, 10), (1, 21), (2, -1), (-1, -1), (i64::MAX, -1)] {
+                let program = vec![add_instr!(Push, selector), switch()];
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                let last = machine.run().unwrap();
+                assert_eq!(last, Some(&expected), "selector {}", selector);
+                assert_eq!(machine.cells, vec![selector, expected]);
+            }
+
+            // Cases run in their own frame, like blocks.
+            let program = vec![
+                add_instr!(Push, 0),
+                Switch {
+                    selector: 0,
+                    cases: vec![vec![make_block!(add_instr!(Push, 5)), add_instr!(Rebase)]],
+                    default: vec![],
+                },
+                add_instr!(Push, 7),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.run().unwrap();
+            assert!(machine.frames.is_empty());
+            assert_eq!(machine.cells, vec![0, 5, 7]);
+        }
+
+        #[test]
+        fn test_bodies_leave_caller_cells_alone() {
+            let program = vec![
+                PushRange { start: 1, count: 3 },
+                make_block!(
+                    SetImm { dest: 0, value: 9 },
+                    add_instr!(Pop, 2),
+                    add_instr!(Push, 7),
+                    make_block!(ReverseTop(2), SetImm { dest: 1, value: 8 }),
+                    add_instr!(Push, 7),
+                    AssertEq { a: 1, b: 3 }, // The inner block's changes are undone
+                    add_instr!(R Read, 2)
+                ),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&8));
+            assert_eq!(machine.cells, vec![1, 2, 3, 8]);
+
+            // Frames left by an error are undone all the way out.
+            let program = vec![
+                PushRange { start: 1, count: 2 },
+                Succeeds(vec![
+                    SetImm { dest: 0, value: 5 },
+                    make_block!(
+                        add_instr!(Pop, 2),
+                        add_instr!(Push, 0),
+                        add_instr!(Div, 0, 0)
+                    ),
+                ]),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&0));
+            assert_eq!(machine.cells, vec![1, 2, 0]);
+
+            // So are the blocks a tail call leaves.
+            let program = vec![
+                add_instr!(fun FunctionDefine, String::from("f")),
+                add_instr!(fun FunctionDefine, String::from("g")),
+                make_block!(make_block!(
+                    SetImm { dest: 0, value: 4 },
+                    add_instr!(Pop, 2),
+                    add_instr!(fun Tail, String::from("g"))
+                )),
+                make_block!(add_instr!(Push, 6)),
+                PushRange { start: 1, count: 2 },
+                add_instr!(fun FunctionCall, String::from("f")),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&6));
+            assert_eq!(machine.cells, vec![1, 2, 6]);
+        }
+
+        #[test]
+        fn test_branch_if_zero_abs() {
+            // The untaken branch would fail if it ran.
+            let abs = || {
+                vec![
+                    add_instr!(Push, 0),
+                    add_instr!(SetLessThan, 0, 1),
+                    BranchIfZero(2, vec![add_instr!(R Read, 0)], vec![add_instr!(R Neg, 0)]),
+                ]
+            };
+
+            for (x, expected) in [(-7, 7), (0, 0), (12, 12)] {
+                let program = abs();
+                let mut machine = Machine::from(vec![x]);
+                machine.load_program(&program);
+                assert_eq!(machine.run().unwrap(), Some(&expected), "abs({})", x);
+                assert_eq!(machine.cells, vec![x, 0, i64::from(x < 0), expected]);
+            }
+
+            // Nested inside a block, with a branch that rebases
+            let program = vec![
+                add_instr!(Push, 4),
+                make_block!(
+                    add_instr!(Push, 0),
+                    BranchIfZero(
+                        1,
+                        vec![
+                            add_instr!(Rebase),
+                            add_instr!(Push, 3),
+                            add_instr!(Add, 0, 0)
+                        ],
+                        vec![add_instr!(Div, 0, 1)],
+                    ),
+                    add_instr!(Add, 0, 2)
+                ),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&10));
+            assert!(machine.frames.is_empty());
+            assert_eq!(machine.cells, vec![4, 10]);
+        }
+
+        #[test]
+        fn test_square_add_42_redundant_check() {
+            let program = vec![
+                add_instr!(Push, 5),
+                make_block!(
+                    add_instr!(R ReadReverse, 0),
+                    add_instr!(Rebase),
+                    add_instr!(Mul, 0, 0),
+                    add_instr!(Push, 42),
+                    add_instr!(Mul, 0, 2),
+                    add_instr!(Add, 1, 3)
+                ),
+                add_instr!(Push, -8),
+                add_instr!(Push, 3),
+                add_instr!(ShiftRightLogical, 2, 3),
+                add_instr!(ShiftRightArithmetic, 2, 3),
+                add_instr!(ShiftLeftLogical, 2, 3),
+                add_instr!(Div, 2, 3),
+                add_instr!(Xor, 2, 1),
+                add_instr!(SetLessThan, 2, 3),
+            ];
+
+            let mut machine = Machine::new();
+            machine.set_redundant_check(true);
+            machine.load_program(&program);
+            machine.run().unwrap();
+            assert_eq!(machine.cells[1], 235);
+            assert_eq!(machine.cells[4..7], [(-8i64 as u64 >> 3) as i64, -1, -64]);
+        }
+
+        #[test]
+        fn test_square_add_42() {
+            let program = vec![
+                add_instr!(Push, 5), // Argument
+                make_block!(
+                    add_instr!(R ReadReverse, 0), // Read x . . . r0 <- x
+                    add_instr!(Rebase),
+                    add_instr!(Mul, 0, 0), // x ^ 2 . . . r1 <- r0 ^ 2
+                    add_instr!(Push, 42),  // r2 <- 42
+                    add_instr!(Mul, 0, 2), // x * 42 . . . r3 <- r0 * r2
+                    add_instr!(Add, 1, 3)  // x^2 + 42x . . . r4 <- r1 + r3
+                ),
+            ];
+
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&235));
+            assert_eq!(machine.cells[0], 5);
+            assert_eq!(machine.cells[1], 235);
+            assert_eq!(machine.cells.len(), 2);
+        }
+    }
+
+    mod counted {
+        use super::*;
+
+        #[test]
+        fn test_count_on_error() {
+            let program = vec![
+                add_instr!(Push, 1),
+                add_instr!(Push, 0),
+                add_instr!(Push, 2),
+                add_instr!(Div, 0, 1),
+                add_instr!(Push, 3),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let (result, count) = machine.run_counted();
+            assert!(matches!(result, Err(MachineError::DivisionByZero)));
+            assert_eq!(count, 3);
+        }
+
+        #[test]
+        fn test_instruction_count() {
+            let program = vec![
+                add_instr!(Push, 1),
+                add_instr!(Nop),
+                make_block!(add_instr!(Push, 2), add_instr!(InstructionCount)),
+                add_instr!(InstructionCount),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.run().unwrap();
+            assert_eq!(machine.cells, vec![1, 4, 5]);
+
+            // Every run counts from zero.
+            machine.reset_pc();
+            machine.run().unwrap();
+            assert_eq!(machine.cells[3..], [1, 4, 5]);
+        }
+
+        #[test]
+        fn test_count_includes_bodies() {
+            let program = vec![
+                add_instr!(Push, 1),
+                make_block!(add_instr!(Push, 2), add_instr!(Add, 0, 1)),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            let (result, count) = machine.run_counted();
+            assert_eq!(result.unwrap(), Some(3));
+            assert_eq!(count, 4);
+
+            // Counting starts over with every run.
+            machine.reset_pc();
+            assert_eq!(machine.run_counted().1, 4);
+        }
+
+        #[test]
+        fn test_run_with_budget() {
+            let mut program = vec![add_instr!(Push, 1), add_instr!(Add, 0, 0)];
+            for _ in 0..20 {
+                program = vec![add_instr!(Push, 1), Block(program), add_instr!(Add, 0, 1)];
+            }
+
+            // 20 levels of three instructions around the innermost two
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run_with_budget(62).unwrap(), Some(&3));
+            machine.reset_pc();
+            let result = machine.run_with_budget(61);
+            assert!(matches!(result, Err(MachineError::StepLimitExceeded)));
+
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert!(matches!(
+                machine.run_with_budget(30),
+                Err(MachineError::StepLimitExceeded)
+            ));
+            assert!(machine.frames.is_empty());
+            assert_eq!(machine.cells, vec![1]); // As when the outer block started
+
+            // The budget isn't left behind, so the run can be resumed.
+            assert_eq!(machine.run().unwrap(), Some(&3));
+        }
+
+        #[test]
+        fn test_fuel() {
+            let program = vec![
+                add_instr!(Push, 2),
+                make_block!(add_instr!(Push, 3), add_instr!(Mul, 0, 1)),
+                add_instr!(Add, 0, 1),
+            ];
+
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.set_fuel(5);
+            assert_eq!(machine.run().unwrap(), Some(&8));
+            assert_eq!(machine.fuel(), Some(0));
+
+            // Blocks cost 10 and multiplication 4.
+            machine.set_cost_model(|instr: &Instruction| match instr {
+                Block(_) => 10,
+                AluBinary(BinaryOp::Mul, ..) => 4,
+                _ => 1,
+            });
+            machine.reset_pc();
+            machine.set_fuel(14);
+            assert!(matches!(machine.run(), Err(MachineError::OutOfFuel)));
+            assert_eq!(machine.cells, vec![2, 6, 8, 2]); // As when the block started
+            assert_eq!(machine.fuel(), Some(2)); // Spent on both pushes and the block
+
+            machine.refill_fuel(3);
+            assert!(matches!(machine.run(), Err(MachineError::OutOfFuel)));
+            machine.refill_fuel(20);
+            assert_eq!(machine.run().unwrap(), Some(&8));
+            assert_eq!(machine.fuel(), Some(9));
+
+            machine.remove_fuel();
+            machine.reset_pc();
+            assert_eq!(machine.run().unwrap(), Some(&8));
+            assert_eq!(machine.fuel(), None);
+        }
+    }
+
+    mod external {
+        use super::*;
+
+        #[test]
+        fn test_host_sees_mutations() {
+            let program = vec![add_instr!(Add, 0, 1), SetImm { dest: 0, value: -1 }];
+            let mut buffer = vec![20, 22];
+            let mut machine = Machine::from(vec![7]);
+            machine.load_program(&program);
+
+            let last = machine
+                .with_external_cells(&mut buffer, |machine| {
+                    machine.run().map(|last| last.copied())
+                })
+                .unwrap();
+            assert_eq!(last, Some(42));
+            assert_eq!(buffer, vec![-1, 22, 42]);
+            assert_eq!(machine.cells, vec![7]);
+        }
+    }
+
+    mod steps {
+        use super::*;
+
+        #[test]
+        fn test_snapshot_per_instruction() {
+            let program = vec![
+                add_instr!(Push, 2),
+                make_block!(add_instr!(Push, 3), add_instr!(Mul, 0, 1)),
+                add_instr!(Add, 0, 1),
+            ];
+            let snapshots: Vec<_> = Machine::new()
+                .into_iter(&program)
+                .map(Result::unwrap)
+                .collect();
+
+            // The block's body is stepped through, and its result is in place
+            // as soon as its last instruction has run.
+            assert_eq!(snapshots, vec![Some(2), Some(2), Some(3), Some(6), Some(8)]);
+            assert_eq!(
+                snapshots.last(),
+                Some(&Machine::load_and_run(&program).unwrap())
+            );
+        }
+
+        #[test]
+        fn test_ends_after_error() {
+            let program = vec![
+                add_instr!(Push, 0),
+                add_instr!(Div, 0, 0),
+                add_instr!(Push, 1),
+            ];
+            let mut steps = Machine::new().into_iter(&program);
+            assert_eq!(steps.next().unwrap().unwrap(), Some(0));
+            assert!(matches!(
+                steps.next(),
+                Some(Err(MachineError::DivisionByZero))
+            ));
+            assert!(steps.next().is_none());
+            assert_eq!(steps.machine().cells, vec![0]);
+        }
+
+        #[test]
+        fn test_step_into() {
+            let program = vec![
+                add_instr!(Push, 1),
+                make_block!(add_instr!(Push, 2), add_instr!(Push, 3)),
+                add_instr!(Push, 4),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+
+            let stepped = |depth| StepResult::Stepped { depth };
+            assert_eq!(machine.step_into().unwrap(), stepped(0));
+            assert_eq!(machine.step_into().unwrap(), stepped(1)); // Entered the block
+            assert_eq!(machine.cells, vec![1]);
+            assert_eq!(machine.step_into().unwrap(), stepped(1));
+            assert_eq!(machine.cells, vec![1, 2]);
+            assert_eq!(machine.step_into().unwrap(), stepped(0)); // Its last instruction
+            assert_eq!(machine.cells, vec![1, 3]);
+            assert_eq!(machine.step_into().unwrap(), stepped(0));
+            assert_eq!(machine.step_into().unwrap(), StepResult::Finished);
+            assert_eq!(machine.cells, vec![1, 3, 4]);
+        }
+
+        #[test]
+        fn test_step() {
+            let program = vec![
+                add_instr!(Push, 1),
+                add_instr!(Push, 2),
+                make_block!(add_instr!(Push, 3), add_instr!(Add, 1, 2)),
+                add_instr!(Add, 0, 1),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+
+            for (step, last) in [1, 2, 5, 3].into_iter().enumerate() {
+                assert!(!machine.is_finished());
+                assert_eq!(machine.step().unwrap(), Some(&last));
+                assert_eq!(machine.cells().len(), step + 1);
+                assert_eq!(machine.pc(), step + 1);
+            }
+            assert!(machine.is_finished());
+            assert_eq!(machine.step().unwrap(), None);
+            assert_eq!(machine.cells(), [1, 2, 5, 3]);
+        }
+    }
+
+    mod jumps {
+        use super::*;
+
+        #[test]
+        fn test_loop() {
+            // Sums 5 + 4 + ... + 1, keeping [-1, counter, sum] at the bottom.
+            let program = vec![
+                add_instr!(Push, -1),
+                add_instr!(Push, 5),
+                add_instr!(Push, 0),
+                add_instr!(Add, 1, 2),
+                add_instr!(Add, 0, 1),
+                ReverseTop(4),
+                add_instr!(Pop, 2),
+                JumpIfNotZero(1, 3),
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&15));
+            assert_eq!(machine.cells, vec![-1, 0, 15]);
+        }
+
+        #[test]
+        fn test_jumps_within_bodies() {
+            let program = vec![
+                add_instr!(Push, 0),
+                JumpIfZero(0, 3),
+                add_instr!(Push, 1), // Skipped
+                make_block!(add_instr!(Push, 2), Jump(3), add_instr!(Push, 3)),
+                JumpIfNotZero(0, 0), // Not taken
+            ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&2));
+            assert_eq!(machine.cells, vec![0, 2]);
+        }
+
+        #[test]
+        fn test_invalid_target() {
+            let program = vec![add_instr!(Push, 1), make_block!(Jump(2))];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert!(matches!(
+                machine.run(),
+                Err(MachineError::InvalidJumpTarget(2))
+            ));
+            assert_eq!(machine.cells, vec![1]);
+        }
+    }
+
+    mod memory {
+        use super::*;
+
+        #[test]
+        fn test_load_and_store() {
+            let program = vec![
+                add_instr!(Push, 42),
+                add_instr!(Push, 5),
+                Store(0, 1),
+                make_block!(add_instr!(Push, 7), add_instr!(Push, 0), Store(2, 3)),
+                Load(1),
+                add_instr!(Push, 0),
+                Load(4),
+            ];
+            let mut machine = Machine::new();
+            machine.set_memory_size(8);
+            machine.load_program(&program);
+            machine.run().unwrap();
+            assert_eq!(machine.cells(), [42, 5, 0, 42, 0, 7]);
+            assert_eq!(machine.memory(), [7, 0, 0, 0, 0, 42, 0, 0]);
+
+            for address in [8, -1, i64::MIN] {
+                let program = vec![add_instr!(Push, address), Store(0, 0)];
+                let mut machine = Machine::new();
+                machine.set_memory_size(8);
+                machine.load_program(&program);
+                assert!(matches!(machine.run(), Err(MachineError::InvalidAddress)));
+
+                let program = vec![add_instr!(Push, address), Load(0)];
+                let mut machine = Machine::new();
+                machine.set_memory_size(8);
+                machine.load_program(&program);
+                assert!(matches!(machine.run(), Err(MachineError::InvalidAddress)));
+            }
         }
-    }
 
-    fn rebase(&mut self) -> Result<(), MachineError> {
-        if self.base > self.cells.len() {
-            return Err(MachineError::RebaseError);
-        }
+        #[test]
+        fn test_estimate_grows_with_cells() {
+            let mut machine = Machine::new();
+            let empty = machine.estimate_memory();
 
-        self.cells = self.cells.split_off(self.base);
+            let program = vec![PushRange {
+                start: 0,
+                count: 10_000,
+            }];
+            machine.load_program(&program);
+            machine.run().unwrap();
+            let full = machine.estimate_memory();
+            assert!(full >= empty + 10_000 * std::mem::size_of::<i64>());
 
-        Ok(())
+            let program = vec![
+                add_instr!(fun FunctionDefine, String::from("a_function_with_a_long_name")),
+                make_block!(add_instr!(Push, 1)),
+            ];
+            machine.load_program(&program);
+            machine.reset_pc();
+            machine.run().unwrap();
+            assert!(machine.estimate_memory() > full);
+        }
     }
 
-    pub fn run(&mut self) -> Result<Option<&i64>, MachineError> {
-        let program = self.get_program()?;
-
-        while let Some(instr) = program.get(self.pc) {
-            match &instr {
-                Instruction::Block(_) => eprintln!("Executing block..."),
-                Instruction::AluFunction(FunctionOp::FunctionDefine, name) => {
-                    eprintln!(
-                        "Defining function {:?} at pc: {} | cells: {:?}",
-                        name, self.pc, self.cells
-                    );
-                }
-                Instruction::AluFunction(FunctionOp::FunctionCall, name) => {
-                    eprintln!(
-                        "Calling function {:?} at pc: {} | cells: {:?}",
-                        name, self.pc, self.cells
-                    );
-                }
-                _ => {
-                    eprintln!(
-                        "Executing instruction {:?} at pc: {} | cells: {:?}",
-                        instr, self.pc, self.cells
-                    );
-                }
-            }
-            instr.eval(self).map_err(|e| {
-                eprintln!(
-                    "Error executing instruction {:?}. Error: {:?} | cells: {:?}",
-                    instr, e, self.cells
-                );
-                e
-            })?;
+    mod heatmap {
+        use super::*;
 
-            match &instr {
-                Instruction::Block(_) => eprintln!("\tFinished executing block..."),
-                Instruction::AluFunction(FunctionOp::FunctionDefine, _) => {}
-                Instruction::AluFunction(FunctionOp::FunctionCall, name) => {
-                    eprintln!("\tExiting function {:?}", name);
-                }
-                _ => {
-                    eprintln!(
-                        "\tFinished inst {:?} at pc: {} | cells: {:?}",
-                        instr, self.pc, self.cells
-                    );
-                }
-            }
+        #[test]
+        fn test_square_add_42_heatmap() {
+            let program = vec![
+                add_instr!(Push, 5), // Argument
+                make_block!(
+                    add_instr!(R ReadReverse, 0), // Copy of the argument, r1
+                    add_instr!(Rebase),
+                    add_instr!(Mul, 0, 0),
+                    add_instr!(Push, 42),
+                    add_instr!(Mul, 0, 2),
+                    add_instr!(Add, 1, 3)
+                ),
+            ];
 
-            self.pc += 1;
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let (last, counts) = machine.run_with_heatmap().unwrap();
+            assert_eq!(last, Some(235));
+            assert_eq!(counts[0], 1); // The argument is only read once, to copy it
+            assert_eq!(counts[1], 3); // The copy feeds both multiplications
+            assert_eq!(counts, vec![1, 3, 1, 1, 1]);
         }
 
-        Ok(self.cells.last())
-    }
-}
-
-impl From<Vec<i64>> for Machine<'_> {
-    fn from(value: Vec<i64>) -> Self {
-        let mut machine = Machine::new();
-        machine.cells = value;
-        machine
-    }
-}
+        #[test]
+        fn test_heatmap_covers_function_calls() {
+            let program = vec![
+                add_instr!(fun FunctionDefine, String::from("double")),
+                make_block!(add_instr!(Add, 0, 0)),
+                add_instr!(Push, 4),
+                add_instr!(fun FunctionCall, String::from("double")),
+                add_instr!(fun FunctionCall, String::from("double")),
+            ];
 
-pub mod macros {
-    #[macro_export]
-    macro_rules! add_instr {
-        ($op:ident) => {
-            AluNullary(NullaryOp::$op)
-        };
-        ($op:ident, $a:expr) => {
-            // for immediate
-            AluUnaryImm(UnaryOpImm::$op, $a)
-        };
-        (R $op:ident, $a:expr) => {
-            // for register
-            AluUnaryCell(UnaryOpCell::$op, $a)
-        };
-        ($op:ident, $a:expr, $b:expr) => {
-            AluBinary(BinaryOp::$op, $a, $b)
-        };
-        (fun $op:ident, $name:expr) => {
-            AluFunction(FunctionOp::$op, $name)
-        };
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            machine.reset_pc();
+            let (last, counts) = machine.run_with_heatmap().unwrap();
+            assert_eq!(last, Some(8));
+            assert_eq!(counts, vec![4, 0, 0]);
+        }
     }
 
-    #[macro_export]
-    macro_rules! make_block {
-        ($($instr:expr),+) => { // Variadic arguments, at least one
-            Block(vec![ $( $instr ),* ])
-        };
-    }
+    mod diff {
+        use super::*;
 
-    pub use add_instr;
-    pub use make_block;
-}
+        #[test]
+        fn test_snapshot_and_restore() {
+            let setup = vec![
+                add_instr!(fun FunctionDefine, String::from("one")),
+                make_block!(add_instr!(Push, 1)),
+                add_instr!(Push, 1),
+                add_instr!(Push, 2),
+                make_block!(add_instr!(Rebase), add_instr!(Push, 3)),
+            ];
+            let trial = vec![
+                add_instr!(Push, 4),
+                add_instr!(fun FunctionDefine, String::from("two")),
+                make_block!(add_instr!(Push, 2)),
+                add_instr!(Push, 5),
+            ];
 
-#[cfg(test)]
-pub mod tests {
-    use super::*;
-    use Instruction::*;
+            let mut machine = Machine::new();
+            machine.load_program(&setup);
+            machine.run().unwrap();
+            let snapshot = machine.snapshot();
+            let cells = machine.cells.clone();
 
-    macro_rules! test_binop {
-        ($name:ident, $a:expr, $b:expr, $op:ident => $expected:expr) => {
-            #[test]
-            fn $name() {
-                let program = vec![
-                    add_instr!(Push, $a),
-                    add_instr!(Push, $b),
-                    add_instr!($op, 0, 1),
-                ];
-                let mut machine = Machine::new();
-                machine.load_program(&program);
-                machine.reset_pc();
-                let last = machine.run().unwrap();
-                assert_eq!(last, Some(&$expected));
-            }
-        };
-    }
+            machine.load_program(&trial);
+            machine.reset_pc();
+            machine.run().unwrap();
+            assert_eq!(machine.function_names(), vec!["one", "two"]);
+            assert_eq!(machine.cells, vec![1, 2, 3, 4, 5]);
 
-    mod basic {
-        use super::*;
+            machine.restore(snapshot);
+            assert_eq!(machine.get_program().unwrap(), &setup[..]);
+            assert_eq!(machine.pc(), setup.len());
+            assert_eq!(machine.cells, cells);
+            assert_eq!(machine.cells, vec![1, 2, 3]);
+            assert_eq!(machine.base, 0);
+            assert!(machine.base_stack.is_empty());
+            assert_eq!(machine.function_names(), vec!["one"]);
+            assert!(machine.diff(&Machine::from(cells)).is_empty());
+        }
 
         #[test]
-        fn test_push_pop() {
+        fn test_restore_mid_run() {
             let program = vec![
-                add_instr!(Push, 1),
-                add_instr!(Push, 2),
                 add_instr!(Push, 3),
                 add_instr!(Push, 4),
+                add_instr!(Mul, 0, 1),
                 add_instr!(Push, 5),
             ];
+
             let mut machine = Machine::new();
             machine.load_program(&program);
-            machine.reset_pc();
-            let _ = machine.run().unwrap();
-            assert_eq!(machine.cells[0], 1);
-            assert_eq!(machine.cells[1], 2);
-            assert_eq!(machine.cells[2], 3);
-            assert_eq!(machine.cells[3], 4);
-            assert_eq!(machine.cells[4], 5);
-            assert!(matches!(machine.cells.get(5), None)); // Ensure no extra cells exist
+            machine.step().unwrap();
+            machine.step().unwrap();
+            let checkpoint = machine.snapshot();
 
-            let prog = vec![add_instr!(Pop, -1)];
-            machine.load_program(&prog);
-            machine.reset_pc();
-            let result = machine.run();
-            assert!(matches!(result, Err(MachineError::InvalidCell)));
+            assert_eq!(machine.run().unwrap(), Some(&5));
+            machine.cells.clear();
+            machine.restore(checkpoint);
+            assert_eq!(machine.pc(), 2);
+            assert_eq!(machine.run().unwrap(), Some(&5));
+            assert_eq!(machine.cells, vec![3, 4, 12, 5]);
+        }
 
-            let prog = vec![add_instr!(Pop, 1)];
-            machine.load_program(&prog);
-            machine.reset_pc();
-            let val = machine.run().unwrap();
-            assert_eq!(val, Some(&4));
+        #[test]
+        fn test_diff_highlights_changed_cells() {
+            let left_program = vec![
+                add_instr!(Push, 10),
+                add_instr!(Push, 20),
+                add_instr!(Add, 0, 1),
+            ];
+            let right_program = vec![
+                add_instr!(Push, 10),
+                add_instr!(Push, 25),
+                add_instr!(Add, 0, 1),
+                add_instr!(Push, 1),
+            ];
 
-            let prog = vec![add_instr!(Pop, 2)];
-            machine.load_program(&prog);
-            machine.reset_pc();
-            let val = machine.run().unwrap();
-            assert_eq!(val, Some(&2));
+            let mut left = Machine::new();
+            left.load_program(&left_program);
+            left.run().unwrap();
+            let mut right = Machine::new();
+            right.load_program(&right_program);
+            right.run().unwrap();
 
-            let prog = vec![add_instr!(Pop, 2)];
-            machine.load_program(&prog);
-            machine.reset_pc();
-            let val = machine.run().unwrap();
-            assert_eq!(val, None);
+            let diff = left.diff(&right);
+            assert_eq!(
+                diff.cells,
+                vec![
+                    CellDiff {
+                        index: 1,
+                        left: Some(20),
+                        right: Some(25)
+                    },
+                    CellDiff {
+                        index: 2,
+                        left: Some(30),
+                        right: Some(35)
+                    },
+                    CellDiff {
+                        index: 3,
+                        left: None,
+                        right: Some(1)
+                    },
+                ]
+            );
+            assert_eq!(diff.base, None);
+            assert_eq!(diff.base_stack, None);
+            assert_eq!(
+                diff.to_string(),
+                "cell 1: 20 != 25\ncell 2: 30 != 35\ncell 3: <none> != 1\n"
+            );
 
-            let prog = vec![add_instr!(Pop, 1)];
-            machine.load_program(&prog);
-            machine.reset_pc();
-            let result = machine.run();
-            assert!(matches!(result, Err(MachineError::StackUnderflow)));
+            assert!(left.diff(&left.clone()).is_empty());
         }
+    }
+
+    mod functions {
+        use super::*;
 
         #[test]
-        fn test_read() {
+        fn test_simple_function() {
             let program = vec![
-                add_instr!(Push, 100),
-                add_instr!(Push, 200),
-                add_instr!(R Read, 0),
+                add_instr!(fun FunctionDefine, String::from("square")),
+                make_block!(
+                    add_instr!(R ReadReverse, 0),
+                    add_instr!(Rebase),
+                    add_instr!(Mul, 0, 0) // Multiply input by 2
+                ),
+                add_instr!(Push, 3),
+                add_instr!(fun FunctionCall, String::from("square")),
             ];
+
             let mut machine = Machine::new();
             machine.load_program(&program);
             machine.reset_pc();
             let last = machine.run().unwrap();
-            assert_eq!(last, Some(&100));
-            assert_eq!(machine.cells[0], 100);
-            assert_eq!(machine.cells[1], 200);
+            assert_eq!(last, Some(&9));
         }
 
         #[test]
-        fn test_read_reverse() {
+        fn test_callee_sees_caller_functions() {
             let program = vec![
-                add_instr!(Push, 10),
-                add_instr!(Push, 20),
-                add_instr!(Push, 30),
-                add_instr!(R ReadReverse, 1), // Should read 20
+                add_instr!(fun FunctionDefine, String::from("push7")),
+                make_block!(add_instr!(Push, 7)),
             ];
             let mut machine = Machine::new();
             machine.load_program(&program);
             machine.reset_pc();
+            let _ = machine.run().unwrap();
+
+            // Defined by an earlier program, called from inside a nested block.
+            let call = vec![make_block!(make_block!(add_instr!(
+                fun FunctionCall,
+                String::from("push7")
+            )))];
+            machine.load_program(&call);
+            machine.reset_pc();
             let last = machine.run().unwrap();
-            assert_eq!(last, Some(&20));
-            assert_eq!(machine.cells[0], 10);
-            assert_eq!(machine.cells[1], 20);
-            assert_eq!(machine.cells[2], 30);
+            assert_eq!(last, Some(&7));
         }
 
-        test_binop!(test_add, 10, 20, Add => 30);
-        test_binop!(test_add_neg, 10, -30, Add => -20);
-        test_binop!(test_mul, 10, 20, Mul => 200);
-        test_binop!(test_div, 20, 5, Div => 4);
-
         #[test]
-        fn test_div_bad() {
-            let program = vec![
-                add_instr!(Push, 10),
-                add_instr!(Push, 0),
-                add_instr!(Div, 0, 1),
+        fn test_sequential_definitions() {
+            let mut program = vec![
+                add_instr!(fun FunctionDefine, String::from("square")),
+                add_instr!(fun FunctionDefine, String::from("cube")),
+                make_block!(add_instr!(Mul, 0, 0)),
+                make_block!(add_instr!(Mul, 0, 0), add_instr!(Mul, 0, 1)),
+                add_instr!(fun FunctionCall, String::from("square")),
+                add_instr!(fun FunctionCall, String::from("cube")),
             ];
-            let mut machine = Machine::new();
+
+            let mut machine = Machine::from(vec![3]);
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run();
-            assert!(matches!(last, Err(MachineError::DivisionByZero)));
-        }
+            machine.run().unwrap();
+            assert_eq!(machine.cells, vec![3, 9, 27]);
 
-        test_binop!(test_and, 0b1100, 0b1010, And => 0b1000);
-        test_binop!(test_or, 0b1100, 0b1010, Or => 0b1110);
-        test_binop!(test_xor, 0b1100, 0b1010, Xor => 0b0110);
+            program.push(add_instr!(fun FunctionCall, String::from("brr")));
+            let mut machine = Machine::from(vec![3]);
+            machine.load_program(&program);
+            assert!(matches!(
+                machine.run(),
+                Err(MachineError::FunctionUndefined)
+            ));
+            assert_eq!(machine.cells, vec![3, 9, 27]);
+
+            // Every name needs a block of its own.
+            for program in [
+                vec![
+                    add_instr!(fun FunctionDefine, String::from("square")),
+                    add_instr!(fun FunctionDefine, String::from("cube")),
+                    make_block!(add_instr!(Mul, 0, 0)),
+                ],
+                vec![
+                    add_instr!(fun FunctionDefine, String::from("push2")),
+                    add_instr!(Push, 2),
+                ],
+            ] {
+                let mut machine = Machine::new();
+                machine.load_program(&program);
+                assert!(matches!(
+                    machine.run(),
+                    Err(MachineError::InstructionError(_))
+                ));
+            }
+        }
 
         #[test]
-        fn test_not() {
-            let program = vec![add_instr!(Push, 0b1100), add_instr!(R Not, 0)];
+        fn test_nested_functions() {
+            let mut program = vec![
+                add_instr!(fun FunctionDefine, String::from("outer")),
+                make_block!(
+                    add_instr!(fun FunctionDefine, String::from("inner")),
+                    make_block!(add_instr!(Push, 42)),
+                    add_instr!(fun FunctionCall, String::from("inner"))
+                ),
+                add_instr!(fun FunctionCall, String::from("outer")),
+            ];
+
+            // Outer function call should work
             let mut machine = Machine::new();
             machine.load_program(&program);
             machine.reset_pc();
             let last = machine.run().unwrap();
-            assert_eq!(last, Some(&(!0b1100)));
-        }
-
-        test_binop!(test_slt, 10, 20, SetLessThan => 1);
-        test_binop!(test_sgt, 20, 10, SetGreaterThan => 1);
-        test_binop!(test_seq, 10, 10, SetEqual => 1);
-        test_binop!(test_sne, 10, 20, SetNotEqual => 1);
-        test_binop!(test_sle, 10, 10, SetLessThanOrEqual => 1);
-        test_binop!(test_sge, 20, 10, SetGreaterThanOrEqual => 1);
-
-        test_binop!(test_sll, 0b0001, 2, ShiftLeftLogical => 0b0100);
-        test_binop!(test_srl, 0b0100, 2, ShiftRightLogical => 0b0001);
-        test_binop!(test_sra, -8, 2, ShiftRightArithmetic => -2);
+            assert_eq!(last, Some(&42));
 
-        #[test]
-        fn nop() {
-            let program = vec![add_instr!(Nop)];
+            // Inner function call should fail
             let mut machine = Machine::new();
+            program.push(add_instr!(fun FunctionCall, String::from("inner")));
             machine.load_program(&program);
             machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, None);
+            let last = machine.run();
+            assert!(matches!(last, Err(MachineError::FunctionUndefined)));
         }
 
         #[test]
-        fn math_with_read() {
+        fn test_host_function() {
             let program = vec![
-                add_instr!(Push, 50),
-                add_instr!(Push, 70),
-                add_instr!(Push, 10),
-                add_instr!(Add, 0, 1), // 50 + 70 = 120
-                add_instr!(Div, 3, 2), // 120 / 10 = 12
+                add_instr!(Push, 21),
+                make_block!(HostCall {
+                    name: String::from("double"),
+                    args: vec![0],
+                }),
+                HostCall {
+                    name: String::from("double"),
+                    args: vec![1],
+                },
             ];
+
             let mut machine = Machine::new();
+            machine.register_host_fn("double", Box::new(|args| Ok(args[0] * 2)));
             machine.load_program(&program);
             machine.reset_pc();
             let last = machine.run().unwrap();
-            assert_eq!(last, Some(&12));
+            assert_eq!(last, Some(&84));
+            assert_eq!(machine.cells, vec![21, 42, 84]);
         }
-    }
-
-    mod blocks {
-        use super::*;
 
         #[test]
-        fn test_no_arg() {
+        fn test_unary_host_function() {
             let program = vec![
-                add_instr!(Push, 10),
-                add_instr!(Push, 20),
-                add_instr!(Add, 0, 1),
-                make_block!(
-                    add_instr!(Push, 2),   // This push should be deleted after block ends
-                    add_instr!(Mul, 2, 3) // This is the last push, the result, and it should remain after block ends
-                ),
-                add_instr!(Add, 2, 3),
+                add_instr!(Push, -3),
+                HostUnary {
+                    name: String::from("cube"),
+                    arg: 0,
+                },
+                HostUnary {
+                    name: String::from("abs"),
+                    arg: 1,
+                },
+                HostUnary {
+                    name: String::from("missing"),
+                    arg: 0,
+                },
             ];
 
             let mut machine = Machine::new();
+            machine.register_unary("cube", |x| x * x * x);
+            machine.register_unary("abs", i64::abs);
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&90)); // (10 + 20) + ((10 + 20) * 2) = 90
-
-            assert_eq!(machine.cells[0], 10);
-            assert_eq!(machine.cells[1], 20);
-            assert_eq!(machine.cells[2], 30); // Result of first addition
-            assert_eq!(machine.cells[3], 60); // Result of multiplication inside block
-            assert_eq!(machine.cells[4], 90); // Final result
-            assert!(matches!(machine.cells.get(5), None)); // Ensure no extra cells exist
-            assert_eq!(machine.cells.len(), 5);
+            let result = machine.run();
+            assert!(matches!(result, Err(MachineError::FunctionUndefined)));
+            assert_eq!(machine.cells, vec![-3, -27, 27]);
         }
 
         #[test]
-        fn test_nested() {
+        fn test_tail_recursion() {
+            // Counts cell 0 up to 100_000, tail-calling itself after each step.
             let program = vec![
-                add_instr!(Push, 3),
+                add_instr!(fun FunctionDefine, String::from("count")),
                 make_block!(
-                    add_instr!(Push, 4),
-                    make_block!(
-                        add_instr!(Push, 5),
-                        add_instr!(Mul, 1, 2) // 4 * 5 = 20
-                    ),
-                    add_instr!(Add, 0, 2) // 3 + 20 = 23
+                    add_instr!(Push, 1),
+                    SumTop(2),
+                    add_instr!(Push, 100_000),
+                    add_instr!(SetLessThan, 0, 1),
+                    ReverseTop(2),
+                    add_instr!(Pop, 1),
+                    add_instr!(Cond),
+                    add_instr!(fun Tail, String::from("count"))
                 ),
+                add_instr!(Push, 0),
+                add_instr!(fun FunctionCall, String::from("count")),
             ];
+
+            let mut steps = Machine::new().into_iter(&program);
+            let mut deepest = 0;
+            let mut last = None;
+            while let Some(result) = steps.next() {
+                last = result.unwrap();
+                deepest = deepest.max(steps.machine().frames.len());
+            }
+            assert_eq!(last, Some(100_000));
+            assert_eq!(deepest, 2); // The function's frame and its block's
+
+            let program = vec![add_instr!(fun Tail, String::from("missing"))];
             let mut machine = Machine::new();
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&23));
-            assert_eq!(machine.cells[0], 3);
-            assert_eq!(machine.cells[1], 23);
+            assert!(matches!(
+                machine.run(),
+                Err(MachineError::FunctionUndefined)
+            ));
+        }
+
+        // Counts its argument down to 0, leaving two scratch cells behind on
+        // every call.
+        fn countdown(from: i64) -> Vec<Instruction> {
+            vec![
+                FunctionDefineArity(String::from("down"), 1),
+                make_block!(
+                    add_instr!(Push, 1),
+                    add_instr!(Sub, 0, 1),
+                    add_instr!(R ToBool, 2),
+                    add_instr!(Cond),
+                    add_instr!(fun Tail, String::from("down"))
+                ),
+                add_instr!(Push, from),
+                add_instr!(fun FunctionCall, String::from("down")),
+            ]
         }
 
         #[test]
-        fn test_square_fn() {
-            let square_block = make_block!(
-                add_instr!(R ReadReverse, 0),
-                add_instr!(R ReadReverse, 0),
-                add_instr!(Rebase),
-                add_instr!(Mul, 0, 1) // Multiply input by 2
-            );
+        fn test_tail_call_drops_dead_cells() {
+            let program = countdown(10_000);
+            let mut steps = Machine::new().into_iter(&program);
+            let mut most = 0;
+            let mut last = None;
+            while let Some(result) = steps.next() {
+                last = result.unwrap();
+                most = most.max(steps.machine().cells.len());
+            }
+            assert_eq!(last, Some(0));
+            assert_eq!(most, 5); // The argument, the body's three cells and the condition
+            assert_eq!(steps.machine().cells, vec![10_000, 0]);
+        }
+
+        #[test]
+        fn test_deep_tail_recursion_memory() {
+            // The most memory held at any point of the run.
+            let peak = |from: i64| {
+                let program = countdown(from);
+                let mut steps = Machine::new().into_iter(&program);
+                let mut peak = 0;
+                while let Some(result) = steps.next() {
+                    result.unwrap();
+                    peak = peak.max(steps.machine().estimate_memory());
+                }
+                peak
+            };
+            assert_eq!(peak(100_000), peak(10));
+        }
+
+        #[test]
+        fn test_tracer() {
+            struct Counter(Rc<RefCell<Vec<usize>>>);
+
+            impl Tracer for Counter {
+                fn on_instruction(&mut self, _: &Instruction, cells: &[i64]) {
+                    self.0.borrow_mut().push(cells.len());
+                }
+            }
 
             let program = vec![
-                add_instr!(Push, 2),
-                square_block.clone(),
-                square_block.clone(),
+                add_instr!(fun FunctionDefine, String::from("double")),
+                make_block!(add_instr!(R ReadReverse, 0), add_instr!(Add, 1, 1)),
+                add_instr!(Push, 4),
+                make_block!(
+                    add_instr!(Push, 5),
+                    make_block!(add_instr!(fun FunctionCall, String::from("double")))
+                ),
             ];
 
+            let seen = Rc::new(RefCell::new(Vec::new()));
             let mut machine = Machine::new();
+            machine.set_tracer(Box::new(Counter(Rc::clone(&seen))));
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&16));
+            let (result, executed) = machine.run_counted();
+            assert_eq!(result.unwrap(), Some(10));
+            assert_eq!(executed, 9);
+            // Cell counts as each instruction started
+            assert_eq!(*seen.borrow(), vec![0, 0, 1, 1, 2, 2, 2, 2, 3]);
         }
 
         #[test]
-        fn test_with_pop() {
-            let block = make_block!(
-                add_instr!(Pop, 2) // Pop the 20, leaving only 30
-            );
+        fn test_tracer_events() {
+            struct Recorder(Rc<RefCell<Vec<String>>>);
+
+            impl Tracer for Recorder {
+                fn on_push(&mut self, value: i64) {
+                    self.0.borrow_mut().push(format!("push {}", value));
+                }
+
+                fn on_pop(&mut self, value: i64) {
+                    self.0.borrow_mut().push(format!("pop {}", value));
+                }
+
+                fn on_call(&mut self, event: &CallEvent) {
+                    let event = match event {
+                        CallEvent::Enter { name, .. } => format!("enter {}", name),
+                        CallEvent::Exit { name, .. } => format!("exit {}", name),
+                    };
+                    self.0.borrow_mut().push(event);
+                }
+
+                fn on_error(&mut self, error: &MachineError) {
+                    self.0.borrow_mut().push(error.to_string());
+                }
+            }
 
             let program = vec![
-                add_instr!(Push, 3),
-                add_instr!(Push, 5),
-                block,
-                add_instr!(Mul, 0, 1), // 3 * 5 = 15
+                add_instr!(fun FunctionDefine, String::from("inc")),
+                make_block!(add_instr!(Push, 1), add_instr!(Add, 0, 1)),
+                add_instr!(Push, 4),
+                add_instr!(fun FunctionCall, String::from("inc")),
+                add_instr!(Pop, 1),
+                Succeeds(vec![add_instr!(Pop, 2)]),
+                add_instr!(DupWindow),
             ];
 
+            let events = Rc::new(RefCell::new(Vec::new()));
             let mut machine = Machine::new();
+            machine.set_tracer(Box::new(Recorder(Rc::clone(&events))));
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&15));
+            assert_eq!(machine.run().unwrap(), Some(&0));
+            assert_eq!(
+                *events.borrow(),
+                vec![
+                    "push 4",
+                    "enter inc",
+                    "push 1",
+                    "push 5",
+                    "push 5", // The block's result
+                    "exit inc",
+                    "push 5",
+                    "pop 5",
+                    "pop 4",
+                    "stack underflow", // Caught by `Succeeds`
+                    "push 0",
+                    "push 4", // DupWindow copies the whole window
+                    "push 0",
+                ]
+            );
         }
 
         #[test]
-        fn test_nested_rebase_1() {
+        fn test_call_hook() {
             let program = vec![
-                add_instr!(Push, 2),
+                add_instr!(fun FunctionDefine, String::from("square")),
+                make_block!(add_instr!(R ReadReverse, 0), add_instr!(Mul, 1, 1)),
+                add_instr!(fun FunctionDefine, String::from("main")),
                 make_block!(
                     add_instr!(Push, 3),
-                    add_instr!(Rebase),
-                    make_block!(
-                        add_instr!(Push, 4),
-                        add_instr!(Mul, 0, 1) // 3 * 4 = 12
-                    ),
-                    add_instr!(Add, 0, 1) // 3 + 12 = 14
+                    add_instr!(fun FunctionCall, String::from("square"))
                 ),
+                add_instr!(fun FunctionCall, String::from("main")),
             ];
+
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let recorded = Rc::clone(&events);
             let mut machine = Machine::new();
+            machine.set_call_hook(Box::new(move |event| recorded.borrow_mut().push(event)));
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&15));
-            assert_eq!(machine.cells[0], 2);
-            assert_eq!(machine.cells[1], 15);
+            assert_eq!(machine.run().unwrap(), Some(&9));
+
+            let name = String::from;
+            assert_eq!(
+                *events.borrow(),
+                vec![
+                    CallEvent::Enter {
+                        name: name("main"),
+                        depth: 0
+                    },
+                    CallEvent::Enter {
+                        name: name("square"),
+                        depth: 1
+                    },
+                    CallEvent::Exit {
+                        name: name("square"),
+                        depth: 1,
+                        result: Some(9)
+                    },
+                    CallEvent::Exit {
+                        name: name("main"),
+                        depth: 0,
+                        result: Some(9)
+                    },
+                ]
+            );
         }
 
         #[test]
-        fn test_nested_rebase_2() {
+        fn test_host_function_errors() {
             let program = vec![
-                add_instr!(Push, 2),
-                make_block!(
-                    add_instr!(Push, 3),
-                    add_instr!(Rebase),
-                    make_block!(
-                        add_instr!(R ReadReverse, 0),
-                        add_instr!(Push, 4),
-                        add_instr!(Rebase),
-                        add_instr!(Mul, 0, 1) // 3 * 4 = 12
-                    ),
-                    add_instr!(Add, 0, 1) // 3 + 12 = 14
-                ),
+                add_instr!(Push, 1),
+                HostCall {
+                    name: String::from("missing"),
+                    args: vec![0],
+                },
             ];
             let mut machine = Machine::new();
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&15));
-            assert_eq!(machine.cells[0], 2);
-            assert_eq!(machine.cells[1], 15);
+            let last = machine.run();
+            assert!(matches!(last, Err(MachineError::FunctionUndefined)));
+
+            let program = vec![HostCall {
+                name: String::from("fail"),
+                args: vec![],
+            }];
+            let mut machine = Machine::new();
+            machine.register_host_fn(
+                "fail",
+                Box::new(|_| Err(MachineError::OtherError(String::from("host failure")))),
+            );
+            machine.load_program(&program);
+            let last = machine.run();
+            assert!(matches!(last, Err(MachineError::OtherError(_))));
         }
 
         #[test]
-        fn test_square_add_42() {
+        fn test_return() {
             let program = vec![
-                add_instr!(Push, 5), // Argument
+                add_instr!(fun FunctionDefine, String::from("first")),
+                add_instr!(fun FunctionDefine, String::from("nothing")),
                 make_block!(
-                    add_instr!(R ReadReverse, 0), // Read x . . . r0 <- x
-                    add_instr!(Rebase),
-                    add_instr!(Mul, 0, 0), // x ^ 2 . . . r1 <- r0 ^ 2
-                    add_instr!(Push, 42),  // r2 <- 42
-                    add_instr!(Mul, 0, 2), // x * 42 . . . r3 <- r0 * r2
-                    add_instr!(Add, 1, 3)  // x^2 + 42x . . . r4 <- r1 + r3
+                    add_instr!(Push, 1),
+                    add_instr!(Push, 2),
+                    make_block!(ReturnCell(1)),
+                    add_instr!(Push, 99)
                 ),
+                make_block!(add_instr!(Push, 5), Return, add_instr!(Push, 99)),
+                add_instr!(Push, 10),
+                add_instr!(fun FunctionCall, String::from("first")),
+                add_instr!(fun FunctionCall, String::from("nothing")),
             ];
-
             let mut machine = Machine::new();
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&235));
-            assert_eq!(machine.cells[0], 5);
-            assert_eq!(machine.cells[1], 235);
-            assert_eq!(machine.cells.len(), 2);
-        }
-    }
-
-    mod functions {
-        use super::*;
+            assert_eq!(machine.run().unwrap(), Some(&1));
+            assert_eq!(machine.cells, vec![10, 1]);
 
-        #[test]
-        fn test_simple_function() {
+            // Outside functions, returning ends the program.
             let program = vec![
-                add_instr!(fun FunctionDefine, String::from("square")),
-                make_block!(
-                    add_instr!(R ReadReverse, 0),
-                    add_instr!(Rebase),
-                    add_instr!(Mul, 0, 0) // Multiply input by 2
-                ),
+                add_instr!(Push, 1),
+                make_block!(add_instr!(Push, 2), ReturnCell(1)),
                 add_instr!(Push, 3),
-                add_instr!(fun FunctionCall, String::from("square")),
             ];
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert_eq!(machine.run().unwrap(), Some(&2));
+            assert_eq!(machine.cells, vec![1, 2]);
+            assert!(machine.is_finished());
 
+            let program = vec![add_instr!(Push, 1), Return, add_instr!(Push, 2)];
             let mut machine = Machine::new();
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&9));
+            assert_eq!(machine.run().unwrap(), Some(&1));
         }
 
         #[test]
-        fn test_sequential_definitions() {
-            let program = vec![
-                add_instr!(fun FunctionDefine, String::from("push2_1")),
-                add_instr!(fun FunctionDefine, String::from("push2_2")),
-                add_instr!(fun FunctionDefine, String::from("push2_3")),
-                add_instr!(Push, 2),
-                add_instr!(fun FunctionCall, String::from("push2_1")),
-                add_instr!(fun FunctionCall, String::from("push2_2")),
+        fn test_function_arity() {
+            let definition = vec![
+                FunctionDefineArity(String::from("add"), 2),
+                // Only the two arguments are visible, as r0 and r1
+                make_block!(add_instr!(Add, 0, 1)),
             ];
 
+            let mut program = definition.clone();
+            program.extend([
+                add_instr!(Push, 1),
+                add_instr!(Push, 10),
+                add_instr!(Push, 20),
+                add_instr!(fun FunctionCall, String::from("add")),
+            ]);
             let mut machine = Machine::new();
             machine.load_program(&program);
-            machine.reset_pc();
-            let _ = machine.run().unwrap();
-
-            assert_eq!(machine.cells[0], 2);
-            assert_eq!(machine.cells[1], 2);
-            assert!(matches!(machine.cells.get(2), None));
-        }
-
-        #[test]
-        fn test_nested_functions() {
-            let mut program = vec![
-                add_instr!(fun FunctionDefine, String::from("outer")),
-                make_block!(
-                    add_instr!(fun FunctionDefine, String::from("inner")),
-                    make_block!(add_instr!(Push, 42)),
-                    add_instr!(fun FunctionCall, String::from("inner"))
-                ),
-                add_instr!(fun FunctionCall, String::from("outer")),
-            ];
+            assert_eq!(machine.run().unwrap(), Some(&30));
+            assert_eq!(machine.cells, vec![1, 10, 20, 30]);
 
-            // Outer function call should work
+            let mut program = definition.clone();
+            program.extend([
+                add_instr!(Push, 1),
+                add_instr!(fun FunctionCall, String::from("add")),
+            ]);
             let mut machine = Machine::new();
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run().unwrap();
-            assert_eq!(last, Some(&42));
+            assert!(matches!(
+                machine.run(),
+                Err(MachineError::FunctionCallError)
+            ));
 
-            // Inner function call should fail
+            // Cells below the caller's base aren't arguments.
+            let mut program = definition;
+            program.extend([
+                add_instr!(Push, 1),
+                make_block!(
+                    add_instr!(Push, 2),
+                    add_instr!(fun FunctionCall, String::from("add"))
+                ),
+            ]);
             let mut machine = Machine::new();
-            program.push(add_instr!(fun FunctionCall, String::from("inner")));
             machine.load_program(&program);
-            machine.reset_pc();
-            let last = machine.run();
-            assert!(matches!(last, Err(MachineError::FunctionUndefined)));
+            assert!(matches!(
+                machine.run(),
+                Err(MachineError::FunctionCallError)
+            ));
         }
     }
 
@@ -950,6 +4689,75 @@ pub mod tests {
             assert_eq!(last, Some(&factorial(number)));
         }
 
+        #[test]
+        fn test_recursion_limit() {
+            let program = vec![
+                add_instr!(fun FunctionDefine, String::from("forever")),
+                make_block!(
+                    add_instr!(fun FunctionCall, String::from("forever")),
+                    add_instr!(Push, 1)
+                ),
+                add_instr!(Push, 7),
+                add_instr!(fun FunctionCall, String::from("forever")),
+            ];
+
+            let mut machine = Machine::new();
+            machine.load_program(&program);
+            assert!(matches!(
+                machine.run(),
+                Err(MachineError::RecursionLimitExceeded)
+            ));
+            assert!(machine.frames.is_empty());
+            assert_eq!(machine.cells, vec![7]);
+
+            // Blocks count toward the limit too.
+            let program = vec![make_block!(make_block!(make_block!(add_instr!(Push, 1))))];
+            let mut machine = Machine::new();
+            machine.set_max_call_depth(2);
+            machine.load_program(&program);
+            assert!(matches!(
+                machine.run(),
+                Err(MachineError::RecursionLimitExceeded)
+            ));
+            machine.set_max_call_depth(3);
+            assert_eq!(machine.run().unwrap(), Some(&1));
+        }
+
+        #[test]
+        fn test_deep_recursion() {
+            // sum(n) = n + sum(n - 1), recursing once per level. Every level
+            // nests three frames: the call, the function's block and the
+            // conditional block.
+            let number = 10_000;
+
+            let program = vec![
+                add_instr!(fun FunctionDefine, String::from("sum")),
+                make_block!(
+                    add_instr!(R ReadReverse, 0), // n
+                    add_instr!(Rebase),
+                    add_instr!(Push, 0),
+                    add_instr!(SetGreaterThan, 0, 1), // n > 0
+                    add_instr!(Cond),                 // if n <= 0, return 0
+                    make_block!(
+                        add_instr!(Push, -1),
+                        add_instr!(Add, 0, 2), // n - 1
+                        add_instr!(fun FunctionCall, String::from("sum")),
+                        add_instr!(Add, 0, 4) // n + sum(n - 1)
+                    )
+                ),
+                add_instr!(Push, number),
+                add_instr!(fun FunctionCall, String::from("sum")),
+            ];
+
+            let mut machine = Machine::new();
+            machine.set_max_call_depth(4 * 10_000);
+            machine.load_program(&program);
+            machine.reset_pc();
+            let last = machine.run().unwrap();
+            assert_eq!(last, Some(&(number * (number + 1) / 2)));
+            assert!(machine.frames.is_empty());
+        }
+
         #[test]
         fn test_fibonacci() {
             fn fib(n: i64) -> i64 {
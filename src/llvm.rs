@@ -0,0 +1,442 @@
+/*
+ * Export of programs as LLVM-IR-like text.
+ *
+ * Every pushed cell becomes an SSA temporary (`%v0`, `%v1`, ...), and every
+ * operation a line defining one, so `add r0, r1` turns into
+ * `%v2 = add i64 %v0, %v1`. Blocks become basic blocks whose last value is
+ * pushed back to the enclosing block. The output only imitates LLVM IR: it's
+ * meant for reading, and isn't guaranteed to be accepted by LLVM tools.
+ *
+ * Instructions without a direct counterpart (conditionals, assertions,
+ * function definitions) are kept as comments; values that can't be known
+ * statically (such as the result of a `Switch`) are `freeze i64 undef`.
+ */
+
+use crate::{
+    BinaryOp, Cell, FunctionOp, Instruction, NullaryOp, UnaryOpCell, UnaryOpImm, definition_count,
+};
+
+#[derive(Default)]
+struct Emitter {
+    lines: Vec<String>,
+    temporaries: usize,
+    blocks: usize,
+}
+
+// The SSA value of every cell, and where the current window starts.
+#[derive(Clone, Default)]
+struct Stack {
+    values: Vec<String>,
+    base: usize,
+}
+
+impl Stack {
+    fn get(&self, cell: Cell) -> String {
+        self.values
+            .get(usize::from(cell))
+            .cloned()
+            .unwrap_or_else(|| String::from("undef"))
+    }
+
+    fn truncate(&mut self, count: usize) {
+        self.values
+            .truncate(self.values.len().saturating_sub(count));
+    }
+}
+
+impl Emitter {
+    fn temporary(&mut self) -> String {
+        self.temporaries += 1;
+        format!("%v{}", self.temporaries - 1)
+    }
+
+    fn line(&mut self, line: String) {
+        self.lines.push(format!("  {}", line));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.lines.push(format!("{}:", label));
+    }
+
+    // Defines a new temporary as `expression` and pushes it.
+    fn define(&mut self, stack: &mut Stack, expression: String) {
+        let name = self.temporary();
+        self.line(format!("{} = {}", name, expression));
+        stack.values.push(name);
+    }
+
+    fn constant(&mut self, stack: &mut Stack, value: i64) {
+        self.define(stack, format!("add i64 0, {}", value));
+    }
+
+    // Points at the memory word `address`, in the `@memory` array.
+    fn word(&mut self, address: String) -> String {
+        let pointer = self.temporary();
+        self.line(format!(
+            "{} = getelementptr i64, ptr @memory, i64 {}",
+            pointer, address
+        ));
+        pointer
+    }
+
+    fn unknown(&mut self, stack: &mut Stack, reason: &str) {
+        self.define(stack, format!("freeze i64 undef ; {}", reason));
+    }
+
+    fn binary(&mut self, stack: &mut Stack, op: BinaryOp, a: String, b: String) {
+        use BinaryOp::*;
+
+        let instruction = match op {
+            Add => "add",
+            Mul => "mul",
+            Div => "sdiv",
+            Sub => "sub",
+            Mod => "srem",
+            And => "and",
+            Or => "or",
+            Xor => "xor",
+            ShiftLeftLogical => "shl",
+            ShiftRightLogical => "lshr",
+            ShiftRightArithmetic => "ashr",
+            SetEqual => "icmp eq",
+            SetNotEqual => "icmp ne",
+            SetLessThan => "icmp slt",
+            SetLessThanOrEqual => "icmp sle",
+            SetGreaterThan => "icmp sgt",
+            SetGreaterThanOrEqual => "icmp sge",
+            SetLessThanUnsigned => "icmp ult",
+            SetLessThanOrEqualUnsigned => "icmp ule",
+            SetGreaterThanUnsigned => "icmp ugt",
+            SetGreaterThanOrEqualUnsigned => "icmp uge",
+        };
+
+        if instruction.starts_with("icmp") {
+            let flag = self.temporary();
+            self.line(format!("{} = {} i64 {}, {}", flag, instruction, a, b));
+            self.define(stack, format!("zext i1 {} to i64", flag));
+        } else {
+            self.define(stack, format!("{} i64 {}, {}", instruction, a, b));
+        }
+    }
+
+    fn emit(&mut self, program: &[Instruction], stack: &mut Stack) {
+        use Instruction::*;
+
+        let mut index = 0;
+        while let Some(instruction) = program.get(index) {
+            index += 1;
+
+            match instruction {
+                AluNullary(NullaryOp::Nop) => {}
+                AluNullary(NullaryOp::Rebase) => {
+                    let base = stack.base.min(stack.values.len());
+                    stack.values.drain(..base);
+                    stack.base = 0;
+                }
+                AluNullary(NullaryOp::Cond) => {
+                    stack.truncate(1);
+                    self.line(String::from("; cond: the next instruction is conditional"));
+                }
+                AluNullary(NullaryOp::InstructionCount) => self.unknown(stack, "icount"),
+                AluNullary(NullaryOp::DupWindow) => {
+                    let base = stack.base.min(stack.values.len());
+                    let window = stack.values[base..].to_vec();
+                    stack.values.extend(window);
+                }
+                AluUnaryImm(UnaryOpImm::Push, value) => self.constant(stack, *value),
+                AluUnaryImm(UnaryOpImm::Pop, count) => {
+                    stack.truncate(usize::try_from(*count).unwrap_or(0));
+                }
+                AluUnaryCell(op, cell) => {
+                    let value = match op {
+                        UnaryOpCell::ReadReverse => {
+                            let len = stack.values.len();
+                            match len.checked_sub(1 + usize::from(*cell)) {
+                                Some(index) => stack.values[index].clone(),
+                                None => String::from("undef"),
+                            }
+                        }
+                        _ => stack.get(*cell),
+                    };
+                    match op {
+                        UnaryOpCell::Read | UnaryOpCell::ReadReverse => stack.values.push(value),
+                        UnaryOpCell::Not => self.define(stack, format!("xor i64 {}, -1", value)),
+                        UnaryOpCell::Neg => self.define(stack, format!("sub i64 0, {}", value)),
+                        UnaryOpCell::ToBool => {
+                            self.binary(stack, BinaryOp::SetNotEqual, value, String::from("0"))
+                        }
+                    }
+                }
+                AluBinary(op, a, b) => {
+                    let (a, b) = (stack.get(*a), stack.get(*b));
+                    self.binary(stack, *op, a, b);
+                }
+                AluFunction(FunctionOp::FunctionDefine, name) | FunctionDefineArity(name, _) => {
+                    self.line(format!("; fn @{}", name));
+                    // The rest of the run, then a body for each definition.
+                    let count = definition_count(program, index);
+                    while let Some((name, _)) = program.get(index).and_then(Instruction::definition)
+                    {
+                        self.line(format!("; fn @{}", name));
+                        index += 1;
+                    }
+                    index += count + 1;
+                }
+                AluFunction(FunctionOp::FunctionCall, name) => {
+                    self.define(stack, format!("call i64 @{}()", name));
+                }
+                AluFunction(FunctionOp::Tail, name) => {
+                    self.define(stack, format!("tail call i64 @{}()", name));
+                }
+                PushRange { start, count } => {
+                    for offset in 0..i64::from(*count) {
+                        self.constant(stack, start.wrapping_add(offset));
+                    }
+                }
+                DivMod { a, b } => {
+                    let (a, b) = (stack.get(*a), stack.get(*b));
+                    self.define(stack, format!("sdiv i64 {}, {}", a, b));
+                    self.define(stack, format!("srem i64 {}, {}", a, b));
+                }
+                Average { a, b } => {
+                    // Widened so that neither the difference nor the sum
+                    // can overflow.
+                    let wide_a = self.temporary();
+                    self.line(format!("{} = sext i64 {} to i128", wide_a, stack.get(*a)));
+                    let wide_b = self.temporary();
+                    self.line(format!("{} = sext i64 {} to i128", wide_b, stack.get(*b)));
+                    let difference = self.temporary();
+                    self.line(format!("{} = sub i128 {}, {}", difference, wide_b, wide_a));
+                    let half = self.temporary();
+                    self.line(format!("{} = sdiv i128 {}, 2", half, difference));
+                    let midpoint = self.temporary();
+                    self.line(format!("{} = add i128 {}, {}", midpoint, wide_a, half));
+                    self.define(stack, format!("trunc i128 {} to i64", midpoint));
+                }
+                Fma { a, x, b } => {
+                    let wide_a = self.temporary();
+                    self.line(format!("{} = sext i64 {} to i128", wide_a, stack.get(*a)));
+                    let wide_x = self.temporary();
+                    self.line(format!("{} = sext i64 {} to i128", wide_x, stack.get(*x)));
+                    let product = self.temporary();
+                    self.line(format!("{} = mul i128 {}, {}", product, wide_a, wide_x));
+                    let wide_b = self.temporary();
+                    self.line(format!("{} = sext i64 {} to i128", wide_b, stack.get(*b)));
+                    let sum = self.temporary();
+                    self.line(format!("{} = add i128 {}, {}", sum, product, wide_b));
+                    self.define(stack, format!("trunc i128 {} to i64", sum));
+                }
+                HashRange { .. } => self.unknown(stack, "hash"),
+                MatchMask { .. } => self.unknown(stack, "matchmask"),
+                ReverseTop(count) => {
+                    let start = stack.values.len().saturating_sub(usize::from(*count));
+                    stack.values[start..].reverse();
+                }
+                PopDynamic(cell) => {
+                    // The count isn't known statically, so neither are the
+                    // cells left afterwards; they keep their old names here.
+                    self.line(format!("; popdyn {}", stack.get(*cell)));
+                }
+                Store(value, address) => {
+                    let pointer = self.word(stack.get(*address));
+                    self.line(format!("store i64 {}, ptr {}", stack.get(*value), pointer));
+                }
+                Jump(target) => self.line(format!("; jmp {}", target)),
+                Return => self.line(String::from("; ret")),
+                ReturnCell(cell) => self.line(format!("; ret {}", stack.get(*cell))),
+                JumpIfZero(cell, target) => {
+                    self.line(format!("; jz {}, {}", stack.get(*cell), target));
+                }
+                JumpIfNotZero(cell, target) => {
+                    self.line(format!("; jnz {}, {}", stack.get(*cell), target));
+                }
+                Load(address) => {
+                    let pointer = self.word(stack.get(*address));
+                    self.define(stack, format!("load i64, ptr {}", pointer));
+                }
+                AssertEq { a, b } => {
+                    self.line(format!("; asserteq {}, {}", stack.get(*a), stack.get(*b)));
+                }
+                Assume(cell) => self.line(format!("; assume {}", stack.get(*cell))),
+                Assert(cell) => self.line(format!("; assert {}", stack.get(*cell))),
+                Clamp { value, lo, hi } => {
+                    let at_least = self.temporary();
+                    self.line(format!(
+                        "{} = call i64 @llvm.smax.i64(i64 {}, i64 {})",
+                        at_least,
+                        stack.get(*value),
+                        stack.get(*lo)
+                    ));
+                    let hi = stack.get(*hi);
+                    self.define(
+                        stack,
+                        format!("call i64 @llvm.smin.i64(i64 {}, i64 {})", at_least, hi),
+                    );
+                }
+                SetImm { dest, value } => {
+                    let name = self.temporary();
+                    self.line(format!("{} = add i64 0, {}", name, value));
+                    if let Some(slot) = stack.values.get_mut(usize::from(*dest)) {
+                        *slot = name;
+                    }
+                }
+                TestBit { value, bit } => {
+                    let shift = self.temporary();
+                    self.line(format!("{} = and i64 {}, 63", shift, stack.get(*bit)));
+                    let shifted = self.temporary();
+                    self.line(format!(
+                        "{} = lshr i64 {}, {}",
+                        shifted,
+                        stack.get(*value),
+                        shift
+                    ));
+                    self.define(stack, format!("and i64 {}, 1", shifted));
+                }
+                HostCall { name, args } => {
+                    let args: Vec<String> = args
+                        .iter()
+                        .map(|arg| format!("i64 {}", stack.get(*arg)))
+                        .collect();
+                    self.define(stack, format!("call i64 @{}({})", name, args.join(", ")));
+                }
+                HostUnary { name, arg } => {
+                    let arg = stack.get(*arg);
+                    self.define(stack, format!("call i64 @{}(i64 {})", name, arg));
+                }
+                Input(name) => self.define(stack, format!("load i64, ptr @{}", name)),
+                SumTop(count) => {
+                    let start = stack.values.len().saturating_sub(usize::from(*count));
+                    let operands: Vec<String> = stack.values.drain(start..).collect();
+                    match operands.split_first() {
+                        None => self.constant(stack, 0),
+                        Some((first, rest)) => {
+                            stack.values.push(first.clone());
+                            for operand in rest {
+                                let sum = stack.values.pop().unwrap_or_default();
+                                self.binary(stack, BinaryOp::Add, sum, operand.clone());
+                            }
+                        }
+                    }
+                }
+                Block(body) => {
+                    if let Some(result) = self.block("block", body, stack) {
+                        stack.values.push(result);
+                    }
+                }
+                Succeeds(body) => {
+                    self.block("succeeds", body, stack);
+                    self.unknown(stack, "succeeds");
+                }
+                Switch {
+                    selector,
+                    cases,
+                    default,
+                } => {
+                    self.line(format!("; switch on {}", stack.get(*selector)));
+                    for body in cases.iter().chain([default]) {
+                        self.block("case", body, stack);
+                    }
+                    self.unknown(stack, "switch");
+                }
+                BranchIfZero(cell, then, otherwise) => {
+                    self.line(format!("; branch on {}", stack.get(*cell)));
+                    self.block("then", then, stack);
+                    self.block("else", otherwise, stack);
+                    self.unknown(stack, "brz");
+                }
+            }
+        }
+    }
+
+    // Emits `body` as a basic block of its own, returning its last value.
+    fn block(&mut self, kind: &str, body: &[Instruction], stack: &Stack) -> Option<String> {
+        let label = format!("{}{}", kind, self.blocks);
+        self.blocks += 1;
+
+        self.line(format!("br label %{}", label));
+        self.label(&label);
+
+        let mut inner = Stack {
+            values: stack.values.clone(),
+            base: stack.values.len(),
+        };
+        self.emit(body, &mut inner);
+
+        self.line(format!("br label %{}.end", label));
+        self.label(&format!("{}.end", label));
+        inner.values.last().cloned()
+    }
+}
+
+// Renders `program` as the body of an LLVM-IR-like `@main` function returning
+// the program's last cell.
+pub fn to_llvm_like(program: &[Instruction]) -> String {
+    let mut emitter = Emitter::default();
+    let mut stack = Stack::default();
+
+    emitter.label("entry");
+    emitter.emit(program, &mut stack);
+    let result = stack
+        .values
+        .last()
+        .cloned()
+        .unwrap_or_else(|| String::from("0"));
+    emitter.line(format!("ret i64 {}", result));
+
+    format!("define i64 @main() {{\n{}\n}}\n", emitter.lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Instruction::*,
+        macros::{add_instr, make_block},
+    };
+
+    #[test]
+    fn test_add_program() {
+        let program = vec![
+            add_instr!(Push, 10),
+            add_instr!(Push, 20),
+            add_instr!(Add, 0, 1),
+        ];
+
+        let ir = to_llvm_like(&program);
+        assert!(ir.contains("  %v2 = add i64 %v0, %v1\n"));
+        assert!(ir.contains("  ret i64 %v2\n"));
+    }
+
+    #[test]
+    fn test_blocks_become_basic_blocks() {
+        let program = vec![
+            add_instr!(Push, 5),
+            make_block!(
+                add_instr!(R ReadReverse, 0),
+                add_instr!(Rebase),
+                add_instr!(Mul, 0, 0),
+                add_instr!(Push, 42),
+                add_instr!(SetLessThan, 1, 2)
+            ),
+            add_instr!(Add, 0, 1),
+        ];
+
+        let expected = "\
+define i64 @main() {
+entry:
+  %v0 = add i64 0, 5
+  br label %block0
+block0:
+  %v1 = mul i64 %v0, %v0
+  %v2 = add i64 0, 42
+  %v3 = icmp slt i64 %v1, %v2
+  %v4 = zext i1 %v3 to i64
+  br label %block0.end
+block0.end:
+  %v5 = add i64 %v0, %v4
+  ret i64 %v5
+}
+";
+        assert_eq!(to_llvm_like(&program), expected);
+    }
+}
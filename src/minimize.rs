@@ -0,0 +1,116 @@
+/*
+ * Shrinking of failing inputs.
+ *
+ * Once an input is known to make a program fail, a smaller input failing the
+ * same way is usually easier to reason about. Inputs are shrunk one variable
+ * at a time: a variable is first dropped altogether (unset inputs read as 0),
+ * and if the program then stops failing, its value is bisected toward zero.
+ * This repeats until no variable can be shrunk any further.
+ */
+
+use std::{collections::HashMap, mem::discriminant};
+
+use crate::{Instruction, Machine, MachineError};
+
+fn run(program: &[Instruction], input: &HashMap<String, i64>) -> Option<MachineError> {
+    let mut machine = Machine::new();
+    for (name, &value) in input {
+        machine.set_input(name, value);
+    }
+    machine.load_program(program);
+    machine.run().err()
+}
+
+/*
+ * Returns a smaller input for which `program` fails with the same kind of
+ * `MachineError` as it does for `input`. Errors carrying data (e.g.
+ * `AssertionFailed`) count as the same error whatever their data is.
+ *
+ * If the program doesn't fail for `input`, it's returned unchanged.
+ */
+pub fn minimize_failing_input(
+    program: &[Instruction],
+    input: &HashMap<String, i64>,
+) -> HashMap<String, i64> {
+    let mut input = input.clone();
+    let Some(expected) = run(program, &input) else {
+        return input;
+    };
+    let fails = |input: &HashMap<String, i64>| {
+        run(program, input).is_some_and(|error| discriminant(&error) == discriminant(&expected))
+    };
+
+    let mut names: Vec<String> = input.keys().cloned().collect();
+    names.sort();
+
+    let mut shrunk = true;
+    while shrunk {
+        shrunk = false;
+
+        for name in &names {
+            let Some(&value) = input.get(name) else {
+                continue;
+            };
+
+            input.remove(name);
+            if fails(&input) {
+                shrunk = true;
+                continue;
+            }
+
+            // 0 passes and `value` fails; look for the failing value closest to 0.
+            let (mut passing, mut failing) = (0i128, value as i128);
+            while (failing - passing).abs() > 1 {
+                let mid = passing + (failing - passing) / 2;
+                input.insert(name.clone(), mid as i64);
+                if fails(&input) {
+                    failing = mid;
+                } else {
+                    passing = mid;
+                }
+            }
+
+            input.insert(name.clone(), failing as i64);
+            shrunk |= failing as i64 != value;
+        }
+    }
+
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BinaryOp, Instruction::*, UnaryOpImm, macros::add_instr};
+
+    #[test]
+    fn test_minimize_failing_input() {
+        // Divides by zero whenever x >= 100; y and z are irrelevant.
+        let program = vec![
+            Input(String::from("x")),
+            Input(String::from("y")),
+            Input(String::from("z")),
+            add_instr!(Push, 100),
+            add_instr!(SetGreaterThanOrEqual, 0, 3),
+            add_instr!(Push, 1),
+            add_instr!(Xor, 4, 5),
+            add_instr!(Div, 1, 6),
+        ];
+
+        let input = HashMap::from([
+            (String::from("x"), 123_456),
+            (String::from("y"), 999),
+            (String::from("z"), -7),
+        ]);
+        let minimized = minimize_failing_input(&program, &input);
+        assert_eq!(minimized, HashMap::from([(String::from("x"), 100)]));
+        assert!(matches!(
+            run(&program, &minimized),
+            Some(MachineError::DivisionByZero)
+        ));
+
+        // Nothing to minimize when the program doesn't fail.
+        let input = HashMap::from([(String::from("x"), 5), (String::from("y"), 6)]);
+        assert_eq!(minimize_failing_input(&program, &input), input);
+    }
+}
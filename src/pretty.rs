@@ -0,0 +1,222 @@
+/*
+ * Human-friendly rendering of programs.
+ *
+ * Every instruction goes on its own line, with the bodies of blocks indented
+ * one level deeper than the block itself. Within a body, operands start in
+ * the same column so that immediates and cells line up. The output reads back
+ * through `asm::parse`.
+ */
+
+use std::fmt::{self, Display, Write};
+
+use crate::{Cell, Instruction};
+
+fn cells(cells: &[Cell]) -> String {
+    cells
+        .iter()
+        .map(|cell| format!("r{}", cell))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn operands(instruction: &Instruction) -> String {
+    use Instruction::*;
+
+    match instruction {
+        AluNullary(_) | Return => String::new(),
+        AluUnaryImm(_, imm) => imm.to_string(),
+        SumTop(n) | ReverseTop(n) => n.to_string(),
+        AluUnaryCell(_, cell)
+        | PopDynamic(cell)
+        | Load(cell)
+        | ReturnCell(cell)
+        | Assume(cell)
+        | Assert(cell) => cells(&[*cell]),
+        Store(value, address) => cells(&[*value, *address]),
+        AluBinary(_, a, b) | AssertEq { a, b } | DivMod { a, b } | Average { a, b } => {
+            cells(&[*a, *b])
+        }
+        AluFunction(_, name) | Input(name) => name.clone(),
+        FunctionDefineArity(name, arity) => format!("{}, {}", name, arity),
+        Jump(target) => target.to_string(),
+        JumpIfZero(cell, target) | JumpIfNotZero(cell, target) => {
+            format!("{}, {}", cells(&[*cell]), target)
+        }
+        PushRange { start, count } => format!("{}, {}", start, count),
+        Clamp { value, lo, hi } => cells(&[*value, *lo, *hi]),
+        Fma { a, x, b } => cells(&[*a, *x, *b]),
+        HashRange { start, len } => format!("{}, {}", cells(&[*start]), len),
+        MatchMask { value, start, len } => format!("{}, {}", cells(&[*value, *start]), len),
+        SetImm { dest, value } => format!("{}, {}", cells(&[*dest]), value),
+        TestBit { value, bit } => cells(&[*value, *bit]),
+        HostCall { name, args } if args.is_empty() => name.clone(),
+        HostCall { name, args } => format!("{}, {}", name, cells(args)),
+        HostUnary { name, arg } => format!("{}, {}", name, cells(&[*arg])),
+        Block(_) | Succeeds(_) => String::from("{"),
+        Switch { selector, .. } | BranchIfZero(selector, ..) => {
+            format!("{} {{", cells(&[*selector]))
+        }
+    }
+}
+
+fn render(output: &mut String, program: &[Instruction], indent: usize, depth: usize) {
+    let padding = " ".repeat(indent * depth);
+    let width = program
+        .iter()
+        .map(|instruction| instruction.opcode().mnemonic().len())
+        .max()
+        .unwrap_or(0);
+
+    for instruction in program {
+        let line = format!(
+            "{:width$} {}",
+            instruction.opcode().mnemonic(),
+            operands(instruction)
+        );
+        let _ = writeln!(output, "{}{}", padding, line.trim_end());
+
+        match instruction {
+            Instruction::Block(body) | Instruction::Succeeds(body) => {
+                render(output, body, indent, depth + 1);
+                let _ = writeln!(output, "{}}}", padding);
+            }
+            Instruction::Switch { cases, default, .. } => {
+                let mut bodies: Vec<_> = (cases.iter().enumerate())
+                    .map(|(index, body)| (format!("case {}", index), body))
+                    .collect();
+                bodies.push((String::from("default"), default));
+                render_labeled(output, &bodies, indent, depth);
+            }
+            Instruction::BranchIfZero(_, then, otherwise) => {
+                let bodies = [
+                    (String::from("then"), then),
+                    (String::from("else"), otherwise),
+                ];
+                render_labeled(output, &bodies, indent, depth);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Renders the bodies of a switch or branch, each under its label, and closes
+// the instruction.
+fn render_labeled(
+    output: &mut String,
+    bodies: &[(String, &Vec<Instruction>)],
+    indent: usize,
+    depth: usize,
+) {
+    let label_padding = " ".repeat(indent * (depth + 1));
+    for (label, body) in bodies {
+        let _ = writeln!(output, "{}{} {{", label_padding, label);
+        render(output, body, indent, depth + 2);
+        let _ = writeln!(output, "{}}}", label_padding);
+    }
+    let _ = writeln!(output, "{}}}", " ".repeat(indent * depth));
+}
+
+// Renders `program` one instruction per line, indenting block bodies by
+// `indent` spaces per level of nesting.
+pub fn pretty_print(program: &[Instruction], indent: usize) -> String {
+    let mut output = String::new();
+    render(&mut output, program, indent, 0);
+    output
+}
+
+// Shows a single instruction the way `pretty_print` does, bodies included.
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rendered = pretty_print(std::slice::from_ref(self), 4);
+        f.write_str(rendered.trim_end())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp, FunctionOp,
+        Instruction::*,
+        NullaryOp, UnaryOpCell, UnaryOpImm,
+        macros::{add_instr, make_block},
+    };
+
+    #[test]
+    fn test_pretty_print_nested_blocks() {
+        let program = vec![
+            add_instr!(fun FunctionDefine, String::from("square_add_42")),
+            make_block!(
+                add_instr!(R ReadReverse, 0),
+                add_instr!(Rebase),
+                add_instr!(Mul, 0, 0),
+                add_instr!(Push, 42),
+                make_block!(add_instr!(Mul, 0, 2), SetImm { dest: 3, value: -1 }),
+                add_instr!(Add, 1, 3)
+            ),
+            add_instr!(Push, 5),
+            add_instr!(fun FunctionCall, String::from("square_add_42")),
+        ];
+
+        let expected = "\
+fn    square_add_42
+block {
+  readrev r0
+  rebase
+  mul     r0, r0
+  push    42
+  block   {
+    mul    r0, r2
+    setimm r3, -1
+  }
+  add     r1, r3
+}
+push  5
+call  square_add_42
+";
+        assert_eq!(pretty_print(&program, 2), expected);
+    }
+
+    #[test]
+    fn test_display() {
+        let instruction = make_block!(add_instr!(Push, 7), add_instr!(R Neg, 0));
+        assert_eq!(
+            instruction.to_string(),
+            "block {\n    push 7\n    neg  r0\n}"
+        );
+        assert_eq!(add_instr!(Add, 0, 1).to_string(), "add r0, r1");
+    }
+
+    #[test]
+    fn test_round_trip_through_assembler() {
+        let program = crate::asm::parse(
+            "
+            fn twice:
+            block {
+                readrev r0
+                add r1, r1
+            }
+            push 3
+            switch r0 {
+                case 0 {
+                }
+                case 1 {
+                    block {
+                        push -4
+                        succeeds {
+                            div r0, r1
+                        }
+                    }
+                }
+                default {
+                    call twice
+                }
+            }
+            ",
+        )
+        .unwrap();
+
+        let printed = pretty_print(&program, 2);
+        assert_eq!(crate::asm::parse(&printed).unwrap(), program);
+    }
+}
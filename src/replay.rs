@@ -0,0 +1,255 @@
+/*
+ * Recorded runs, and replaying them to find where a run stops agreeing.
+ *
+ * A `TraceRecorder` is a tracer that writes down each instruction run: its
+ * opcode, the values of the cells it names, and the pushes, pops and writes
+ * it makes, along with any error it fails with. `Machine::replay` runs a
+ * machine again with a recorder of its own and compares the two runs step by
+ * step, stopping at the first step that differs. Machines that depend on
+ * host functions or inputs can go another way on the second run, and the
+ * step they first differ at is where to start looking.
+ */
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    Cell, Instruction, Machine, MachineError, Opcode, SharedTracer, Tracer, analysis::cell_operands,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    Push(i64),
+    Pop(i64),
+    Write(Cell, i64),
+    Error(String), // The error's message
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub opcode: Opcode,
+    pub operands: Vec<i64>, // The values of the cells it names, before it ran
+    pub events: Vec<TraceEvent>, // Everything up to the next instruction, in order
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+}
+
+// Where a replay stopped agreeing with its trace. A missing step means one
+// of the runs ended there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub step: usize,
+    pub expected: Option<TraceStep>,
+    pub found: Option<TraceStep>,
+}
+
+// Clones share what they record, so one can be given to the machine while
+// the other reads the trace.
+#[derive(Debug, Clone, Default)]
+pub struct TraceRecorder(Rc<RefCell<Trace>>);
+
+impl TraceRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trace(&self) -> Trace {
+        self.0.borrow().clone()
+    }
+
+    // Events before the first instruction belong to no step and are dropped.
+    fn record(&self, event: TraceEvent) {
+        if let Some(step) = self.0.borrow_mut().steps.last_mut() {
+            step.events.push(event);
+        }
+    }
+}
+
+impl Tracer for TraceRecorder {
+    fn on_instruction(&mut self, instr: &Instruction, cells: &[i64]) {
+        let operands = (cell_operands(instr).into_iter())
+            .filter_map(|cell| cells.get(usize::from(cell)).copied())
+            .collect();
+        self.0.borrow_mut().steps.push(TraceStep {
+            opcode: instr.opcode(),
+            operands,
+            events: Vec::new(),
+        });
+    }
+
+    fn on_push(&mut self, value: i64) {
+        self.record(TraceEvent::Push(value));
+    }
+
+    fn on_pop(&mut self, value: i64) {
+        self.record(TraceEvent::Pop(value));
+    }
+
+    fn on_write(&mut self, cell: Cell, value: i64) {
+        self.record(TraceEvent::Write(cell, value));
+    }
+
+    fn on_error(&mut self, error: &MachineError) {
+        self.record(TraceEvent::Error(error.to_string()));
+    }
+}
+
+impl Machine<'_> {
+    // Runs the loaded program, recording every step, whether or not the run
+    // failed. A tracer set on the machine sees nothing of this run.
+    pub fn run_with_trace(&mut self) -> (Result<Option<i64>, MachineError>, Trace) {
+        self.recorded(|machine, recorder| {
+            let result = machine.run().map(|last| last.copied());
+            (result, recorder.trace())
+        })
+    }
+
+    /*
+     * Runs the loaded program again, checking each step against `trace`,
+     * and returns the first step that differs, or `None` if the whole run
+     * matched. A step is only checked once the next one has started, so the
+     * machine is left one instruction past the divergence.
+     */
+    pub fn replay(&mut self, trace: &Trace) -> Option<Divergence> {
+        self.recorded(|machine, recorder| machine.replay_steps(trace, recorder))
+    }
+
+    // Runs `f` with a new recorder in place of the machine's tracer.
+    fn recorded<T>(&mut self, f: impl FnOnce(&mut Self, &TraceRecorder) -> T) -> T {
+        let recorder = TraceRecorder::new();
+        let tracer: Box<dyn Tracer> = Box::new(recorder.clone());
+        let previous = (self.tracer).replace(SharedTracer(Rc::new(RefCell::new(tracer))));
+        let result = f(self, &recorder);
+        self.tracer = previous;
+        result
+    }
+
+    fn replay_steps(&mut self, trace: &Trace, recorder: &TraceRecorder) -> Option<Divergence> {
+        let mut checked = 0;
+        loop {
+            let running = matches!(self.step_instruction(), Ok(true));
+            let found = recorder.0.borrow();
+            // The last step may still see a step limit or fuel error.
+            let complete = match running {
+                true => found.steps.len().saturating_sub(1),
+                false => found.steps.len(),
+            };
+            while checked < complete {
+                let expected = trace.steps.get(checked);
+                if expected != Some(&found.steps[checked]) {
+                    return Some(Divergence {
+                        step: checked,
+                        expected: expected.cloned(),
+                        found: Some(found.steps[checked].clone()),
+                    });
+                }
+                checked += 1;
+            }
+            if !running {
+                break;
+            }
+        }
+        (trace.steps.get(checked)).map(|expected| Divergence {
+            step: checked,
+            expected: Some(expected.clone()),
+            found: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp,
+        Instruction::*,
+        UnaryOpImm,
+        macros::{add_instr, make_block},
+    };
+
+    fn program() -> Vec<Instruction> {
+        vec![
+            add_instr!(Push, 2),
+            HostCall {
+                name: String::from("clock"),
+                args: vec![],
+            },
+            make_block!(add_instr!(Add, 0, 1)),
+            SetImm { dest: 0, value: 7 },
+            add_instr!(Pop, 4),
+        ]
+    }
+
+    fn machine(program: &[Instruction], now: i64) -> Machine<'_> {
+        let mut machine = Machine::new();
+        machine.register_host_fn("clock", Box::new(move |_| Ok(now)));
+        machine.load_program(program);
+        machine
+    }
+
+    #[test]
+    fn test_run_with_trace() {
+        let program = program();
+        let (result, trace) = machine(&program, 10).run_with_trace();
+        assert!(matches!(result, Err(MachineError::StackUnderflow)));
+
+        let opcodes: Vec<_> = trace.steps.iter().map(|step| step.opcode).collect();
+        assert_eq!(
+            opcodes,
+            vec![
+                Opcode::Push,
+                Opcode::HostCall,
+                Opcode::Block,
+                Opcode::Add,
+                Opcode::SetImm,
+                Opcode::Pop
+            ]
+        );
+        assert_eq!(trace.steps[3].operands, vec![2, 10]);
+        assert_eq!(
+            trace.steps[3].events,
+            vec![TraceEvent::Push(12), TraceEvent::Push(12)] // The block's result too
+        );
+        assert_eq!(trace.steps[4].events, vec![TraceEvent::Write(0, 7)]);
+        assert_eq!(
+            trace.steps[5].events,
+            vec![
+                TraceEvent::Pop(12),
+                TraceEvent::Pop(10),
+                TraceEvent::Pop(7),
+                TraceEvent::Error(String::from("stack underflow")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let program = program();
+        let (_, trace) = machine(&program, 10).run_with_trace();
+        assert_eq!(machine(&program, 10).replay(&trace), None);
+
+        let divergence = machine(&program, 11).replay(&trace).unwrap();
+        assert_eq!(divergence.step, 1);
+        assert_eq!(
+            divergence.expected.unwrap().events,
+            vec![TraceEvent::Push(10)]
+        );
+        assert_eq!(divergence.found.unwrap().events, vec![TraceEvent::Push(11)]);
+
+        let mut shorter = trace.clone();
+        shorter.steps.truncate(4);
+        let divergence = machine(&program, 10).replay(&shorter).unwrap();
+        assert_eq!((divergence.step, divergence.expected), (4, None));
+
+        let mut stopped = machine(&program, 10);
+        stopped.set_fuel(3);
+        let divergence = stopped.replay(&trace).unwrap();
+        assert_eq!(divergence.step, 2);
+        assert_eq!(
+            divergence.found.unwrap().events.last(),
+            Some(&TraceEvent::Error(String::from("out of fuel")))
+        );
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * Repro files: a machine and a program saved together, so that a failing run
+ * can be replayed exactly somewhere else.
+ *
+ * A repro file holds the machine's cells, its settings and call depth limit,
+ * named inputs, immutable cells and memory, and the program as checksummed
+ * bytecode. Functions the machine has defined are saved as definitions placed
+ * in front of the program, so they're defined again before the program itself
+ * starts. Host functions are native code and can't be saved; they have to be
+ * registered again after loading.
+ */
+
+use std::{fs, path::Path};
+
+use crate::{
+    Cell, FunctionOp, Instruction, Machine, MachineError,
+    bytecode::{decode_program, encode_program},
+};
+
+const MAGIC: &[u8; 8] = b"VMREPRO1";
+
+fn malformed(reason: &str) -> MachineError {
+    MachineError::InstructionError(format!("Malformed repro file: {}", reason))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], MachineError> {
+        let (taken, rest) = self
+            .bytes
+            .split_first_chunk()
+            .ok_or_else(|| malformed("unexpected end of file"))?;
+        self.bytes = rest;
+        Ok(*taken)
+    }
+
+    fn count(&mut self) -> Result<usize, MachineError> {
+        let count = u32::from_le_bytes(self.take()?);
+        usize::try_from(count)
+            .ok()
+            .filter(|&count| count <= self.bytes.len())
+            .ok_or_else(|| malformed("count exceeds the file"))
+    }
+
+    fn value(&mut self) -> Result<i64, MachineError> {
+        self.take().map(i64::from_le_bytes)
+    }
+
+    fn string(&mut self) -> Result<String, MachineError> {
+        let length = self.count()?;
+        let (string, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        String::from_utf8(string.to_vec()).map_err(|_| malformed("invalid UTF-8 in a name"))
+    }
+}
+
+fn put_count(bytes: &mut Vec<u8>, count: usize) {
+    let count = u32::try_from(count).expect("counts are limited to u32");
+    bytes.extend(count.to_le_bytes());
+}
+
+impl Machine<'_> {
+    /*
+     * Saves the machine's current state along with `program` to `path`, for
+     * `load_repro` to rebuild. Dump the machine before running it, so that
+     * the repro starts from the same cells the failing run did.
+     */
+    pub fn dump_repro(&self, program: &[Instruction], path: &Path) -> Result<(), MachineError> {
+        let mut bytes = MAGIC.to_vec();
+
+        put_count(&mut bytes, self.cells.len());
+        for cell in &self.cells {
+            bytes.extend(cell.to_le_bytes());
+        }
+
+        let flags = u8::from(self.require_result)
+            | u8::from(self.redundant_check) << 1
+            | u8::from(self.strict_scopes) << 2
+            | u8::from(self.wrapping) << 3;
+        bytes.push(flags);
+        bytes.extend((self.max_call_depth as u64).to_le_bytes());
+
+        let mut inputs: Vec<_> = self.inputs.iter().collect();
+        inputs.sort();
+        put_count(&mut bytes, inputs.len());
+        for (name, value) in inputs {
+            put_count(&mut bytes, name.len());
+            bytes.extend(name.as_bytes());
+            bytes.extend(value.to_le_bytes());
+        }
+
+        let mut immutable: Vec<_> = self.immutable.iter().collect();
+        immutable.sort();
+        put_count(&mut bytes, immutable.len());
+        for cell in immutable {
+            bytes.extend(cell.to_le_bytes());
+        }
+
+        put_count(&mut bytes, self.memory.len());
+        for word in &self.memory {
+            bytes.extend(word.to_le_bytes());
+        }
+
+        let mut functions: Vec<_> = self.function_data.function_table.iter().collect();
+        functions.sort_by_key(|(name, _)| name.as_str());
+        let mut full_program = Vec::new();
+        for (name, body) in functions {
+            full_program.push(match self.function_data.arities.get(name) {
+                Some(&arity) => Instruction::FunctionDefineArity(name.clone(), arity),
+                None => Instruction::AluFunction(FunctionOp::FunctionDefine, name.clone()),
+            });
+            match body {
+                [instruction] => full_program.push(instruction.clone()),
+                _ => full_program.push(Instruction::Block(body.to_vec())),
+            }
+        }
+        full_program.extend_from_slice(program);
+        bytes.extend(encode_program(&full_program));
+
+        fs::write(path, bytes)
+            .map_err(|e| MachineError::OtherError(format!("Failed to write repro: {}", e)))
+    }
+}
+
+// Reads a file written by `Machine::dump_repro`, returning the machine and the
+// program to run on it.
+pub fn load_repro(path: &Path) -> Result<(Machine<'static>, Vec<Instruction>), MachineError> {
+    let bytes = fs::read(path)
+        .map_err(|e| MachineError::OtherError(format!("Failed to read repro: {}", e)))?;
+    let mut reader = Reader {
+        bytes: bytes
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| malformed("not a repro file"))?,
+    };
+
+    let mut machine = Machine::new();
+    let count = reader.count()?;
+    for _ in 0..count {
+        machine.cells.push(reader.value()?);
+    }
+
+    let [flags] = reader.take()?;
+    machine.set_require_result(flags & 1 != 0);
+    machine.set_redundant_check(flags & 2 != 0);
+    machine.set_strict_scopes(flags & 4 != 0);
+    machine.set_wrapping(flags & 8 != 0);
+    let depth = u64::from_le_bytes(reader.take()?);
+    machine.set_max_call_depth(usize::try_from(depth).unwrap_or(usize::MAX));
+
+    let count = reader.count()?;
+    for _ in 0..count {
+        let name = reader.string()?;
+        machine.set_input(&name, reader.value()?);
+    }
+
+    let count = reader.count()?;
+    for _ in 0..count {
+        machine.mark_immutable(Cell::from_le_bytes(reader.take()?));
+    }
+
+    let count = reader.count()?;
+    machine.set_memory_size(count);
+    for address in 0..count {
+        machine.memory[address] = reader.value()?;
+    }
+
+    let program = decode_program(reader.bytes)?;
+    Ok((machine, program))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        BinaryOp,
+        Instruction::*,
+        UnaryOpCell, UnaryOpImm,
+        macros::{add_instr, make_block},
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let definitions = vec![
+            add_instr!(fun FunctionDefine, String::from("square")),
+            make_block!(add_instr!(R ReadReverse, 0), add_instr!(Mul, 0, 0)),
+        ];
+        let program = vec![
+            Input(String::from("x")),
+            add_instr!(fun FunctionCall, String::from("square")),
+            add_instr!(Add, 0, 2),
+            add_instr!(Push, 0),
+            add_instr!(Div, 4, 5),
+        ];
+
+        let mut machine = Machine::from(vec![3, 4]);
+        machine.load_program(&definitions);
+        machine.run().unwrap();
+        machine.set_input("x", 5);
+        machine.set_redundant_check(true);
+        machine.mark_immutable(1);
+        machine.set_max_call_depth(5);
+        machine.set_memory_size(3);
+        machine.memory[1] = -9;
+
+        let path = std::env::temp_dir().join(format!("vm-repro-{}.bin", std::process::id()));
+        machine.dump_repro(&program, &path).unwrap();
+        let (mut loaded, loaded_program) = load_repro(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        machine.load_program(&program);
+        machine.reset_pc();
+        let expected = machine.run().map(|last| last.copied());
+        loaded.load_program(&loaded_program);
+        let replayed = loaded.run().map(|last| last.copied());
+
+        assert!(matches!(expected, Err(MachineError::DivisionByZero)));
+        assert!(matches!(replayed, Err(MachineError::DivisionByZero)));
+        assert_eq!(loaded.cells, machine.cells);
+        assert_eq!(loaded.cells, vec![3, 4, 5, 9, 8, 0]);
+        assert!(loaded.redundant_check);
+        assert!(!loaded.require_result);
+        assert_eq!(loaded.max_call_depth, 5);
+        assert_eq!(loaded.memory(), [0, -9, 0]);
+        assert_eq!(loaded.immutable, HashSet::from([1]));
+    }
+}
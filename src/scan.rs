@@ -0,0 +1,96 @@
+/*
+ * Exhaustive search for failing inputs.
+ *
+ * Where `minimize` starts from an input already known to fail, a scan runs the
+ * program on every combination of input values within the given ranges and
+ * reports each one that ends in an error.
+ */
+
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use crate::{Instruction, Machine, MachineError};
+
+/*
+ * Runs `program` on every input in the product of `ranges`, at most `cap` of
+ * them, and returns each input that made it fail along with the error. Inputs
+ * are tried in order, the alphabetically last variable changing fastest, so a
+ * capped scan covers the lowest values of the first variables.
+ */
+pub fn scan_for_errors(
+    program: &[Instruction],
+    ranges: &HashMap<String, RangeInclusive<i64>>,
+    cap: usize,
+) -> Vec<(HashMap<String, i64>, MachineError)> {
+    let mut errors = Vec::new();
+    if ranges.values().any(RangeInclusive::is_empty) {
+        return errors;
+    }
+
+    let mut names: Vec<&String> = ranges.keys().collect();
+    names.sort();
+    let mut input: HashMap<String, i64> = (ranges.iter())
+        .map(|(name, range)| (name.clone(), *range.start()))
+        .collect();
+
+    for _ in 0..cap {
+        let mut machine = Machine::new();
+        for (name, &value) in &input {
+            machine.set_input(name, value);
+        }
+        machine.load_program(program);
+        if let Err(error) = machine.run() {
+            errors.push((input.clone(), error));
+        }
+
+        // Advances like an odometer; once every variable wraps, all were tried.
+        let advanced = names.iter().rev().any(|&name| {
+            let range = &ranges[name];
+            let value = input.entry(name.clone()).or_default();
+            if *value < *range.end() {
+                *value += 1;
+                true
+            } else {
+                *value = *range.start();
+                false
+            }
+        });
+        if !advanced {
+            break;
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BinaryOp, Instruction::*, UnaryOpImm, macros::add_instr};
+
+    #[test]
+    fn test_scan_finds_division_by_zero() {
+        // Computes 60 / (x - y).
+        let program = vec![
+            Input(String::from("x")),
+            Input(String::from("y")),
+            add_instr!(Push, -1),
+            add_instr!(Mul, 1, 2),
+            add_instr!(Add, 0, 3),
+            add_instr!(Push, 60),
+            add_instr!(Div, 5, 4),
+        ];
+        let ranges = HashMap::from([(String::from("x"), -2..=2), (String::from("y"), 1..=2)]);
+
+        let errors = scan_for_errors(&program, &ranges, 100);
+        let inputs: Vec<_> = (errors.iter())
+            .map(|(input, error)| {
+                assert!(matches!(error, MachineError::DivisionByZero));
+                (input["x"], input["y"])
+            })
+            .collect();
+        assert_eq!(inputs, vec![(1, 1), (2, 2)]);
+
+        // Only the first six inputs, x from -2 to 0, are tried.
+        assert!(scan_for_errors(&program, &ranges, 6).is_empty());
+    }
+}
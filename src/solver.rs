@@ -0,0 +1,461 @@
+/*
+ * Deciding path constraints.
+ *
+ * A `Solver` is given constraints, expressions over symbols that must be
+ * nonzero, and decides whether some value of the symbols satisfies all of
+ * them, producing such values as a `Model`. Constraints are asserted within
+ * scopes: `pop` forgets everything asserted since the matching `push`, so
+ * that a prefix of a path can be shared by the paths branching off it.
+ *
+ * `BoundedSolver` needs nothing outside the crate but only searches a small
+ * range of values. With the `z3` feature, `z3::Z3Solver` hands the
+ * constraints to the Z3 solver.
+ */
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Debug, Display},
+    ops::RangeInclusive,
+    rc::Rc,
+};
+
+use crate::{
+    BinaryOp, Cell, Machine, MachineError,
+    symbolic::{self, Expr, SymId},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SatResult {
+    Sat,
+    Unsat,
+    Unknown, // The solver gave up
+}
+
+// A value for each symbol of the constraints a solver found satisfiable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Model {
+    values: BTreeMap<SymId, i64>,
+}
+
+impl Model {
+    pub fn get(&self, symbol: SymId) -> Option<i64> {
+        self.values.get(&symbol).copied()
+    }
+
+    pub fn insert(&mut self, symbol: SymId, value: i64) {
+        self.values.insert(symbol, value);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SymId, i64)> + '_ {
+        self.values.iter().map(|(&symbol, &value)| (symbol, value))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // The value of `expr` under the model.
+    pub fn eval(&self, expr: &Expr) -> Option<i64> {
+        expr.eval(&|symbol| self.get(symbol))
+    }
+}
+
+impl Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (symbol, value)) in self.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "s{} = {}", symbol, value)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait Solver {
+    fn push(&mut self) -> Result<(), MachineError>;
+
+    fn pop(&mut self) -> Result<(), MachineError>;
+
+    // Requires `constraint` to be nonzero, until the current scope is popped.
+    fn assert(&mut self, constraint: Rc<Expr>) -> Result<(), MachineError>;
+
+    fn check_sat(&mut self) -> Result<SatResult, MachineError>;
+
+    // The values found by the last `check_sat`, if it returned `Sat`.
+    fn get_model(&mut self) -> Result<Option<Model>, MachineError>;
+}
+
+// Candidate assignments `BoundedSolver` tries before giving up.
+const MAX_CANDIDATES: u64 = 1 << 20;
+
+/*
+ * Searches every assignment of the symbols to values in a range. Within the
+ * range the answer is exact: `Unsat` means no values from the range satisfy
+ * the constraints, though others might. Gives up with `Unknown` when there
+ * are too many assignments to try.
+ */
+#[derive(Debug, Clone)]
+pub struct BoundedSolver {
+    range: RangeInclusive<i64>,
+    constraints: Vec<Rc<Expr>>,
+    scopes: Vec<usize>, // How many constraints each open scope started with
+    model: Option<Model>,
+}
+
+impl BoundedSolver {
+    pub fn new(range: RangeInclusive<i64>) -> Self {
+        BoundedSolver {
+            range,
+            constraints: Vec::new(),
+            scopes: Vec::new(),
+            model: None,
+        }
+    }
+
+    fn satisfied(&self, model: &Model) -> bool {
+        (self.constraints.iter()).all(|constraint| model.eval(constraint).is_some_and(|v| v != 0))
+    }
+}
+
+impl Solver for BoundedSolver {
+    fn push(&mut self) -> Result<(), MachineError> {
+        self.scopes.push(self.constraints.len());
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<(), MachineError> {
+        let len = (self.scopes.pop())
+            .ok_or_else(|| MachineError::SolverError("no scope to pop".to_string()))?;
+        self.constraints.truncate(len);
+        Ok(())
+    }
+
+    fn assert(&mut self, constraint: Rc<Expr>) -> Result<(), MachineError> {
+        self.constraints.push(constraint);
+        Ok(())
+    }
+
+    fn check_sat(&mut self) -> Result<SatResult, MachineError> {
+        self.model = None;
+        let mut symbols = BTreeSet::new();
+        for constraint in &self.constraints {
+            constraint.symbols(&mut symbols);
+        }
+        let symbols: Vec<_> = symbols.into_iter().collect();
+
+        let (lo, hi) = (*self.range.start(), *self.range.end());
+        if lo > hi {
+            return Ok(SatResult::Unsat);
+        }
+        let width = u64::try_from(i128::from(hi) - i128::from(lo) + 1).unwrap_or(u64::MAX);
+        let candidates = u32::try_from(symbols.len())
+            .ok()
+            .and_then(|count| width.checked_pow(count));
+        if candidates.is_none_or(|candidates| candidates > MAX_CANDIDATES) {
+            return Ok(SatResult::Unknown);
+        }
+
+        // Counts through the assignments like an odometer.
+        let mut model = Model::default();
+        for &symbol in &symbols {
+            model.insert(symbol, lo);
+        }
+        loop {
+            if self.satisfied(&model) {
+                self.model = Some(model);
+                return Ok(SatResult::Sat);
+            }
+            let next = symbols
+                .iter()
+                .find(|&&symbol| model.get(symbol) != Some(hi));
+            let Some(&next) = next else {
+                return Ok(SatResult::Unsat);
+            };
+            for &symbol in symbols.iter().take_while(|&&symbol| symbol != next) {
+                model.insert(symbol, lo);
+            }
+            model.insert(next, model.get(next).unwrap_or(lo) + 1);
+        }
+    }
+
+    fn get_model(&mut self) -> Result<Option<Model>, MachineError> {
+        Ok(self.model.clone())
+    }
+}
+
+// A solver a machine and its copies share.
+#[derive(Clone)]
+pub(crate) struct SharedSolver(Rc<RefCell<Box<dyn Solver>>>);
+
+impl Debug for SharedSolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Solver")
+    }
+}
+
+impl Machine<'_> {
+    // Lets `Assert` ask `solver` whether the asserted cell could be zero, and
+    // `AssertEq` whether the two cells could differ.
+    pub fn set_solver(&mut self, solver: Box<dyn Solver>) {
+        self.solver = Some(SharedSolver(Rc::new(RefCell::new(solver))));
+    }
+
+    /*
+     * Fails with the values of the symbols under which `cell` is zero: those
+     * of this run if it is, or else values taking the same path that the
+     * solver finds, if one is set.
+     */
+    pub(crate) fn assert(&mut self, cell: Cell) -> Result<(), MachineError> {
+        let value = self.read(cell)?;
+        let mut values = self.symbol_values();
+        if value == 0 {
+            self.constrain_zero(cell, value);
+            return Err(MachineError::AssertionFailure(values));
+        }
+        if let (Some(expr), Some(solver)) = (self.expression(cell), &self.solver) {
+            let solver = &mut **solver.0.borrow_mut();
+            if let Some(model) = solve(solver, self.path.iter(), &[expr])? {
+                for (symbol, value) in model.iter() {
+                    values.insert(symbol, value);
+                }
+                return Err(MachineError::AssertionFailure(values));
+            }
+        }
+        self.constrain_zero(cell, value);
+        Ok(())
+    }
+
+    /*
+     * Fails with the two values if `a` and `b` differ. If either is symbolic
+     * and a solver is set, also fails, with values the solver finds taking
+     * the same path, if the two could differ on it. Records whether they were
+     * equal as a path constraint.
+     */
+    pub(crate) fn assert_eq(&mut self, a: Cell, b: Cell) -> Result<(), MachineError> {
+        let left = self.read(a)?;
+        let right = self.read(b)?;
+        let equal = symbolic::binary(
+            BinaryOp::SetEqual,
+            (left, self.expression(a)),
+            (right, self.expression(b)),
+        );
+        if left != right {
+            if let Some(equal) = equal {
+                self.path.push(equal, BinaryOp::SetEqual, 0);
+            }
+            return Err(MachineError::AssertionFailed(left, right));
+        }
+        let Some(equal) = equal else {
+            return Ok(());
+        };
+        if let Some(solver) = &self.solver {
+            let solver = &mut **solver.0.borrow_mut();
+            if let Some(model) = solve(solver, self.path.iter(), std::slice::from_ref(&equal))? {
+                let mut values = self.symbol_values();
+                for (symbol, value) in model.iter() {
+                    values.insert(symbol, value);
+                }
+                return Err(MachineError::AssertionFailure(values));
+            }
+        }
+        self.path.require(equal);
+        Ok(())
+    }
+
+    /*
+     * Asks `solver` for values of the symbols that drive a run down the path
+     * this one took, that is, satisfying its path constraints. The solver's
+     * own constraints stay as they were.
+     */
+    pub fn solve_path(&self, solver: &mut dyn Solver) -> Result<Option<Model>, MachineError> {
+        solve(solver, self.path_constraints().iter(), &[])
+    }
+}
+
+/*
+ * Asks `solver` for values satisfying each of `constraints` and none of
+ * `excluded`, within a scope of its own. `None` when the solver finds there
+ * are none, or gives up.
+ */
+pub(crate) fn solve<'e>(
+    solver: &mut dyn Solver,
+    constraints: impl IntoIterator<Item = &'e Rc<Expr>>,
+    excluded: &[Rc<Expr>],
+) -> Result<Option<Model>, MachineError> {
+    solver.push()?;
+    let result = (|| {
+        for constraint in constraints {
+            solver.assert(constraint.clone())?;
+        }
+        for constraint in excluded {
+            let zero = Rc::new(Expr::Concrete(0));
+            solver.assert(Rc::new(Expr::Binary(
+                BinaryOp::SetEqual,
+                constraint.clone(),
+                zero,
+            )))?;
+        }
+        match solver.check_sat()? {
+            SatResult::Sat => solver.get_model(),
+            SatResult::Unsat | SatResult::Unknown => Ok(None),
+        }
+    })();
+    solver.pop()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Instruction::*,
+        UnaryOpImm,
+        macros::{add_instr, make_block},
+    };
+
+    fn symbol(symbol: SymId) -> Rc<Expr> {
+        Rc::new(Expr::Symbol(symbol))
+    }
+
+    fn binary(op: BinaryOp, a: Rc<Expr>, b: Rc<Expr>) -> Rc<Expr> {
+        Rc::new(Expr::Binary(op, a, b))
+    }
+
+    fn concrete(value: i64) -> Rc<Expr> {
+        Rc::new(Expr::Concrete(value))
+    }
+
+    #[test]
+    fn test_bounded_solver() {
+        let mut solver = BoundedSolver::new(-10..=10);
+        // s0 + s1 == 7 and s0 > s1 + 4
+        let sum = binary(BinaryOp::Add, symbol(0), symbol(1));
+        solver
+            .assert(binary(BinaryOp::SetEqual, sum, concrete(7)))
+            .unwrap();
+        solver.push().unwrap();
+        let bound = binary(BinaryOp::Add, symbol(1), concrete(4));
+        solver
+            .assert(binary(BinaryOp::SetGreaterThan, symbol(0), bound))
+            .unwrap();
+
+        assert_eq!(solver.check_sat().unwrap(), SatResult::Sat);
+        let model = solver.get_model().unwrap().unwrap();
+        let (s0, s1) = (model.get(0).unwrap(), model.get(1).unwrap());
+        assert_eq!(s0 + s1, 7);
+        assert!(s0 > s1 + 4);
+
+        // s0 == s1 can't hold along with an odd sum.
+        solver.pop().unwrap();
+        solver
+            .assert(binary(BinaryOp::SetEqual, symbol(0), symbol(1)))
+            .unwrap();
+        assert_eq!(solver.check_sat().unwrap(), SatResult::Unsat);
+        assert_eq!(solver.get_model().unwrap(), None);
+        assert!(matches!(solver.pop(), Err(MachineError::SolverError(_))));
+
+        let mut solver = BoundedSolver::new(i64::MIN..=i64::MAX);
+        solver
+            .assert(binary(BinaryOp::SetEqual, symbol(0), concrete(1)))
+            .unwrap();
+        assert_eq!(solver.check_sat().unwrap(), SatResult::Unknown);
+    }
+
+    #[test]
+    fn test_solve_path() {
+        // Takes the else branch only if s0 * 3 isn't zero.
+        let program = vec![
+            add_instr!(Push, 3),
+            add_instr!(Mul, 0, 1),
+            BranchIfZero(2, vec![], vec![make_block!(add_instr!(Push, 1))]),
+        ];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(0).unwrap();
+        machine.load_program(&program);
+        machine.run().unwrap();
+
+        let mut solver = BoundedSolver::new(-3..=3);
+        let model = machine.solve_path(&mut solver).unwrap().unwrap();
+        assert_eq!(model.get(0), Some(0));
+
+        let mut flipped = Machine::new();
+        flipped.push_symbol(5).unwrap();
+        flipped.load_program(&program);
+        flipped.run().unwrap();
+        let model = flipped.solve_path(&mut solver).unwrap().unwrap();
+        assert_ne!(model.get(0), Some(0));
+        assert!(solver.pop().is_err()); // Every scope was closed
+    }
+
+    #[test]
+    fn test_assume_and_assert() {
+        // Assumes s0 < 10, then asserts s0 > 2.
+        let program = vec![
+            add_instr!(Push, 10),
+            add_instr!(SetLessThan, 0, 1),
+            Assume(2),
+            add_instr!(Push, 2),
+            add_instr!(SetGreaterThan, 0, 3),
+            Assert(4),
+        ];
+        let run = |value: i64, solver: Option<BoundedSolver>| {
+            let mut machine = Machine::new();
+            machine.push_symbol(value).unwrap();
+            if let Some(solver) = solver {
+                machine.set_solver(Box::new(solver));
+            }
+            machine.load_program(&program);
+            machine.run().map(|last| last.copied())
+        };
+
+        assert_eq!(run(5, None).unwrap(), Some(1));
+        assert!(matches!(
+            run(12, None),
+            Err(MachineError::AssumptionViolated)
+        ));
+        let Err(failure) = run(1, None) else {
+            panic!("s0 = 1 should fail the assertion");
+        };
+        assert_eq!(failure.to_string(), "assertion failed for s0 = 1");
+
+        // The solver finds the values failing it, among those below 10.
+        let Err(MachineError::AssertionFailure(model)) = run(5, Some(BoundedSolver::new(-3..=20)))
+        else {
+            panic!("the solver should find a counterexample");
+        };
+        assert_eq!(model.get(0), Some(-3));
+        assert_eq!(run(5, Some(BoundedSolver::new(3..=20))).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_assert_eq() {
+        // Asserts s0 == s1 * s1.
+        let program = vec![add_instr!(Mul, 1, 1), AssertEq { a: 0, b: 2 }];
+        let run = |solver: Option<BoundedSolver>| {
+            let mut machine = Machine::new();
+            machine.push_symbol(4).unwrap();
+            machine.push_symbol(2).unwrap();
+            if let Some(solver) = solver {
+                machine.set_solver(Box::new(solver));
+            }
+            machine.load_program(&program);
+            let result = machine.run().map(|_| ());
+            (result, machine.path_constraints().to_string())
+        };
+
+        // Equal on this run, so without a solver the assertion holds.
+        let (result, path) = run(None);
+        assert!(result.is_ok());
+        assert_eq!(path, "(s0 == (s1 * s1))");
+
+        let (Err(MachineError::AssertionFailure(model)), _) = run(Some(BoundedSolver::new(0..=3)))
+        else {
+            panic!("the solver should make the cells differ");
+        };
+        let (s0, s1) = (model.get(0).unwrap(), model.get(1).unwrap());
+        assert_ne!(s0, s1 * s1);
+    }
+}
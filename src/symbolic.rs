@@ -0,0 +1,627 @@
+/*
+ * Symbolic execution: cells holding expressions over unknown inputs.
+ *
+ * Symbols are pushed with `Machine::push_symbol`, or by `Input` instructions
+ * reading a symbolic input, each with the value it takes on this run. Every
+ * cell then carries, next to its value, the expression it was computed by, or
+ * nothing if it doesn't depend on any symbol. Binary and unary cell operations
+ * build expressions out of their operands', as does `Clamp`, and copies keep
+ * them; every other instruction only sees values, so its results are
+ * concrete.
+ *
+ * Branches deciding on a symbolic cell (`BranchIfZero`, `Switch`, `Cond` and
+ * the conditional jumps) record the way they went as a path constraint: an
+ * expression that held for the branch to go that way.
+ */
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::{self, Display},
+    ops::Range,
+    rc::Rc,
+};
+
+use crate::{BinaryOp, Cell, Machine, MachineError, UnaryOpCell, solver::Model};
+
+// Symbols are numbered from 0, in the order they're created.
+pub type SymId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Symbol(SymId),
+    Concrete(i64),
+    Unary(UnaryOpCell, Rc<Expr>),
+    Binary(BinaryOp, Rc<Expr>, Rc<Expr>),
+    Ite(Rc<Expr>, Rc<Expr>, Rc<Expr>), // The second if the first is nonzero, else the third
+}
+
+// What a cell holds, as seen by symbolic execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymValue {
+    Concrete(i64),
+    Sym(Rc<Expr>),
+}
+
+fn infix(op: BinaryOp) -> &'static str {
+    use BinaryOp::*;
+
+    match op {
+        Add => "+",
+        Mul => "*",
+        Div => "/",
+        Sub => "-",
+        Mod => "%",
+        And => "&",
+        Or => "|",
+        Xor => "^",
+        ShiftLeftLogical => "<<",
+        ShiftRightLogical => ">>>",
+        ShiftRightArithmetic => ">>",
+        SetEqual => "==",
+        SetNotEqual => "!=",
+        SetLessThan => "<",
+        SetLessThanOrEqual => "<=",
+        SetGreaterThan => ">",
+        SetGreaterThanOrEqual => ">=",
+        SetLessThanUnsigned => "<u",
+        SetLessThanOrEqualUnsigned => "<=u",
+        SetGreaterThanUnsigned => ">u",
+        SetGreaterThanOrEqualUnsigned => ">=u",
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Symbol(n) => write!(f, "s{}", n),
+            Expr::Concrete(value) => write!(f, "{}", value),
+            Expr::Unary(UnaryOpCell::Not, operand) => write!(f, "~{}", operand),
+            Expr::Unary(UnaryOpCell::Neg, operand) => write!(f, "-{}", operand),
+            Expr::Unary(op, operand) => write!(f, "{}({})", op.mnemonic(), operand),
+            Expr::Binary(op, a, b) => write!(f, "({} {} {})", a, infix(*op), b),
+            Expr::Ite(cond, then, otherwise) => write!(f, "ite({}, {}, {})", cond, then, otherwise),
+        }
+    }
+}
+
+impl Expr {
+    /*
+     * The value of the expression when each symbol takes the value `values`
+     * gives it, computed with wrapping arithmetic. `None` if a symbol has no
+     * value or a division by zero makes the expression undefined.
+     */
+    pub fn eval(&self, values: &impl Fn(SymId) -> Option<i64>) -> Option<i64> {
+        match self {
+            Expr::Symbol(symbol) => values(*symbol),
+            Expr::Concrete(value) => Some(*value),
+            Expr::Unary(op, operand) => {
+                let value = operand.eval(values)?;
+                Some(match op {
+                    UnaryOpCell::Not => !value,
+                    UnaryOpCell::Neg => value.wrapping_neg(),
+                    UnaryOpCell::ToBool => i64::from(value != 0),
+                    UnaryOpCell::Read | UnaryOpCell::ReadReverse => value,
+                })
+            }
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(values)?, b.eval(values)?);
+                match op {
+                    BinaryOp::Div | BinaryOp::Mod if b == 0 => None,
+                    _ => Some(op.recompute(a, b)),
+                }
+            }
+            Expr::Ite(cond, then, otherwise) => match cond.eval(values)? {
+                0 => otherwise.eval(values),
+                _ => then.eval(values),
+            },
+        }
+    }
+
+    // Adds every symbol the expression refers to to `symbols`.
+    pub fn symbols(&self, symbols: &mut BTreeSet<SymId>) {
+        match self {
+            Expr::Symbol(symbol) => {
+                symbols.insert(*symbol);
+            }
+            Expr::Concrete(_) => {}
+            Expr::Unary(_, operand) => operand.symbols(symbols),
+            Expr::Binary(_, a, b) => {
+                a.symbols(symbols);
+                b.symbols(symbols);
+            }
+            Expr::Ite(cond, then, otherwise) => {
+                cond.symbols(symbols);
+                then.symbols(symbols);
+                otherwise.symbols(symbols);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Symbolic {
+    cells: Vec<Option<Rc<Expr>>>,   // Indexed like the machine's cells
+    pending: Option<Rc<Expr>>,      // The expression of the running instruction's result
+    values: Vec<i64>,               // The value each symbol takes on this run
+    inputs: HashMap<String, SymId>, // The symbol of each symbolic input
+}
+
+// The path constraints of a run, in the order the branches were decided.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathConstraints {
+    constraints: Vec<Rc<Expr>>,
+}
+
+impl PathConstraints {
+    pub fn iter(&self) -> std::slice::Iter<'_, Rc<Expr>> {
+        self.constraints.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.constraints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.constraints.is_empty()
+    }
+
+    // Records that `expr op rhs` held.
+    pub(crate) fn push(&mut self, expr: Rc<Expr>, op: BinaryOp, rhs: i64) {
+        let rhs = Rc::new(Expr::Concrete(rhs));
+        self.constraints.push(Rc::new(Expr::Binary(op, expr, rhs)));
+    }
+
+    // Records that `constraint` held, as an expression that must be nonzero.
+    pub(crate) fn require(&mut self, constraint: Rc<Expr>) {
+        self.constraints.push(constraint);
+    }
+
+    // Whether the symbols taking the values in `model` satisfy every
+    // constraint.
+    pub(crate) fn holds(&self, model: &Model) -> bool {
+        (self.constraints.iter()).all(|constraint| model.eval(constraint).is_some_and(|v| v != 0))
+    }
+}
+
+// The conjunction of the constraints, or `true` for none.
+impl Display for PathConstraints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.constraints.is_empty() {
+            return write!(f, "true");
+        }
+        for (index, constraint) in self.constraints.iter().enumerate() {
+            if index > 0 {
+                write!(f, " && ")?;
+            }
+            write!(f, "{}", constraint)?;
+        }
+        Ok(())
+    }
+}
+
+impl Symbolic {
+    fn fresh(&mut self, value: i64) -> SymId {
+        self.values.push(value);
+        self.values.len() - 1
+    }
+
+    pub(crate) fn start_instruction(&mut self) {
+        self.pending = None;
+    }
+
+    pub(crate) fn set_pending(&mut self, expr: Option<Rc<Expr>>) {
+        self.pending = expr;
+    }
+
+    pub(crate) fn get(&self, position: usize) -> Option<Rc<Expr>> {
+        self.cells.get(position).cloned().flatten()
+    }
+
+    pub(crate) fn last(&self) -> Option<Rc<Expr>> {
+        self.cells.last().cloned().flatten()
+    }
+
+    pub(crate) fn push(&mut self) {
+        self.cells.push(self.pending.clone());
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.cells.pop();
+    }
+
+    pub(crate) fn write(&mut self, position: usize) {
+        if let Some(cell) = self.cells.get_mut(position) {
+            *cell = self.pending.clone();
+        }
+    }
+
+    pub(crate) fn rebase(&mut self, base: usize) {
+        self.cells = self.cells.split_off(base);
+    }
+
+    pub(crate) fn reverse_from(&mut self, from: usize) {
+        if let Some(cells) = self.cells.get_mut(from..) {
+            cells.reverse();
+        }
+    }
+
+    pub(crate) fn remove(&mut self, range: Range<usize>) {
+        self.cells.drain(range);
+    }
+
+    // The cells' expressions, for a frame to give back when it ends.
+    pub(crate) fn save(&self) -> Vec<Option<Rc<Expr>>> {
+        self.cells.clone()
+    }
+
+    pub(crate) fn restore(&mut self, cells: Vec<Option<Rc<Expr>>>) {
+        self.cells = cells;
+    }
+}
+
+// The result of applying `op` to two operands, symbolic if either is.
+pub(crate) fn binary(
+    op: BinaryOp,
+    (a, a_expr): (i64, Option<Rc<Expr>>),
+    (b, b_expr): (i64, Option<Rc<Expr>>),
+) -> Option<Rc<Expr>> {
+    if a_expr.is_none() && b_expr.is_none() {
+        return None;
+    }
+    let a = a_expr.unwrap_or_else(|| Rc::new(Expr::Concrete(a)));
+    let b = b_expr.unwrap_or_else(|| Rc::new(Expr::Concrete(b)));
+    Some(Rc::new(Expr::Binary(op, a, b)))
+}
+
+// `value` clamped to [lo, hi], symbolic if any of them is.
+pub(crate) fn clamp(
+    value: (i64, Option<Rc<Expr>>),
+    lo: (i64, Option<Rc<Expr>>),
+    hi: (i64, Option<Rc<Expr>>),
+) -> Option<Rc<Expr>> {
+    if value.1.is_none() && lo.1.is_none() && hi.1.is_none() {
+        return None;
+    }
+    let expr = |(value, expr): &(i64, Option<Rc<Expr>>)| {
+        expr.clone()
+            .unwrap_or_else(|| Rc::new(Expr::Concrete(*value)))
+    };
+    let below = binary(BinaryOp::SetLessThan, value.clone(), lo.clone())?;
+    let above = binary(BinaryOp::SetGreaterThan, value.clone(), hi.clone())?;
+    let within = Rc::new(Expr::Ite(above, expr(&hi), expr(&value)));
+    Some(Rc::new(Expr::Ite(below, expr(&lo), within)))
+}
+
+impl Machine<'_> {
+    // Turns symbolic execution on, if it isn't yet. The cells so far are
+    // concrete.
+    fn symbolic(&mut self) -> &mut Symbolic {
+        let len = self.cells.len();
+        self.symbolic.get_or_insert_with(|| Symbolic {
+            cells: vec![None; len],
+            ..Symbolic::default()
+        })
+    }
+
+    // Pushes a fresh symbol, taking `value` on this run, and returns its
+    // number.
+    pub fn push_symbol(&mut self, value: i64) -> Result<SymId, MachineError> {
+        let symbolic = self.symbolic();
+        let symbol = symbolic.fresh(value);
+        symbolic.set_pending(Some(Rc::new(Expr::Symbol(symbol))));
+
+        self.push(value)?;
+        self.set_expression(None);
+        Ok(symbol)
+    }
+
+    // Makes `Input` instructions reading `name` push a fresh symbol, the same
+    // one every time, taking `value` on this run. Returns its number.
+    pub fn set_symbolic_input(&mut self, name: &str, value: i64) -> SymId {
+        self.set_input(name, value);
+        let symbolic = self.symbolic();
+        let symbol = symbolic.fresh(value);
+        symbolic.inputs.insert(name.to_string(), symbol);
+        symbol
+    }
+
+    pub(crate) fn symbolic_input(&self, name: &str) -> Option<Rc<Expr>> {
+        let symbol = *self.symbolic.as_ref()?.inputs.get(name)?;
+        Some(Rc::new(Expr::Symbol(symbol)))
+    }
+
+    // Records that `cell op rhs` held for a branch to go the way it did, if
+    // the cell is symbolic.
+    pub(crate) fn constrain(&mut self, cell: Cell, op: BinaryOp, rhs: i64) {
+        if let Some(expr) = self.expression(cell) {
+            self.path.push(expr, op, rhs);
+        }
+    }
+
+    // Records whether a branch found `cell`, holding `value`, zero.
+    pub(crate) fn constrain_zero(&mut self, cell: Cell, value: i64) {
+        let op = match value {
+            0 => BinaryOp::SetEqual,
+            _ => BinaryOp::SetNotEqual,
+        };
+        self.constrain(cell, op, 0);
+    }
+
+    // The expression in a visible cell, or its value if it's concrete.
+    pub fn symbolic_cell(&self, index: Cell) -> Result<SymValue, MachineError> {
+        let value = *(self.visible().get(usize::from(index))).ok_or(MachineError::InvalidCell)?;
+        Ok(match self.expression(index) {
+            Some(expr) => SymValue::Sym(expr),
+            None => SymValue::Concrete(value),
+        })
+    }
+
+    // The value each symbol takes on this run.
+    pub fn symbol_values(&self) -> Model {
+        let mut model = Model::default();
+        if let Some(symbolic) = &self.symbolic {
+            for (symbol, &value) in symbolic.values.iter().enumerate() {
+                model.insert(symbol, value);
+            }
+        }
+        model
+    }
+
+    // What the branches decided on symbolic cells so far required of them.
+    pub fn path_constraints(&self) -> &PathConstraints {
+        &self.path
+    }
+
+    /*
+     * Requires `constraint`, an expression that must be nonzero, of every
+     * path from here on, as if a branch had decided it. Machines cloned from
+     * this one, such as an explorer's forks, require it too. An explorer
+     * starting from values that break it starts from values the solver finds
+     * instead.
+     */
+    pub fn add_global_constraint(&mut self, constraint: Rc<Expr>) {
+        self.path.require(constraint);
+    }
+
+    // Returns the path constraints, leaving none, to start another path.
+    pub fn take_path_constraints(&mut self) -> PathConstraints {
+        std::mem::take(&mut self.path)
+    }
+
+    /*
+     * Changes the values the symbols take to those in `model`, as if the run
+     * had started with them, recomputing every symbolic cell, including those
+     * frames have saved, and the symbolic inputs. Memory, where values lose
+     * their expressions, stays as it is. Returns false, changing nothing, if
+     * some cell's expression is undefined under the new values.
+     */
+    pub(crate) fn concretize(&mut self, model: &Model) -> bool {
+        let Some(symbolic) = &self.symbolic else {
+            return true;
+        };
+        let mut values = symbolic.values.clone();
+        for (symbol, value) in model.iter() {
+            if let Some(old) = values.get_mut(symbol) {
+                *old = value;
+            }
+        }
+        let value_of = |symbol: SymId| values.get(symbol).copied();
+        let recompute = |cells: &[i64], exprs: &[Option<Rc<Expr>>], first: usize| {
+            let mut cells = cells.to_vec();
+            for (offset, cell) in cells.iter_mut().enumerate() {
+                if let Some(Some(expr)) = exprs.get(first + offset) {
+                    *cell = expr.eval(&value_of)?;
+                }
+            }
+            Some(cells)
+        };
+
+        let Some(cells) = recompute(&self.cells, &symbolic.cells, 0) else {
+            return false;
+        };
+        let mut saved = Vec::new();
+        for frame in &self.frames {
+            let Some(exprs) = &frame.symbolic else {
+                saved.push(frame.saved.clone());
+                continue;
+            };
+            // Saved values run down from the cell below `len`.
+            let below: Vec<i64> = frame.saved.iter().rev().copied().collect();
+            let Some(mut below) = recompute(&below, exprs, frame.len - below.len()) else {
+                return false;
+            };
+            below.reverse();
+            saved.push(below);
+        }
+
+        self.cells = cells;
+        for (frame, saved) in self.frames.iter_mut().zip(saved) {
+            frame.saved = saved;
+        }
+        for (name, &symbol) in &symbolic.inputs {
+            self.inputs.insert(name.clone(), values[symbol]);
+        }
+        if let Some(symbolic) = &mut self.symbolic {
+            symbolic.values = values;
+        }
+        true
+    }
+
+    pub(crate) fn expression(&self, index: Cell) -> Option<Rc<Expr>> {
+        let symbolic = self.symbolic.as_ref()?;
+        symbolic.get(self.window + usize::from(index))
+    }
+
+    // Sets the expression of the running instruction's next result.
+    pub(crate) fn set_expression(&mut self, expr: Option<Rc<Expr>>) {
+        if let Some(symbolic) = &mut self.symbolic {
+            symbolic.set_pending(expr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Instruction::*,
+        NullaryOp, UnaryOpImm,
+        macros::{add_instr, make_block},
+    };
+
+    #[test]
+    fn test_symbolic_arithmetic() {
+        let program = vec![
+            add_instr!(Add, 0, 1),
+            add_instr!(Push, 3),
+            add_instr!(Mul, 2, 3),
+            add_instr!(R Read, 4),
+            add_instr!(Push, 5),
+        ];
+
+        let mut machine = Machine::new();
+        assert_eq!(machine.push_symbol(4).unwrap(), 0);
+        assert_eq!(machine.push_symbol(6).unwrap(), 1);
+        machine.load_program(&program);
+        assert_eq!(machine.run().unwrap(), Some(&5));
+
+        let SymValue::Sym(sum) = machine.symbolic_cell(2).unwrap() else {
+            panic!("the sum should be symbolic");
+        };
+        assert_eq!(sum.to_string(), "(s0 + s1)");
+        let SymValue::Sym(product) = machine.symbolic_cell(5).unwrap() else {
+            panic!("copies should keep their expression");
+        };
+        assert_eq!(product.to_string(), "((s0 + s1) * 3)");
+        assert_eq!(machine.symbolic_cell(3).unwrap(), SymValue::Concrete(3));
+        assert_eq!(machine.cells, vec![4, 6, 10, 3, 30, 30, 5]);
+    }
+
+    #[test]
+    fn test_symbolic_clamp() {
+        let program = vec![
+            add_instr!(Push, 0),
+            add_instr!(Push, 10),
+            Clamp {
+                value: 0,
+                lo: 1,
+                hi: 2,
+            },
+            Clamp {
+                value: 1,
+                lo: 1,
+                hi: 2,
+            },
+        ];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(-4).unwrap();
+        machine.load_program(&program);
+        assert_eq!(machine.run().unwrap(), Some(&0));
+
+        let SymValue::Sym(clamped) = machine.symbolic_cell(3).unwrap() else {
+            panic!("clamping a symbol should be symbolic");
+        };
+        assert_eq!(
+            clamped.to_string(),
+            "ite((s0 < 0), 0, ite((s0 > 10), 10, s0))"
+        );
+        for (value, expected) in [(-4, 0), (3, 3), (12, 10)] {
+            assert_eq!(clamped.eval(&|_| Some(value)), Some(expected));
+        }
+        assert_eq!(machine.symbolic_cell(4).unwrap(), SymValue::Concrete(0));
+    }
+
+    #[test]
+    fn test_symbolic_inputs() {
+        let program = vec![
+            Input(String::from("x")),
+            Input(String::from("y")),
+            add_instr!(Mul, 0, 1),
+            Input(String::from("x")),
+        ];
+
+        let mut machine = Machine::new();
+        machine.set_input("y", 4);
+        assert_eq!(machine.set_symbolic_input("x", 3), 0);
+        machine.load_program(&program);
+        assert_eq!(machine.run().unwrap(), Some(&3));
+
+        let SymValue::Sym(product) = machine.symbolic_cell(2).unwrap() else {
+            panic!("the product should be symbolic");
+        };
+        assert_eq!(product.to_string(), "(s0 * 4)");
+        assert_eq!(
+            machine.symbolic_cell(3).unwrap(),
+            SymValue::Sym(Rc::new(Expr::Symbol(0)))
+        );
+        assert_eq!(machine.cells, vec![3, 4, 12, 3]);
+    }
+
+    #[test]
+    fn test_switch_and_cond_constraints() {
+        let program = vec![
+            Switch {
+                selector: 0,
+                cases: vec![vec![], vec![add_instr!(Push, 1)]],
+                default: vec![add_instr!(Push, 2)],
+            },
+            add_instr!(R Read, 0),
+            add_instr!(Cond),
+            add_instr!(Push, 3),
+        ];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(1).unwrap();
+        machine.load_program(&program);
+        assert_eq!(machine.run().unwrap(), Some(&3));
+        assert_eq!(
+            machine.path_constraints().to_string(),
+            "(s0 == 1) && (s0 == 1)"
+        );
+
+        let path = machine.take_path_constraints();
+        assert_eq!(path.len(), 2);
+        assert!(machine.path_constraints().is_empty());
+        assert_eq!(machine.path_constraints().to_string(), "true");
+
+        let mut machine = Machine::new();
+        machine.push_symbol(5).unwrap();
+        machine.load_program(&program);
+        assert_eq!(machine.run().unwrap(), Some(&2));
+        assert_eq!(
+            machine.path_constraints().to_string(),
+            "(s0 >=u 2) && (s0 != 1)"
+        );
+    }
+
+    #[test]
+    fn test_path_constraints() {
+        let program = vec![
+            add_instr!(Push, 2),
+            add_instr!(SetLessThan, 0, 1),
+            BranchIfZero(
+                2,
+                vec![add_instr!(Push, 0)],
+                vec![make_block!(add_instr!(R Neg, 0))],
+            ),
+        ];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(1).unwrap();
+        machine.load_program(&program);
+        assert_eq!(machine.run().unwrap(), Some(&-1));
+
+        let constraints: Vec<_> = (machine.path_constraints().iter())
+            .map(|constraint| constraint.to_string())
+            .collect();
+        assert_eq!(constraints, vec!["((s0 < 2) != 0)"]);
+        assert_eq!(
+            machine.symbolic_cell(3).unwrap(),
+            SymValue::Sym(Rc::new(Expr::Unary(
+                UnaryOpCell::Neg,
+                Rc::new(Expr::Symbol(0))
+            )))
+        );
+    }
+}
@@ -0,0 +1,138 @@
+/*
+ * Taint tracking: which cells hold values derived from designated inputs.
+ *
+ * Every cell carries a flag next to its value. An instruction's results are
+ * tainted if any cell it read was, so taint follows data through every
+ * operation, block and function call. Only explicit data flow is tracked: a
+ * value chosen by a tainted `Cond`, or a `Succeeds` flag from a body that
+ * read tainted cells, isn't tainted.
+ */
+
+use std::{collections::HashSet, ops::Range};
+
+use crate::{Cell, Machine, MachineError};
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Taint {
+    cells: Vec<bool>, // Indexed like the machine's cells
+    pending: bool,    // Whether the running instruction read a tainted cell
+}
+
+impl Taint {
+    pub(crate) fn start_instruction(&mut self) {
+        self.pending = false;
+    }
+
+    pub(crate) fn set_pending(&mut self, tainted: bool) {
+        self.pending = tainted;
+    }
+
+    pub(crate) fn pending(&self) -> bool {
+        self.pending
+    }
+
+    pub(crate) fn last(&self) -> bool {
+        self.cells.last().copied().unwrap_or(false)
+    }
+
+    pub(crate) fn read(&mut self, reg: usize) {
+        self.pending |= self.cells.get(reg).copied().unwrap_or(false);
+    }
+
+    pub(crate) fn push(&mut self) {
+        self.cells.push(self.pending);
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.cells.pop();
+    }
+
+    pub(crate) fn write(&mut self, reg: usize) {
+        if let Some(cell) = self.cells.get_mut(reg) {
+            *cell = self.pending;
+        }
+    }
+
+    pub(crate) fn rebase(&mut self, base: usize) {
+        self.cells = self.cells.split_off(base);
+    }
+
+    pub(crate) fn reverse_from(&mut self, from: usize) {
+        if let Some(cells) = self.cells.get_mut(from..) {
+            cells.reverse();
+        }
+    }
+
+    pub(crate) fn remove(&mut self, range: Range<usize>) {
+        self.cells.drain(range);
+    }
+}
+
+impl Machine<'_> {
+    /*
+     * Runs the loaded program with the cells in `tainted_inputs` marked as
+     * tainted, and returns the indices of the final cells whose values depend
+     * on any of them.
+     */
+    pub fn run_with_taint(
+        &mut self,
+        tainted_inputs: &HashSet<Cell>,
+    ) -> Result<(Option<i64>, HashSet<usize>), MachineError> {
+        let cells = (0..self.cells.len())
+            .map(|index| Cell::try_from(index).is_ok_and(|cell| tainted_inputs.contains(&cell)))
+            .collect();
+        self.taint = Some(Taint {
+            cells,
+            pending: false,
+        });
+
+        let result = self.run().map(|last| last.copied());
+        let taint = self.taint.take().unwrap_or_default();
+
+        let tainted = (taint.cells.iter().enumerate())
+            .filter_map(|(index, &tainted)| tainted.then_some(index))
+            .collect();
+        Ok((result?, tainted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp, FunctionOp,
+        Instruction::*,
+        NullaryOp, UnaryOpImm,
+        macros::{add_instr, make_block},
+    };
+
+    #[test]
+    fn test_taint_propagates() {
+        let program = vec![
+            add_instr!(Mul, 0, 2),              // 3: derived from the secret
+            add_instr!(Add, 1, 2),              // 4: public only
+            make_block!(add_instr!(Add, 3, 4)), // 5: secret, through a block
+            SetImm { dest: 3, value: 0 },       // Overwriting clears the taint
+            add_instr!(fun FunctionDefine, String::from("leak")),
+            make_block!(add_instr!(Push, 1), add_instr!(Add, 0, 6)),
+            add_instr!(fun FunctionCall, String::from("leak")), // 6: secret, through a call
+        ];
+
+        // Cell 0 holds the secret, cells 1 and 2 public values.
+        let mut machine = Machine::from(vec![7, 10, 20]);
+        machine.load_program(&program);
+        let (result, tainted) = machine.run_with_taint(&HashSet::from([0])).unwrap();
+        assert_eq!(result, Some(8));
+        assert_eq!(tainted, HashSet::from([0, 5, 6]));
+    }
+
+    #[test]
+    fn test_nothing_tainted() {
+        let program = vec![add_instr!(Add, 0, 1), add_instr!(Rebase)];
+        let mut machine = Machine::from(vec![1, 2]);
+        machine.load_program(&program);
+        let (result, tainted) = machine.run_with_taint(&HashSet::new()).unwrap();
+        assert_eq!(result, Some(3));
+        assert!(tainted.is_empty());
+    }
+}
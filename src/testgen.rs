@@ -0,0 +1,132 @@
+/*
+ * Regression tests from symbolic exploration.
+ *
+ * Every path an `Explorer` finds becomes a `TestCase`: the cells and named
+ * inputs a concrete machine starts with to go down that path, and what the
+ * run ends with. The starting values come from the values of the symbols
+ * the explorer solved for along the path; the expected end comes from
+ * running the program on them, so a test case always replays as recorded
+ * on the machine it was made from. With the `serde` feature, test cases can
+ * be saved as JSON with `json::encode_tests`.
+ */
+
+use crate::{
+    Instruction, Machine, MachineError,
+    explore::{Explorer, TerminalState},
+    solver::Solver,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TestCase {
+    pub cells: Vec<i64>,                    // The cells the run starts with
+    pub inputs: Vec<(String, i64)>,         // Values for `Input`, sorted by name
+    pub max_steps: Option<usize>,           // The step limit the path ran under
+    pub expected: Result<Vec<i64>, String>, // The cells at the end, or the error's message
+}
+
+impl TestCase {
+    // Runs `program` from the test's starting state on a new machine.
+    pub fn run(&self, program: &[Instruction]) -> Result<Vec<i64>, String> {
+        let mut machine = Machine::from(self.cells.clone());
+        for (name, value) in &self.inputs {
+            machine.set_input(name, *value);
+        }
+        machine.load_program(program);
+        let result = match self.max_steps {
+            Some(max_steps) => machine.run_with_budget(max_steps).map(|_| ()),
+            None => machine.run().map(|_| ()),
+        };
+        result
+            .map(|()| machine.cells.clone())
+            .map_err(|e| e.to_string())
+    }
+
+    // Whether `program` still ends the way the test expects.
+    pub fn passes(&self, program: &[Instruction]) -> bool {
+        self.run(program) == self.expected
+    }
+}
+
+impl<'a, S: Solver> Explorer<'a, S> {
+    /*
+     * A test case following the path `state` went down, made from the
+     * machine exploration started from. `None` if no path was explored yet,
+     * or the values the path's symbols take leave some cell undefined.
+     */
+    pub fn test_case(&self, state: &TerminalState<'a>) -> Option<TestCase> {
+        let mut start = self.origin.clone()?;
+        if !start.concretize(&state.machine.symbol_values()) {
+            return None;
+        }
+        let mut inputs: Vec<_> = start.inputs.into_iter().collect();
+        inputs.sort();
+
+        let mut test = TestCase {
+            cells: start.cells,
+            inputs,
+            max_steps: start.budget,
+            expected: Ok(Vec::new()),
+        };
+        test.expected = test.run(start.program?);
+        Some(test)
+    }
+
+    // Explores every path, making a test case of each.
+    pub fn generate_tests(mut self) -> Result<Vec<TestCase>, MachineError> {
+        let mut tests = Vec::new();
+        while let Some(state) = self.next() {
+            tests.extend(self.test_case(&state?));
+        }
+        Ok(tests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BinaryOp,
+        Instruction::*,
+        UnaryOpImm,
+        macros::{add_instr, make_block},
+        solver::BoundedSolver,
+    };
+
+    #[test]
+    fn test_generate_tests() {
+        // Pushes 100 / (x - s0) if s0 < x, and loops forever otherwise.
+        let program = vec![
+            Input(String::from("x")),
+            add_instr!(SetLessThan, 0, 1),
+            BranchIfZero(
+                2,
+                vec![Jump(0)],
+                vec![make_block!(
+                    add_instr!(Sub, 1, 0),
+                    add_instr!(Push, 100),
+                    add_instr!(Div, 4, 3)
+                )],
+            ),
+        ];
+
+        let mut machine = Machine::new();
+        machine.push_symbol(-2).unwrap();
+        machine.set_symbolic_input("x", 1);
+        machine.load_program(&program);
+        let explorer = Explorer::new(machine, BoundedSolver::new(-2..=2)).with_step_limit(30);
+
+        let tests = explorer.generate_tests().unwrap();
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[0].cells, vec![-2]);
+        assert_eq!(tests[0].inputs, vec![(String::from("x"), 1)]);
+        assert_eq!(tests[0].expected, Ok(vec![-2, 1, 1, 33]));
+        assert_eq!(tests[1].expected, Err(String::from("step limit exceeded")));
+        assert_eq!(tests[1].max_steps, Some(30));
+        assert!(tests.iter().all(|test| test.passes(&program)));
+
+        let mut changed = program.clone();
+        changed[1] = add_instr!(SetGreaterThan, 0, 1);
+        assert!(!tests[0].passes(&changed));
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ * Execution traces meant for external tools.
+ *
+ * `run_with_chrome_trace` records a Begin/End pair for every block and
+ * function scope entered while running, and writes them out in the Trace
+ * Event Format understood by chrome://tracing and Perfetto.
+ */
+
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{Machine, MachineError};
+
+#[derive(Debug, Clone)]
+struct ScopeEvent {
+    name: String,
+    category: &'static str,
+    begin: bool,
+    at: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ScopeTrace {
+    start: Instant,
+    events: Vec<ScopeEvent>,
+}
+
+impl ScopeTrace {
+    fn new() -> Self {
+        ScopeTrace {
+            start: Instant::now(),
+            events: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, name: &str, category: &'static str, begin: bool) {
+        self.events.push(ScopeEvent {
+            name: name.to_string(),
+            category,
+            begin,
+            at: self.start.elapsed(),
+        });
+    }
+
+    fn write_json(&self, sink: &mut impl Write) -> std::io::Result<()> {
+        write!(sink, "{{\"traceEvents\":[")?;
+        for (i, event) in self.events.iter().enumerate() {
+            if i > 0 {
+                write!(sink, ",")?;
+            }
+            write!(
+                sink,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":0,\"tid\":0}}",
+                escape(&event.name),
+                event.category,
+                if event.begin { "B" } else { "E" },
+                event.at.as_secs_f64() * 1e6,
+            )?;
+        }
+        write!(sink, "],\"displayTimeUnit\":\"ns\"}}")
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Machine<'_> {
+    // Runs the loaded program, then writes a chrome://tracing compatible JSON
+    // trace with one span per block and function scope to `sink`. The trace is
+    // written even when the program fails, with every open span closed.
+    pub fn run_with_chrome_trace(
+        &mut self,
+        mut sink: impl Write,
+    ) -> Result<Option<i64>, MachineError> {
+        self.scope_trace = Some(ScopeTrace::new());
+        let result = self.run().map(|last| last.copied());
+        let trace = self.scope_trace.take().unwrap_or_else(ScopeTrace::new);
+
+        trace
+            .write_json(&mut sink)
+            .map_err(|e| MachineError::OtherError(format!("Failed to write trace: {}", e)))?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        BinaryOp, FunctionOp,
+        Instruction::{self, *},
+        Machine, MachineError, UnaryOpImm,
+        macros::{add_instr, make_block},
+    };
+
+    fn trace(program: &[Instruction]) -> (Result<Option<i64>, MachineError>, serde_json::Value) {
+        let mut machine = Machine::new();
+        machine.load_program(program);
+        let mut sink = Vec::new();
+        let result = machine.run_with_chrome_trace(&mut sink);
+        let json = serde_json::from_slice(&sink).expect("trace should be valid JSON");
+        (result, json)
+    }
+
+    fn spans(json: &serde_json::Value) -> Vec<(String, String)> {
+        json["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                (
+                    event["ph"].as_str().unwrap().to_string(),
+                    event["name"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_span_per_block() {
+        let program = vec![
+            add_instr!(Push, 1),
+            make_block!(add_instr!(Push, 2), make_block!(add_instr!(Push, 3))),
+            make_block!(add_instr!(Push, 4)),
+            add_instr!(fun FunctionDefine, String::from("say \"hi\"")),
+            make_block!(add_instr!(Push, 5)),
+            add_instr!(fun FunctionCall, String::from("say \"hi\"")),
+        ];
+
+        let (result, json) = trace(&program);
+        assert_eq!(result.unwrap(), Some(5));
+
+        let expected = [
+            ("B", "block"),
+            ("B", "block"),
+            ("E", "block"),
+            ("E", "block"),
+            ("B", "block"),
+            ("E", "block"),
+            ("B", "say \"hi\""),
+            ("B", "block"),
+            ("E", "block"),
+            ("E", "say \"hi\""),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(ph, name)| (ph.to_string(), name.to_string()))
+            .collect();
+        assert_eq!(spans(&json), expected);
+
+        let events = json["traceEvents"].as_array().unwrap();
+        let timestamps: Vec<f64> = events.iter().map(|e| e["ts"].as_f64().unwrap()).collect();
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_spans_closed_on_error() {
+        let program = vec![
+            add_instr!(Push, 0),
+            make_block!(make_block!(add_instr!(Div, 0, 0))),
+        ];
+
+        let (result, json) = trace(&program);
+        assert!(matches!(result, Err(MachineError::DivisionByZero)));
+
+        let phases: Vec<String> = spans(&json).into_iter().map(|(ph, _)| ph).collect();
+        assert_eq!(phases, vec!["B", "B", "E", "E"]);
+    }
+}
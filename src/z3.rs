@@ -0,0 +1,334 @@
+/*
+ * A `Solver` backed by Z3.
+ *
+ * Z3 runs as a separate process, `z3 -in`, and is given the constraints in
+ * SMT-LIB. Symbols become 64-bit bitvectors, so the answers agree with the
+ * machine's wrapping arithmetic. Z3 has to be installed and on the `PATH`,
+ * or named with `Z3Solver::with_command`.
+ */
+
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    rc::Rc,
+};
+
+use crate::{
+    BinaryOp, MachineError, UnaryOpCell,
+    solver::{Model, SatResult, Solver},
+    symbolic::{Expr, SymId},
+};
+
+const ZERO: &str = "#x0000000000000000";
+const ONE: &str = "#x0000000000000001";
+
+fn bitvector(value: i64) -> String {
+    format!("#x{:016x}", value)
+}
+
+fn symbol(symbol: SymId) -> String {
+    format!("s{}", symbol)
+}
+
+/*
+ * Writes `expr` as an SMT-LIB bitvector term. Division by zero leaves the
+ * machine's expressions undefined but not Z3's, so the divisors met along
+ * the way are added to `divisors`, to be required nonzero.
+ */
+fn term(expr: &Expr, out: &mut String, divisors: &mut Vec<String>) {
+    use BinaryOp::*;
+
+    match expr {
+        Expr::Symbol(id) => out.push_str(&symbol(*id)),
+        Expr::Concrete(value) => out.push_str(&bitvector(*value)),
+        Expr::Unary(op, operand) => {
+            let operand = term_string(operand, divisors);
+            let _ = match op {
+                UnaryOpCell::Not => write!(out, "(bvnot {})", operand),
+                UnaryOpCell::Neg => write!(out, "(bvneg {})", operand),
+                UnaryOpCell::ToBool => {
+                    write!(out, "(ite (= {} {}) {} {})", operand, ZERO, ZERO, ONE)
+                }
+                UnaryOpCell::Read | UnaryOpCell::ReadReverse => write!(out, "{}", operand),
+            };
+        }
+        Expr::Binary(op, a, b) => {
+            let (a, b) = (term_string(a, divisors), term_string(b, divisors));
+            let function = match op {
+                Add => "bvadd",
+                Mul => "bvmul",
+                Div => "bvsdiv",
+                Sub => "bvsub",
+                Mod => "bvsrem",
+                And => "bvand",
+                Or => "bvor",
+                Xor => "bvxor",
+                ShiftLeftLogical => "bvshl",
+                ShiftRightLogical => "bvlshr",
+                ShiftRightArithmetic => "bvashr",
+                SetEqual => "=",
+                SetNotEqual => "distinct",
+                SetLessThan => "bvslt",
+                SetLessThanOrEqual => "bvsle",
+                SetGreaterThan => "bvsgt",
+                SetGreaterThanOrEqual => "bvsge",
+                SetLessThanUnsigned => "bvult",
+                SetLessThanOrEqualUnsigned => "bvule",
+                SetGreaterThanUnsigned => "bvugt",
+                SetGreaterThanOrEqualUnsigned => "bvuge",
+            };
+            let _ = match op {
+                Div | Mod => {
+                    divisors.push(b.clone());
+                    write!(out, "({} {} {})", function, a, b)
+                }
+                // The machine only looks at the low six bits of a shift.
+                ShiftLeftLogical | ShiftRightLogical | ShiftRightArithmetic => {
+                    write!(out, "({} {} (bvand {} {}))", function, a, b, bitvector(63))
+                }
+                Add | Mul | Sub | And | Or | Xor => write!(out, "({} {} {})", function, a, b),
+                _ => write!(out, "(ite ({} {} {}) {} {})", function, a, b, ONE, ZERO),
+            };
+        }
+        Expr::Ite(cond, then, otherwise) => {
+            let cond = term_string(cond, divisors);
+            let (then, otherwise) = (
+                term_string(then, divisors),
+                term_string(otherwise, divisors),
+            );
+            let _ = write!(
+                out,
+                "(ite (distinct {} {}) {} {})",
+                cond, ZERO, then, otherwise
+            );
+        }
+    }
+}
+
+fn term_string(expr: &Expr, divisors: &mut Vec<String>) -> String {
+    let mut out = String::new();
+    term(expr, &mut out, divisors);
+    out
+}
+
+// An SMT-LIB formula that holds when `constraint` is nonzero.
+pub(crate) fn formula(constraint: &Expr) -> String {
+    let mut divisors = Vec::new();
+    let value = term_string(constraint, &mut divisors);
+    let mut out = format!("(and (distinct {} {})", value, ZERO);
+    for divisor in divisors {
+        let _ = write!(out, " (distinct {} {})", divisor, ZERO);
+    }
+    out.push(')');
+    out
+}
+
+// Reads `#x...` and `#b...` literals, the forms Z3 prints bitvectors in.
+fn parse_bitvector(literal: &str) -> Option<i64> {
+    let value = if let Some(hex) = literal.strip_prefix("#x") {
+        u64::from_str_radix(hex, 16).ok()?
+    } else {
+        u64::from_str_radix(literal.strip_prefix("#b")?, 2).ok()?
+    };
+    Some(value as i64)
+}
+
+// Reads the `((s0 #x...) (s1 #x...))` answer to a `get-value`.
+fn parse_values(answer: &str) -> Option<Model> {
+    let tokens = answer.replace(['(', ')'], " ");
+    let mut tokens = tokens.split_whitespace();
+    let mut model = Model::default();
+    while let Some(name) = tokens.next() {
+        let id = name.strip_prefix('s')?.parse().ok()?;
+        model.insert(id, parse_bitvector(tokens.next()?)?);
+    }
+    Some(model)
+}
+
+pub struct Z3Solver {
+    process: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    declared: BTreeSet<SymId>,
+    scopes: usize,
+    sat: bool, // Whether the last `check_sat` found the constraints satisfiable
+}
+
+impl Z3Solver {
+    pub fn new() -> Result<Self, MachineError> {
+        Self::with_command("z3")
+    }
+
+    pub fn with_command(command: &str) -> Result<Self, MachineError> {
+        let failed = |e: std::io::Error| MachineError::SolverError(format!("{}: {}", command, e));
+        let mut process = Command::new(command)
+            .args(["-in", "-smt2"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(failed)?;
+        let (Some(input), Some(output)) = (process.stdin.take(), process.stdout.take()) else {
+            return Err(MachineError::SolverError("no pipe to z3".to_string()));
+        };
+
+        let mut solver = Z3Solver {
+            process,
+            input,
+            output: BufReader::new(output),
+            declared: BTreeSet::new(),
+            scopes: 0,
+            sat: false,
+        };
+        // Keeps symbols declared inside a scope once it's popped.
+        solver.send("(set-option :global-declarations true)")?;
+        solver.send("(set-option :print-success false)")?;
+        Ok(solver)
+    }
+
+    fn send(&mut self, command: &str) -> Result<(), MachineError> {
+        writeln!(self.input, "{}", command)
+            .and_then(|()| self.input.flush())
+            .map_err(|e| MachineError::SolverError(e.to_string()))
+    }
+
+    // Reads one answer, which may span several lines if it has parentheses.
+    fn receive(&mut self) -> Result<String, MachineError> {
+        let mut answer = String::new();
+        loop {
+            let read = (self.output.read_line(&mut answer))
+                .map_err(|e| MachineError::SolverError(e.to_string()))?;
+            if read == 0 {
+                return Err(MachineError::SolverError("z3 exited".to_string()));
+            }
+            let depth = answer.matches('(').count() as isize - answer.matches(')').count() as isize;
+            if depth <= 0 {
+                break;
+            }
+        }
+        let answer = answer.trim();
+        if answer.starts_with("(error") {
+            return Err(MachineError::SolverError(answer.to_string()));
+        }
+        Ok(answer.to_string())
+    }
+}
+
+impl Solver for Z3Solver {
+    fn push(&mut self) -> Result<(), MachineError> {
+        self.scopes += 1;
+        self.send("(push 1)")
+    }
+
+    fn pop(&mut self) -> Result<(), MachineError> {
+        if self.scopes == 0 {
+            return Err(MachineError::SolverError("no scope to pop".to_string()));
+        }
+        self.scopes -= 1;
+        self.send("(pop 1)")
+    }
+
+    fn assert(&mut self, constraint: Rc<Expr>) -> Result<(), MachineError> {
+        let mut symbols = BTreeSet::new();
+        constraint.symbols(&mut symbols);
+        for id in symbols {
+            if self.declared.insert(id) {
+                self.send(&format!("(declare-const {} (_ BitVec 64))", symbol(id)))?;
+            }
+        }
+        self.send(&format!("(assert {})", formula(&constraint)))
+    }
+
+    fn check_sat(&mut self) -> Result<SatResult, MachineError> {
+        self.send("(check-sat)")?;
+        let result = match self.receive()?.as_str() {
+            "sat" => SatResult::Sat,
+            "unsat" => SatResult::Unsat,
+            _ => SatResult::Unknown,
+        };
+        self.sat = result == SatResult::Sat;
+        Ok(result)
+    }
+
+    fn get_model(&mut self) -> Result<Option<Model>, MachineError> {
+        if !self.sat {
+            return Ok(None);
+        }
+        if self.declared.is_empty() {
+            return Ok(Some(Model::default()));
+        }
+        // Symbols that don't appear in the constraints still get some value.
+        let names: Vec<_> = self.declared.iter().map(|&id| symbol(id)).collect();
+        self.send(&format!("(get-value ({}))", names.join(" ")))?;
+        let answer = self.receive()?;
+        parse_values(&answer)
+            .map(Some)
+            .ok_or_else(|| MachineError::SolverError(format!("unexpected answer: {}", answer)))
+    }
+}
+
+impl Drop for Z3Solver {
+    fn drop(&mut self) {
+        let _ = self.send("(exit)");
+        let _ = self.process.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formula() {
+        let s0 = Rc::new(Expr::Symbol(0));
+        let quotient = Rc::new(Expr::Binary(
+            BinaryOp::Div,
+            Rc::new(Expr::Concrete(10)),
+            s0.clone(),
+        ));
+        let less = Expr::Binary(
+            BinaryOp::SetLessThan,
+            quotient,
+            Rc::new(Expr::Unary(UnaryOpCell::Neg, s0)),
+        );
+        assert_eq!(
+            formula(&less),
+            "(and (distinct (ite (bvslt (bvsdiv #x000000000000000a s0) (bvneg s0)) \
+             #x0000000000000001 #x0000000000000000) #x0000000000000000) \
+             (distinct s0 #x0000000000000000))"
+        );
+
+        let shift = Expr::Binary(
+            BinaryOp::ShiftLeftLogical,
+            Rc::new(Expr::Symbol(1)),
+            Rc::new(Expr::Concrete(-1)),
+        );
+        assert_eq!(
+            formula(&shift),
+            "(and (distinct (bvshl s1 (bvand #xffffffffffffffff #x000000000000003f)) \
+             #x0000000000000000))"
+        );
+
+        let clamped = Expr::Ite(
+            Rc::new(Expr::Symbol(0)),
+            Rc::new(Expr::Concrete(1)),
+            Rc::new(Expr::Symbol(1)),
+        );
+        assert_eq!(
+            formula(&clamped),
+            "(and (distinct (ite (distinct s0 #x0000000000000000) #x0000000000000001 s1) \
+             #x0000000000000000))"
+        );
+    }
+
+    #[test]
+    fn test_parse_values() {
+        let model = parse_values("((s0 #x0000000000000005)\n (s3 #xfffffffffffffffe))").unwrap();
+        assert_eq!(model.get(0), Some(5));
+        assert_eq!(model.get(3), Some(-2));
+        assert_eq!(parse_bitvector("#b101"), Some(5));
+        assert!(parse_values("((x #x01))").is_none());
+    }
+}